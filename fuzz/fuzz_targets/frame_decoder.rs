@@ -0,0 +1,30 @@
+//Fuzzes the server's receive path: arbitrary bytes are split into frames, decoded and answered like the server does.
+//Every frame must either fail cleanly or produce a response that encodes and decodes back unchanged.
+#![no_main]
+
+//IMPORTS
+use embedded_recruitment_task::{
+    frame::{read_frame, HEADER_LEN},
+    handler::process,
+    message::{ClientMessage, ServerMessage},
+};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    // Ok(None) is a clean end of input, Err a truncated frame, both end the connection
+    while let Ok(Some(frame)) = read_frame(&mut reader) {
+        // The body buffer only grows with bytes actually present, whatever length the header declares
+        assert!(frame.len() + HEADER_LEN <= data.len());
+        let Ok(request) = ClientMessage::decode(&frame[..]) else {
+            continue;     // The server skips undecodable frames
+        };
+        if let Some(response) = request.message.and_then(process) {
+            let response = ServerMessage { message: Some(response), ..Default::default() };
+            let decoded = ServerMessage::decode(&response.encode_to_vec()[..]).expect("Response does not decode");
+            assert_eq!(decoded, response);
+        }
+    }
+});
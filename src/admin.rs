@@ -0,0 +1,100 @@
+//Admin messages: listing, measuring, kicking and broadcasting to the data connections of a server.
+//They are only answered on the admin port (ServerBuilder::admin_address), so control traffic stays off the data port.
+
+//IMPORTS
+use crate::message::{client_message, server_message, BroadcastResponse, ClientInfo, ClientInfoResponse, Error, KickResponse, MetricsResponse, Notification};   //Protobuf-generated message types
+use crate::server::Registry;                 //Open data connections, shared with the accept loop
+use log::{info, warn};                       //Logs kicks and failed deliveries
+use std::{
+    net::{Shutdown, SocketAddr},             //Kick closes a connection by peer address
+    sync::{
+        atomic::{AtomicUsize, Ordering},     //Server-wide counters reported by MetricsRequest
+        Arc,
+    },
+};
+
+pub(crate) const BROADCAST_TOPIC: &str = "broadcast";   // Topic of the Notification sent by Broadcast
+
+//Admin: shared by every admin connection of a server
+pub(crate) struct Admin {
+    pub(crate) connections: Registry,            // Data connections, admin connections are not listed
+    pub(crate) client_count: Arc<AtomicUsize>,
+    pub(crate) inflight: Arc<AtomicUsize>,
+    pub(crate) active_handlers: Arc<AtomicUsize>,
+}
+
+// Returns true for messages only answered on the admin port
+pub(crate) fn is_admin_message(message: &client_message::Message) -> bool {
+    matches!(
+        message,
+        client_message::Message::ClientInfoRequest(_)
+            | client_message::Message::MetricsRequest(_)
+            | client_message::Message::Kick(_)
+            | client_message::Message::Broadcast(_)
+    )
+}
+
+impl Admin {
+    // Answers one admin message, anything else is an Error
+    pub(crate) fn process(&self, message: client_message::Message) -> server_message::Message {
+        match message {
+            client_message::Message::ClientInfoRequest(_) => {
+                let mut addresses: Vec<SocketAddr> = self.connections.lock().unwrap().keys().copied().collect();
+                addresses.sort();
+                server_message::Message::ClientInfoResponse(ClientInfoResponse {
+                    clients: addresses
+                        .into_iter()
+                        .map(|addr| ClientInfo { address: addr.to_string() })
+                        .collect(),
+                })
+            }
+            client_message::Message::MetricsRequest(_) => server_message::Message::MetricsResponse(MetricsResponse {
+                active_clients: self.client_count.load(Ordering::SeqCst) as u64,
+                inflight: self.inflight.load(Ordering::SeqCst) as u64,
+                active_handlers: self.active_handlers.load(Ordering::SeqCst) as u64,
+            }),
+            client_message::Message::Kick(kick) => {
+                let kicked = match kick.address.parse::<SocketAddr>() {
+                    Ok(addr) => match self.connections.lock().unwrap().get(&addr) {
+                        Some(connection) => {
+                            info!("Admin kicked client {}", addr);
+                            let _ = connection.stream.shutdown(Shutdown::Both);   // Its handler thread unregisters it
+                            true
+                        }
+                        None => false,
+                    },
+                    Err(_) => {
+                        warn!("Kick with an invalid address: {}", kick.address);
+                        false
+                    }
+                };
+                server_message::Message::KickResponse(KickResponse { kicked })
+            }
+            client_message::Message::Broadcast(broadcast) => {
+                // Writers are cloned out so a blocked connection doesn't hold the registry lock
+                let writers: Vec<_> = self
+                    .connections
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(addr, connection)| (*addr, connection.writer.clone()))
+                    .collect();
+                let mut delivered = 0;
+                for (addr, writer) in writers {
+                    let notification = server_message::Message::Notification(Notification {
+                        topic: BROADCAST_TOPIC.to_string(),
+                        content: broadcast.content.clone(),
+                    });
+                    match writer.lock().unwrap().send(notification) {
+                        Ok(()) => delivered += 1,
+                        Err(e) => warn!("Failed to broadcast to {}: {}", addr, e),
+                    }
+                }
+                server_message::Message::BroadcastResponse(BroadcastResponse { delivered })
+            }
+            _ => server_message::Message::Error(Error {
+                reason: "Not an admin message".to_string(),
+            }),
+        }
+    }
+}
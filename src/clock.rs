@@ -0,0 +1,69 @@
+//Time source for every deadline the server and client compute (frame deadlines, idle and lifetime timeouts).
+//SystemClock is the real one, MockClock only moves when told to, so tests can expire timeouts without sleeping.
+
+//IMPORTS
+use std::{
+    sync::Mutex,                  //MockClock offset, advanced from the test thread
+    thread,                       //Waits between polls
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},     //Request expiry is wall-clock time
+};
+
+//Clock: returns the current instant, and waits between polls of a deadline computed from it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Blocks for `duration` of real time, a MockClock doesn't move meanwhile and its deadlines only pass on advance()
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+//SystemClock: Instant::now()
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+//MockClock: starts at the instant it was created and only moves forward through advance()
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    // Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+// Returns true once the wall clock passed `expires_at_unix_nanos`, never for 0 (no expiry)
+// Request expiry is an absolute wall-clock time set by the client, so it doesn't go through a Clock
+pub(crate) fn is_expired(expires_at_unix_nanos: u64) -> bool {
+    expires_at_unix_nanos != 0
+        && SystemTime::now().duration_since(UNIX_EPOCH).is_ok_and(|now| now.as_nanos() >= expires_at_unix_nanos as u128)
+}
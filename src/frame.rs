@@ -0,0 +1,141 @@
+//Length-prefixed framing shared by the server and the client.
+//Every protobuf message on the wire is preceded by its length as a 4-byte big-endian u32, so a reader always knows where one message ends and the next begins.
+//Reads and writes retry on ErrorKind::Interrupted (read_header, read_to_end and write_all do), so a signal never drops a connection.
+
+//encode_client_message() and decode_server_message() work on whole frames without a connection, for tooling such as record/replay.
+
+//IMPORTS
+use crate::message::{client_message, ClientMessage, ServerMessage};   //Protobuf-generated message types
+use prost::Message;                             //Protobuf encoding and decoding
+use std::io::{self, ErrorKind, Read, Write};    //I/O traits used to read/write frames on any stream
+
+pub const HEADER_LEN: usize = 4;     // Size of the length prefix in bytes
+
+// Writes a single frame (length prefix followed by the payload)
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(payload)?)         // One write so the header and body are not split into separate segments
+}
+
+// Returns the frame bytes of a payload, length prefix included
+fn encode_frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        io::Error::new(ErrorKind::InvalidInput, "Frame exceeds the maximum encodable length")
+    })?;
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+// Returns the exact bytes a client puts on the wire for `message`: the length prefix and a ClientMessage without
+// optional envelope fields
+// A message that doesn't fit a frame (4 GiB) fails with InvalidInput carrying an EncodeError
+pub fn encode_client_message(message: &client_message::Message) -> io::Result<Vec<u8>> {
+    let envelope = ClientMessage { message: Some(message.clone()), ..Default::default() };
+    let limit = u32::MAX as usize;
+    let required = envelope.encoded_len();
+    if required > limit {
+        return Err(io::Error::new(ErrorKind::InvalidInput, EncodeError { required, limit }));
+    }
+    encode_frame(&envelope.encode_to_vec())
+}
+
+// A message that couldn't be encoded because its body would exceed the frame size allowed, at most the 4 GiB a length
+// prefix can describe. Carried by the io::Error encoding returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeError {
+    pub required: usize,     // Encoded size of the message
+    pub limit: usize,        // Largest frame body allowed
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message needs {} bytes, frames are limited to {} bytes", self.required, self.limit)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl EncodeError {
+    // Returns the encode failure if `error` is one
+    pub fn find(error: &io::Error) -> Option<EncodeError> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<EncodeError>()).copied()
+    }
+}
+
+// Decodes one whole frame a server sent, length prefix included, bytes before or after that frame are an error
+pub fn decode_server_message(frame: &[u8]) -> io::Result<ServerMessage> {
+    let mut reader = frame;
+    let body = read_frame(&mut reader)?.ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Empty input, no frame"))?;
+    if !reader.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("{} bytes after the frame", reader.len())));
+    }
+    ServerMessage::decode(&body[..])
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Failed to decode ServerMessage: {}", e)))
+}
+
+// Reads a single frame, returns Ok(None) if the peer closed the connection before a new frame started
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    match read_header(reader)? {
+        Some(len) => read_body(reader, len).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Reads the length prefix, returns Ok(None) on a clean EOF between frames
+// EOF after part of the prefix is a truncated header and fails with UnexpectedEof, a read timeout after part of it
+// keeps its kind and carries a PartialHeader
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<usize>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut received = 0;
+    while received < HEADER_LEN {
+        match reader.read(&mut header[received..]) {
+            Ok(0) if received == 0 => return Ok(None),         // Peer disconnected between frames
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("Connection closed mid-header ({} of {} bytes)", received, HEADER_LEN),
+                ))
+            }
+            Ok(n) => received += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if received > 0 && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(io::Error::new(e.kind(), PartialHeader { received }))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(u32::from_be_bytes(header) as usize))
+}
+
+// A read that timed out after only part of a length prefix arrived, unlike a timeout before any of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialHeader {
+    pub received: usize,     // Bytes of the prefix read before the timeout
+}
+
+impl std::fmt::Display for PartialHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out mid-header ({} of {} bytes)", self.received, HEADER_LEN)
+    }
+}
+
+impl std::error::Error for PartialHeader {}
+
+impl PartialHeader {
+    // Returns the partial header if `error` is one
+    pub fn find(error: &io::Error) -> Option<PartialHeader> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<PartialHeader>()).copied()
+    }
+}
+
+// Reads a body of `len` bytes, the buffer grows with the bytes actually received rather than the declared length
+pub fn read_body<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed mid-frame"));   // Truncated frame
+    }
+    Ok(payload)
+}
@@ -0,0 +1,156 @@
+//Message handlers decide how the server answers each ClientMessage and whether the connection stays open afterwards.
+
+//IMPORTS
+use crate::message::{client_message, server_message, AddResponse};   //Protobuf-generated message types
+use log::info;                                                      //Logs handled requests
+use std::{
+    any::Any,                            //Values handlers keep in a ConnectionContext
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Condvar, Mutex},         //Cancellation flag shared between the connection and the work it started
+    time::Duration,                      //Bounded waits for cancellation
+};
+
+//HandlerAction: what the connection does once a message has been handled
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerAction {
+    Respond(server_message::Message),           // Send the response and keep the connection open
+    RespondMany(Vec<server_message::Message>),  // Send several responses in order and keep the connection open
+    RespondAndClose(server_message::Message),   // Send the response, then close the connection
+    Close,                                      // Close the connection without responding
+    Ignore,                                     // Neither respond nor close
+}
+
+//CancellationToken: cancelled when the client sends a Cancel for the request, long-running work checks it between steps
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<(Mutex<bool>, Condvar)>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        *self.0 .0.lock().unwrap() = true;
+        self.0 .1.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    // Waits up to `timeout` for a cancellation, returns true as soon as the token is cancelled
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (cancelled, changed) = &*self.0;
+        let guard = changed.wait_timeout_while(cancelled.lock().unwrap(), timeout, |cancelled| !*cancelled).unwrap();
+        *guard.0
+    }
+}
+
+//ConnectionContext: per-connection state handed to the handler with every message, dropped with the connection
+pub struct ConnectionContext {
+    pub(crate) peer: SocketAddr,
+    pub(crate) version: u32,             // Negotiated protocol version, the server's own until the client sends Hello
+    pub(crate) authenticated: bool,      // True once this connection sent a valid Auth
+    pub extensions: HashMap<String, Box<dyn Any + Send>>,   // Free for handlers to keep their own state in
+}
+
+impl ConnectionContext {
+    pub fn new(peer: SocketAddr, version: u32) -> Self {
+        ConnectionContext {
+            peer,
+            version,
+            authenticated: false,
+            extensions: HashMap::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+}
+
+//MessageHandler: called from the connection's handler thread for every decoded message, after authentication
+pub trait MessageHandler: Send + Sync {
+    fn handle(&self, message: client_message::Message, context: &mut ConnectionContext) -> HandlerAction;
+}
+
+// Closures can be used as handlers directly
+impl<F> MessageHandler for F
+where
+    F: Fn(client_message::Message, &mut ConnectionContext) -> HandlerAction + Send + Sync,
+{
+    fn handle(&self, message: client_message::Message, context: &mut ConnectionContext) -> HandlerAction {
+        self(message, context)
+    }
+}
+
+//DefaultHandler: echoes EchoMessage back and answers AddRequest with the sum
+pub struct DefaultHandler {
+    echo_repeat: u32,     // How many times each EchoMessage is sent back
+}
+
+impl DefaultHandler {
+    // Echoes every EchoMessage `echo_repeat` times, to exercise clients that expect several responses
+    pub fn with_echo_repeat(echo_repeat: u32) -> Self {
+        DefaultHandler { echo_repeat }
+    }
+}
+
+impl Default for DefaultHandler {
+    fn default() -> Self {
+        DefaultHandler { echo_repeat: 1 }
+    }
+}
+
+impl MessageHandler for DefaultHandler {
+    fn handle(&self, message: client_message::Message, _context: &mut ConnectionContext) -> HandlerAction {
+        match process(message) {
+            Some(server_message::Message::EchoMessage(echo)) if self.echo_repeat != 1 => HandlerAction::RespondMany(
+                (0..self.echo_repeat).map(|_| server_message::Message::EchoMessage(echo.clone())).collect(),
+            ),
+            Some(response) => HandlerAction::Respond(response),
+            None => HandlerAction::Ignore,
+        }
+    }
+}
+
+// Computes the default response to a message, without any I/O so it can be tested and reused directly
+// Returns None for messages that have no default answer (Auth, Ping, Hello, subscriptions, echo chunks, streams and admin
+// messages are answered by the server itself)
+pub fn process(message: client_message::Message) -> Option<server_message::Message> {
+    match message {
+        client_message::Message::EchoMessage(echo) => {
+            info!("Received: {}", echo.content);
+            Some(server_message::Message::EchoMessage(echo))          // Echo back the message
+        }
+        client_message::Message::AddRequest(add) => {
+            info!("Received AddRequest: {} + {}", add.a, add.b);
+            let sum = add.a as i128 + add.b as i128;           // Exact, two int64 values always fit
+            let overflowed = i64::try_from(sum).is_err();
+            Some(server_message::Message::AddResponse(AddResponse {
+                result: add.a.saturating_add(add.b),          // Saturate instead of panicking on overflow
+                big_result: if overflowed && add.allow_big_result { sum.to_string() } else { String::new() },
+                overflow: overflowed && !add.allow_big_result,
+                ..Default::default()      // Operands are only echoed by a server with echo_add_operands
+            }))
+        }
+        client_message::Message::Auth(_)
+        | client_message::Message::Subscribe(_)
+        | client_message::Message::Resubscribe(_)
+        | client_message::Message::Publish(_)
+        | client_message::Message::Ping(_)
+        | client_message::Message::EchoChunk(_)
+        | client_message::Message::Hello(_)
+        | client_message::Message::ClientInfoRequest(_)
+        | client_message::Message::MetricsRequest(_)
+        | client_message::Message::Kick(_)
+        | client_message::Message::Broadcast(_)
+        | client_message::Message::StreamRequest(_)
+        | client_message::Message::Cancel(_) => None,
+    }
+}
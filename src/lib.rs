@@ -1,5 +1,39 @@
+mod admin;
+pub mod clock;
+pub mod frame;
+pub mod handler;
+mod pool;
+mod priority;
+pub mod recording;
 pub mod server;
+mod subscription;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+    // An echo is built from its text and read back as text, EchoMessage::from("hi") or "hi".into()
+    impl From<&str> for EchoMessage {
+        fn from(content: &str) -> Self {
+            EchoMessage { content: content.to_string() }
+        }
+    }
+
+    impl From<String> for EchoMessage {
+        fn from(content: String) -> Self {
+            EchoMessage { content }
+        }
+    }
+
+    impl From<EchoMessage> for String {
+        fn from(echo: EchoMessage) -> Self {
+            echo.content
+        }
+    }
+
+    impl EchoMessage {
+        // Borrows the echoed text
+        pub fn as_str(&self) -> &str {
+            &self.content
+        }
+    }
 }
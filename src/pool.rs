@@ -0,0 +1,107 @@
+//Worker pools: handler calls for chosen message types run on a fixed set of threads instead of the connection's own.
+//Each pool has its own threads, so a flood of slow messages of one type queues up in its pool and can't starve the rest.
+//Queued messages are taken by priority, see ClientMessage.priority.
+
+//IMPORTS
+use crate::handler::{ConnectionContext, HandlerAction, MessageHandler};   //Runs the server's handler on the pool threads
+use crate::message::{client_message, server_message, Error};   //Protobuf-generated message types
+use crate::priority::{self, PrioritySender};           //Job queue, highest priority first
+use crate::clock::is_expired;                          //Requests that expired while queued are skipped
+use log::error;                                        //Logs a pool that lost its threads and handlers that panicked
+use std::{
+    io,                                    //A thread that can't be started fails the pool
+    mem,                                   //Moves the connection's context to the pool thread and back
+    panic::{self, AssertUnwindSafe},       //A panicking handler fails its message, not the pool thread
+    sync::{
+        atomic::{AtomicUsize, Ordering},   //Live thread count
+        mpsc::{self, Sender},              //Actions back
+        Arc,
+    },
+    thread,
+};
+
+// A message to handle with its expiry and its connection's context, and where to send the resulting action (None once
+// expired) and the context back
+type Job = (client_message::Message, u64, ConnectionContext, Sender<(Option<HandlerAction>, ConnectionContext)>);
+
+//WorkerPool: handler threads shared by every connection of a server, threads exit once the pool is dropped
+//Every thread is started up front by new(), so the first messages don't wait for a thread to be created. Threads are
+//named worker-pool-<message types>, e.g. worker-pool-AddRequest+EchoMessage
+pub(crate) struct WorkerPool {
+    jobs: PrioritySender<Job>,
+    live_threads: Arc<AtomicUsize>,     // Threads started and not yet exited, see thread_count()
+}
+
+// Held by a pool thread, the count drops when the thread exits, even by panicking
+struct LiveThread(Arc<AtomicUsize>);
+
+impl Drop for LiveThread {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WorkerPool {
+    // Starts `threads` threads, fails if one can't be started, those already running then exit with the dropped queue
+    pub(crate) fn new(message_types: &[String], threads: usize, handler: Arc<dyn MessageHandler>) -> io::Result<Self> {
+        let (jobs, queue) = priority::channel::<Job>(usize::MAX);
+        let live_threads = Arc::new(AtomicUsize::new(0));
+        for _ in 0..threads {
+            let queue = queue.clone();
+            let handler = handler.clone();
+            live_threads.fetch_add(1, Ordering::SeqCst);       // Counted from the spawn, so it is exact as soon as new() returns
+            let live = LiveThread(live_threads.clone());
+            thread::Builder::new()
+                .name(format!("worker-pool-{}", message_types.join("+")))
+                .spawn(move || {
+                    let _live = live;
+                    loop {
+                        let Ok((message, expires_at, mut context, reply)) = queue.recv() else {
+                            break;       // Pool dropped
+                        };
+                        let action = (!is_expired(expires_at)).then(|| {
+                            panic::catch_unwind(AssertUnwindSafe(|| handler.handle(message, &mut context))).unwrap_or_else(|_| {
+                                error!("Handler panicked on a worker pool thread.");
+                                internal_error()
+                            })
+                        });
+                        let _ = reply.send((action, context));
+                    }
+                })?;
+        }
+        Ok(WorkerPool { jobs, live_threads })
+    }
+
+    // Returns how many of the pool's threads are running
+    pub(crate) fn thread_count(&self) -> usize {
+        self.live_threads.load(Ordering::SeqCst)
+    }
+
+    // Handles `message` on one of the pool threads, waiting for a free one, and returns its action
+    // Waiting messages with a higher `priority` are handled first, None if it passed `expires_at` while it waited
+    pub(crate) fn run(&self, message: client_message::Message, priority: u8, expires_at: u64, context: &mut ConnectionContext) -> Option<HandlerAction> {
+        let (reply, action) = mpsc::channel();
+        // Left in place while the pool has it, only its handler state is lost if the pool never answers
+        let mut placeholder = ConnectionContext::new(context.peer, context.version);
+        placeholder.authenticated = context.authenticated;
+        let owned = mem::replace(context, placeholder);
+        let sent = self.jobs.send(priority, (message, expires_at, owned, reply));
+        match sent.ok().and_then(|_| action.recv().ok()) {
+            Some((action, owned)) => {
+                *context = owned;
+                action
+            }
+            None => {
+                error!("Worker pool has no threads left.");
+                Some(internal_error())
+            }
+        }
+    }
+}
+
+// Answer to a message whose handler call failed
+fn internal_error() -> HandlerAction {
+    HandlerAction::Respond(server_message::Message::Error(Error {
+        reason: "Internal error".to_string(),
+    }))
+}
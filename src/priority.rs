@@ -0,0 +1,174 @@
+//Priority channel: a queue with the mpsc interface whose receivers always get the highest-priority item first, in the order
+//they were sent among items of equal priority. Carries a connection's outbound frames and the worker pools' jobs.
+
+//IMPORTS
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,               //Pending items, highest priority on top
+    sync::{
+        mpsc::{RecvError, RecvTimeoutError, SendError, TrySendError},   //Same errors as mpsc, so callers handle both alike
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// A queued item, ordered by priority and then by how early it was sent
+struct Entry<T> {
+    priority: u8,
+    order: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.order.cmp(&self.order))     // Older first among equals
+    }
+}
+
+struct State<T> {
+    queue: BinaryHeap<Entry<T>>,
+    next_order: u64,
+    senders: usize,       // Live senders, receivers see the channel closed once it is empty and this reaches 0
+    receivers: usize,     // Live receivers, sends fail once this reaches 0
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    changed: Condvar,     // Signalled on every send, receive and dropped end
+    capacity: usize,
+}
+
+//PrioritySender: sending half, can be cloned
+pub(crate) struct PrioritySender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+//PriorityReceiver: receiving half, can be cloned so several threads take items from one queue
+pub(crate) struct PriorityReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+// Creates a channel holding at most `capacity` items (at least one), use usize::MAX for an unbounded one
+pub(crate) fn channel<T>(capacity: usize) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { queue: BinaryHeap::new(), next_order: 0, senders: 1, receivers: 1 }),
+        changed: Condvar::new(),
+        capacity: capacity.max(1),
+    });
+    (PrioritySender { shared: shared.clone() }, PriorityReceiver { shared })
+}
+
+impl<T> PrioritySender<T> {
+    // Queues `item` if there is room, like SyncSender::try_send
+    pub(crate) fn try_send(&self, priority: u8, item: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(item));
+        }
+        if state.queue.len() >= self.shared.capacity {
+            return Err(TrySendError::Full(item));
+        }
+        push(&mut state, priority, item);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    // Queues `item`, waiting for room, like SyncSender::send
+    pub(crate) fn send(&self, priority: u8, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.receivers > 0 && state.queue.len() >= self.shared.capacity {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        if state.receivers == 0 {
+            return Err(SendError(item));
+        }
+        push(&mut state, priority, item);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+fn push<T>(state: &mut State<T>, priority: u8, item: T) {
+    let order = state.next_order;
+    state.next_order += 1;
+    state.queue.push(Entry { priority, order, item });
+}
+
+impl<T> PriorityReceiver<T> {
+    // Takes the highest-priority item, waiting for one, fails once the queue is empty and every sender is gone
+    pub(crate) fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(entry) = state.queue.pop() {
+                self.shared.changed.notify_all();     // There is room again
+                return Ok(entry.item);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    // Like recv(), giving up after `timeout`
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(entry) = state.queue.pop() {
+                self.shared.changed.notify_all();
+                return Ok(entry.item);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self.shared.changed.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        PrioritySender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Clone for PriorityReceiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        PriorityReceiver { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for PrioritySender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T> Drop for PriorityReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receivers -= 1;
+        self.shared.changed.notify_all();
+    }
+}
@@ -0,0 +1,119 @@
+//Traffic recording: with ServerBuilder::record_traffic every connection writes the frames it receives and sends to a file,
+//and replay() feeds the client side of such a recording back at a server.
+//A recording is the MAGIC bytes followed by records: a direction byte, the time since the connection opened in
+//nanoseconds (u64, big-endian) and the frame itself, length prefix included. Bodies of frames rejected for their size
+//are never read, so they aren't recorded.
+
+//IMPORTS
+use crate::frame::{read_frame, write_frame};     //Records hold frames exactly as they were on the wire
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::Mutex,                                 //Shared by the connection's reader and its response writer
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAGIC: &[u8; 4] = b"REC1";                 // Start of every recording, the digit is the format version
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);   // Longest replay() waits for a response
+
+//Direction: which way a recorded frame went, seen from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,     // Sent by the client
+    Outbound,    // Sent by the server
+}
+
+//Record: one frame of a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    pub at: Duration,         // Since the connection opened (or, for replay() results, since the replay started)
+    pub frame: Vec<u8>,       // Frame body, an empty one is a heartbeat
+}
+
+// File a connection from `peer` is recorded to, inside the directory given to ServerBuilder::record_traffic
+// `connection` numbers the server's recordings from 0, so a peer port used again doesn't overwrite an earlier one
+pub fn recording_path(dir: &Path, peer: SocketAddr, connection: u64) -> PathBuf {
+    dir.join(format!("connection-{}-{}-{}.rec", connection, peer.ip(), peer.port()))
+}
+
+//Recorder: appends the frames of one connection to its recording
+pub(crate) struct Recorder {
+    file: Mutex<BufWriter<File>>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::options().write(true).create_new(true).open(path)?);
+        file.write_all(MAGIC)?;
+        Ok(Recorder { file: Mutex::new(file), started: Instant::now() })
+    }
+
+    // Appends a frame and flushes it, so a recording is complete up to the last frame even if the server dies
+    pub(crate) fn record(&self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let elapsed = self.started.elapsed().as_nanos() as u64;
+        file.write_all(&[direction as u8])?;
+        file.write_all(&elapsed.to_be_bytes())?;
+        write_frame(&mut *file, frame)?;
+        file.flush()
+    }
+}
+
+// Reads every record of a recording
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a traffic recording"));
+    }
+    let mut records = Vec::new();
+    loop {
+        let mut direction = [0u8; 1];
+        if reader.read(&mut direction)? == 0 {
+            return Ok(records);          // End of the recording
+        }
+        let direction = match direction[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            other => return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid direction {}", other))),
+        };
+        let mut at = [0u8; 8];
+        reader.read_exact(&mut at)?;
+        let frame = read_frame(&mut reader)?.ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Record without a frame"))?;
+        records.push(Record { direction, at: Duration::from_nanos(u64::from_be_bytes(at)), frame });
+    }
+}
+
+// Sends the inbound frames of a recording to `server` on a new connection, spaced as they were recorded, and returns
+// the responses: as many frames as the recording has outbound ones, fewer if the server closes the connection first
+pub fn replay(recording: impl AsRef<Path>, server: impl ToSocketAddrs) -> io::Result<Vec<Record>> {
+    let records = read_recording(recording)?;
+    let mut stream = TcpStream::connect(server)?;
+    stream.set_read_timeout(Some(REPLAY_TIMEOUT))?;
+    let mut reader = stream.try_clone()?;
+    let expected = records.iter().filter(|record| record.direction == Direction::Outbound).count();
+    let started = Instant::now();
+
+    // Responses are read while the requests go out, so a server blocked on writing never stalls the replay
+    let responses = thread::spawn(move || -> io::Result<Vec<Record>> {
+        let mut responses = Vec::with_capacity(expected);
+        while responses.len() < expected {
+            match read_frame(&mut reader)? {
+                Some(frame) => responses.push(Record { direction: Direction::Outbound, at: started.elapsed(), frame }),
+                None => break,       // Server closed the connection
+            }
+        }
+        Ok(responses)
+    });
+    for record in records.iter().filter(|record| record.direction == Direction::Inbound) {
+        thread::sleep(record.at.saturating_sub(started.elapsed()));
+        write_frame(&mut stream, &record.frame)?;
+    }
+    responses.join().map_err(|_| io::Error::other("Replay reader panicked"))?
+}
@@ -1,151 +1,2568 @@
+//IMPORTS
+use crate::admin::{self, Admin};            //Admin messages, answered on the admin port
+use crate::clock::{is_expired, Clock, SystemClock};     //Time source for deadlines and timeouts, request expiry
+use crate::frame::{read_body, read_header, write_frame, PartialHeader, HEADER_LEN};   //Length-prefixed framing shared with the client
+use crate::handler::{CancellationToken, ConnectionContext, DefaultHandler, HandlerAction, MessageHandler};   //Decides how each message is answered
+use crate::pool::WorkerPool;                //Handler threads dedicated to some message types
+use crate::priority::{self, PriorityReceiver, PrioritySender};   //Outbound frames, highest priority written first
+use crate::recording::{self, Direction, Recorder};   //Per-connection traffic recordings
+use crate::message::{client_message, server_message, AddResponse, AtCapacity, AuthResponse, ClientMessage, EchoChunk, EchoMessage, Error, Expired, Goodbye, HelloResponse, Pong, Published, QuotaExceeded, ServerBusy, ServerMessage, ShuttingDown, StatusUpdate, StreamEnd, StreamItem, StreamRequest, Subscribe, Subscribed, Unauthorized, UnsupportedOperation};  //Protobuf-generated message types used for encoding and decoding data.
+use crate::subscription::Subscriptions;    //Topic subscriptions shared by all connections
+use log::{error, info, warn};     //log macros: error!, info!, warn! are used for logging.
+use prost::Message;               //Used for encoding/decoding Protocol Buffers
+use serde::Serialize;             //ServerConfig snapshots can be dumped as JSON
+use socket2::{Domain, Protocol, SockRef, Socket, Type};   //Socket options std doesn't expose (buffer sizes, SO_REUSEPORT)
+use std::{
+    borrow::Borrow,                          //Frame bodies are read from the connection stream or a clone of it
+    collections::{HashMap, VecDeque},        //Registry of open connections keyed by peer address, accept queue
+    fmt,                                     //Bind addresses appear in log messages
+    path::PathBuf,                           //Traffic recording directory
+    io::{self, ErrorKind, Read, Write},      //Handles I/O errors, raw reads and discarded bodies
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},           //Provides networking utilities like TcpListener (server-side socket) and TcpStream (client-side connection).
+    sync::{                              //Includes synchronization primitives
+        atomic::{AtomicBool, AtomicU64, Ordering, AtomicUsize},     //Manages a shared flag for server state, atomic types for managing client counts safely
+        mpsc::{self, TrySendError},            //Readiness channel, full outbound queue
+        Arc, Condvar, Mutex, OnceLock,          //Ensures thread-safe sharing of resources, listeners bound once, client count changes
+    },
+    thread,                       //Used for creating threads
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},             // implementing delays and deadlines, wall clock seeds fault injection
+};
+
+// Latest protocol version this server speaks, see message_version() for what each version added
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// Topic a client subscribes to for StatusUpdates, see ServerBuilder::status_updates
+pub const STATUS_TOPIC: &str = "status";
+
+// Decides whether an Auth token is valid, provided through ServerBuilder::require_auth
+pub type AuthVerifier = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+// Receives server events, provided through ServerBuilder::on_event
+pub type EventListener = Arc<dyn Fn(&ServerEvent) + Send + Sync>;
+
+// Receives an AuditRecord for every handled message, provided through ServerBuilder::audit_sink
+pub type AuditSink = Arc<dyn Fn(&AuditRecord) + Send + Sync>;
+
+// Runs once per accept loop iteration, provided through ServerBuilder::accept_loop_hook
+pub type AcceptLoopHook = Arc<dyn Fn(&Server) + Send + Sync>;
+
+const DEFAULT_MAX_CLIENTS: usize = 100;     // Connection limit used when the builder doesn't set one
+const DEFAULT_FRAME_DEADLINE: Duration = Duration::from_secs(30);   // Time allowed to receive a frame body once its length prefix arrived
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;   // Frames above this size go through the LargeMessagePolicy
+const DEFAULT_MAX_PENDING_RESPONSES: usize = 1024;   // Responses a connection may have waiting to be written
+const TIMEOUT_POLL: Duration = Duration::from_millis(50);   // How often an idle connection checks its idle and lifetime timeouts
+const WRITER_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);   // Time a closing connection's writer gets to send what is queued
+const MAX_DECODE_FAILURES: usize = 3;       // Consecutive undecodable frames tolerated before disconnecting
+const LISTEN_BACKLOG: i32 = 128;            // Same backlog TcpListener::bind uses
+const SMALL_MESSAGE_RUN: usize = 16;        // Consecutive small frames after which adaptive nodelay turns Nagle off
+const MAX_STREAMS_PER_CONNECTION: usize = 8;      // StreamRequests a connection may have running at once
+const MAX_STREAM_ITEMS: u32 = 100_000;      // Largest count a StreamRequest may ask for
+const MAX_STREAM_INTERVAL: Duration = Duration::from_secs(60);     // Longest interval a StreamRequest may ask for
+const MAX_OPEN_CHUNKED_MESSAGES: usize = 16;    // Chunked echoes a connection may be sending at once
+const MAX_READ_AHEAD_BYTES: usize = 1024 * 1024;   // Pipelined frame bytes a connection reads ahead of the request it handles
+
+//Settings shared by every connection handler, filled in by ServerBuilder
+struct Settings {
+    auth_verifier: Option<AuthVerifier>, // Set when the server requires authentication
+    event_listener: Option<EventListener>,   // Notified of ServerEvents, None ignores them
+    audit_sink: Option<AuditSink>,       // Told about every handled message, None keeps no audit trail
+    accept_loop_hook: Option<AcceptLoopHook>,   // Embedder maintenance run by the accept loop
+    handler: Arc<dyn MessageHandler>,    // Answers every message once the connection is authenticated
+    frame_deadline: Option<Duration>,    // Per-frame assembly deadline, starts when the length prefix is read
+    header_timeout: Option<Duration>,    // Wait allowed for the next length prefix, None waits indefinitely
+    recv_buffer_size: Option<usize>,     // SO_RCVBUF for accepted sockets, None keeps the OS default
+    send_buffer_size: Option<usize>,     // SO_SNDBUF for accepted sockets, None keeps the OS default
+    slow_handler_threshold: Option<Duration>,   // Handler calls taking longer are logged at warn
+    max_message_size: usize,             // Largest frame body handled normally
+    max_reassembled_size: Option<usize>, // Largest echo reassembled from EchoChunks, None means max_message_size
+    large_message_policy: LargeMessagePolicy,   // What happens to frames above max_message_size
+    max_pending_responses: usize,        // Responses queued per connection before the backpressure policy applies
+    response_coalesce_window: Option<Duration>,  // Responses queued within this window go out in one write
+    buffered_writes: bool,               // The responses a handler returns together go out in one write
+    adaptive_nodelay: Option<usize>,     // Frames up to this size count as small, a run of them enables TCP_NODELAY
+    backpressure_policy: BackpressurePolicy,    // What happens when a connection's response queue is full
+    write_timeout: Option<Duration>,     // SO_SNDTIMEO for accepted sockets, None lets a response write block indefinitely
+    write_retry: WriteRetryPolicy,       // What happens when a response write times out
+    unknown_message_policy: UnknownMessagePolicy,   // What happens to a ClientMessage whose message is None
+    expired_request_policy: ExpiredRequestPolicy,   // What happens to a request dequeued after its expires_at_unix_nanos
+    draining_request_policy: DrainingRequestPolicy,   // What happens to a request arriving on an open connection while draining
+    strict_sequencing: bool,             // Every ClientMessage must carry the next seq of its connection
+    echo_add_operands: bool,             // AddResponses carry the operands of their AddRequest
+    fault_injection_rate: FaultRate,     // Fraction of requests answered with a fault instead of the handler, for chaos testing
+    fault_action: FaultAction,           // What an injected fault does
+    fault_rng: Mutex<u64>,               // SplitMix64 state deciding which requests fail, seeded for reproducible runs
+    max_concurrent_handlers: Option<usize>,     // Handler calls allowed at once across all connections, None means unlimited
+    worker_pool_configs: Vec<WorkerPoolConfig>, // Pools requested through ServerBuilder::worker_pool
+    worker_pools: HashMap<String, Arc<WorkerPool>>,   // Pool handling each assigned message type, started by build()
+    idle_timeout: Option<Duration>,      // Connections without a frame for this long are closed
+    max_connection_lifetime: Option<Duration>,  // Connections open for this long are closed
+    max_bytes_per_connection: Option<u64>,      // Connections that transferred more than this, both ways, are closed
+    status_interval: Option<Duration>,   // How often StatusUpdates go to STATUS_TOPIC subscribers, None sends none
+    thread_join_timeout: Option<Duration>,      // How long stopping waits for the connection threads, None waits indefinitely
+    thread_stack_size: Option<usize>,    // Stack size of connection threads, None keeps the std default
+    clock: Arc<dyn Clock>,               // Time source for every deadline and timeout
+    protocol_version: u32,               // Highest version offered to clients in Hello negotiation
+    record_dir: Option<PathBuf>,         // Every connection records its traffic to a file in this directory
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            auth_verifier: None,
+            event_listener: None,
+            audit_sink: None,
+            accept_loop_hook: None,
+            handler: Arc::new(DefaultHandler::default()),
+            frame_deadline: Some(DEFAULT_FRAME_DEADLINE),
+            header_timeout: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            slow_handler_threshold: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_reassembled_size: None,
+            large_message_policy: LargeMessagePolicy::default(),
+            max_pending_responses: DEFAULT_MAX_PENDING_RESPONSES,
+            response_coalesce_window: None,
+            buffered_writes: false,
+            adaptive_nodelay: None,
+            backpressure_policy: BackpressurePolicy::default(),
+            write_timeout: None,
+            write_retry: WriteRetryPolicy::default(),
+            unknown_message_policy: UnknownMessagePolicy::default(),
+            expired_request_policy: ExpiredRequestPolicy::default(),
+            draining_request_policy: DrainingRequestPolicy::default(),
+            strict_sequencing: false,
+            echo_add_operands: false,
+            fault_injection_rate: FaultRate::default(),
+            fault_action: FaultAction::default(),
+            fault_rng: Mutex::new(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)),
+            max_concurrent_handlers: None,
+            worker_pool_configs: Vec::new(),
+            worker_pools: HashMap::new(),
+            idle_timeout: None,
+            max_connection_lifetime: None,
+            max_bytes_per_connection: None,
+            status_interval: None,
+            thread_join_timeout: None,
+            thread_stack_size: None,
+            clock: Arc::new(SystemClock),
+            protocol_version: PROTOCOL_VERSION,
+            record_dir: None,
+        }
+    }
+}
+
+impl Settings {
+    // Builder for connection threads, with the configured stack size
+    fn thread_builder(&self) -> thread::Builder {
+        match self.thread_stack_size {
+            Some(bytes) => thread::Builder::new().stack_size(bytes),
+            None => thread::Builder::new(),
+        }
+    }
+
+    // Largest message a chunked echo may reassemble, max_message_size unless set
+    fn max_reassembled_size(&self) -> usize {
+        self.max_reassembled_size.unwrap_or(self.max_message_size)
+    }
+
+    // Draws whether the next request gets an injected fault, true for a fault_injection_rate fraction of draws
+    fn inject_fault(&self) -> bool {
+        if self.fault_injection_rate.value() <= 0.0 {
+            return false;
+        }
+        let mut state = self.fault_rng.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);       // SplitMix64
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < self.fault_injection_rate.value()     // Uniform in [0, 1)
+    }
+}
+
+//ResponseWriter: the write half of a connection, shared so other connections can push notifications to it
+//Responses are queued for the connection's writer thread, so a slow reader fills the queue instead of blocking the server unnoticed
+//The writer takes the highest-priority responses first, so urgent responses overtake a backlog of bulk ones. It stamps
+//seq on them in the order it writes them, so seq stays contiguous on the wire whatever the priorities
+pub(crate) struct ResponseWriter {
+    frames: PrioritySender<Vec<Vec<u8>>>,    // Encoded responses without their seq waiting for the writer thread, bounded by max_pending_responses
+    stream: TcpStream,              // Shut down when the queue overflows under BackpressurePolicy::Disconnect
+    policy: BackpressurePolicy,
+    queued: u64,           // Responses queued so far, sizes the seq the writer thread stamps on the next one
+    sent_at: u64,          // sent_at_unix_nanos of the request being answered, 0 between requests
+    correlation_id: u64,   // correlation_id of the request being answered, 0 between requests
+    priority: u8,          // Priority of the request being answered, 0 between requests
+    addr: SocketAddr,      // Peer address, reported in backpressure events
+    events: Option<EventListener>,
+    full: bool,            // The queue was found full and hasn't accepted a frame since, so one episode is reported once
+    tap: Option<Arc<Recorder>>,     // Records every response, see ServerBuilder::record_traffic
+    sent_bytes: u64,       // Bytes queued on this connection so far, counted against max_bytes_per_connection
+    received_bytes: Arc<AtomicU64>,    // Bytes read from this connection so far, counted by the handler thread
+    max_bytes: Option<u64>,            // max_bytes_per_connection
+    over_quota: bool,      // QuotaExceeded was queued, nothing else is sent afterwards
+    buffered: bool,        // Responses to one request go to the writer thread together, see ServerBuilder::buffered_writes
+    batch: Option<Vec<Vec<u8>>>,     // Responses collected since start_batch(), queued as one by flush_batch()
+    batch_bytes: usize,    // Size of the responses in the batch
+    batch_limit: usize,    // A batch reaching this size (max_message_size) is queued right away, so one write stays bounded
+}
+
+pub(crate) type SharedWriter = Arc<Mutex<ResponseWriter>>;
+
+//Connection: registry entry of an open connection
+pub(crate) struct Connection {
+    pub(crate) stream: TcpStream,       // Shut down to close the connection
+    pub(crate) writer: SharedWriter,    // Lets admin broadcasts reach the connection
+    pub(crate) last_activity: Arc<Mutex<Instant>>,   // Updated by the handler thread, picks the eviction victim
+    pub(crate) requests: Arc<Mutex<Vec<u64>>>,   // Ids of the requests being handled, see Server::inflight_requests
+}
+
+pub(crate) type Registry = Arc<Mutex<HashMap<SocketAddr, Connection>>>;
+
+impl ResponseWriter {
+    // Encodes a response as a ServerMessage and queues it for the writer thread, which sends it as one frame
+    // Blocks or fails according to the backpressure policy while the queue is full. Fails once the connection went over
+    // max_bytes_per_connection, the response that takes it over is still sent, followed by QuotaExceeded
+    pub(crate) fn send(&mut self, response: server_message::Message) -> io::Result<()> {
+        if self.over_quota {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Connection exceeded its byte quota"));
+        }
+        self.push(response)?;
+        self.check_quota().map(|_| ())
+    }
+
+    // Once the bytes received and sent add up to more than max_bytes_per_connection, queues a QuotaExceeded and shuts
+    // the connection's read side, so the handler thread closes it after the queue is flushed. This also holds for
+    // connections that mostly receive, such as subscribers. Returns true once the quota was exceeded
+    fn check_quota(&mut self) -> io::Result<bool> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(false);
+        };
+        if self.over_quota {
+            return Ok(true);
+        }
+        let transferred = self.received_bytes.load(Ordering::SeqCst) + self.sent_bytes;
+        if transferred <= max_bytes {
+            return Ok(false);
+        }
+        self.over_quota = true;
+        warn!("Client {} transferred {} bytes, over its {} byte quota; disconnecting.", self.addr, transferred, max_bytes);
+        let _ = self.stream.shutdown(Shutdown::Read);
+        self.push(server_message::Message::QuotaExceeded(QuotaExceeded { max_bytes, transferred }))?;
+        Ok(true)
+    }
+
+    fn push(&mut self, response: server_message::Message) -> io::Result<()> {
+        let payload = ServerMessage {
+            message: Some(response),
+            seq: 0,            // Stamped by the writer thread
+            sent_at_unix_nanos: self.sent_at,
+            correlation_id: self.correlation_id,
+        }
+        .encode_to_vec();   //Serialize the response
+        // Whichever order the writer thread takes them in, the queued responses get the seqs 0..queued between them, so
+        // counting each one's seq field as if it went out in queue order adds up to the bytes actually sent
+        let seq_len = ServerMessage { seq: self.queued, ..Default::default() }.encoded_len();
+        if payload.len() + seq_len > u32::MAX as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Frame exceeds the maximum encodable length"));
+        }
+        self.queued += 1;
+        self.sent_bytes += (HEADER_LEN + payload.len() + seq_len) as u64;
+        let Some(batch) = &mut self.batch else {
+            return self.enqueue(vec![payload]);
+        };
+        self.batch_bytes += payload.len();
+        batch.push(payload);
+        if self.batch_bytes < self.batch_limit {
+            return Ok(());
+        }
+        let full = std::mem::take(batch);      // E.g. a streamed echo, queued in pieces instead of one huge write
+        self.batch_bytes = 0;
+        self.enqueue(full)
+    }
+
+    // Under buffered writes, collects the frames sent from here on until flush_batch()
+    fn start_batch(&mut self) {
+        if self.buffered {
+            self.batch = Some(Vec::new());
+            self.batch_bytes = 0;
+        }
+    }
+
+    // Queues the responses collected since start_batch() for one socket write
+    fn flush_batch(&mut self) -> io::Result<()> {
+        match self.batch.take() {
+            Some(batch) if !batch.is_empty() => self.enqueue(batch),
+            _ => Ok(()),
+        }
+    }
+
+    // Hands encoded responses to the writer thread, blocking or failing according to the backpressure policy when full
+    fn enqueue(&mut self, frame: Vec<Vec<u8>>) -> io::Result<()> {
+        let stopped = || io::Error::new(ErrorKind::BrokenPipe, "Connection writer stopped");
+        let frame = match self.frames.try_send(self.priority, frame) {
+            Ok(()) => {
+                self.full = false;
+                return Ok(());
+            }
+            Err(TrySendError::Full(frame)) => frame,
+            Err(TrySendError::Disconnected(_)) => return Err(stopped()),
+        };
+        if !std::mem::replace(&mut self.full, true) {
+            if let Some(listener) = &self.events {
+                listener(&ServerEvent::Backpressure { connection: self.addr, action: self.policy });
+            }
+        }
+        match self.policy {
+            BackpressurePolicy::Block => self.frames.send(self.priority, frame).map_err(|_| stopped()),     //Send it back once there is room
+            BackpressurePolicy::Disconnect => {
+                warn!("Client is not reading its responses; disconnecting.");
+                let _ = self.stream.shutdown(Shutdown::Both);
+                Err(io::Error::new(ErrorKind::WouldBlock, "Too many pending responses"))
+            }
+        }
+    }
+}
+
+// Writes queued responses to the connection until every ResponseWriter is gone or a write fails, stamping each with the
+// next seq of the connection as it goes, so seq follows the order on the wire
+// With a coalesce window, responses queued within the window after the first one are written together
+// The returned receiver sees the channel disconnect as soon as the thread exits, panics included
+#[allow(clippy::too_many_arguments)]
+fn spawn_writer(
+    builder: thread::Builder,
+    mut stream: TcpStream,
+    frames: PriorityReceiver<Vec<Vec<u8>>>,
+    addr: SocketAddr,
+    coalesce_window: Option<Duration>,
+    write_retry: WriteRetryPolicy,
+    writes: Arc<AtomicUsize>,
+    tap: Option<Arc<Recorder>>,
+    clock: Arc<dyn Clock>,
+) -> io::Result<(thread::JoinHandle<()>, mpsc::Receiver<()>)> {
+    let (done, finished) = mpsc::channel::<()>();
+    let thread = builder.spawn(move || {
+        let _done = done;       // Dropped on exit, wakes close_after_flush()
+        let mut next_seq = 0;      // Contiguous per connection, so clients can detect drops and reordering
+        while let Ok(mut payloads) = frames.recv() {
+            if let Some(window) = coalesce_window {
+                let deadline = clock.now() + window;
+                loop {
+                    let remaining = deadline.saturating_duration_since(clock.now());
+                    if remaining.is_zero() {
+                        break;
+                    }
+                    match frames.recv_timeout(remaining) {
+                        Ok(frame) => payloads.extend(frame),
+                        Err(_) => break,          // Window over or every ResponseWriter gone, flush what we have
+                    }
+                }
+            }
+            let mut batch = Vec::new();
+            for mut payload in payloads {
+                payload.extend(ServerMessage { seq: next_seq, ..Default::default() }.encode_to_vec());  // A field appended to an encoded message is decoded like any other
+                next_seq += 1;
+                record(&tap, Direction::Outbound, &payload, addr);
+                let _ = write_frame(&mut batch, &payload);      // Can't fail, push() checked the length
+            }
+            writes.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = write_batch(&mut stream, &batch, write_retry, addr, &*clock) {
+                error!("Failed to write to client ({}): {}", addr, e);
+                let _ = stream.shutdown(Shutdown::Both);     // Wakes the reader so the connection closes
+                break;
+            }
+        }
+    })?;
+    Ok((thread, finished))
+}
+
+// Writes a whole batch, a write that times out is retried from where it stopped as long as the retry policy allows
+fn write_batch(stream: &mut TcpStream, batch: &[u8], write_retry: WriteRetryPolicy, addr: SocketAddr, clock: &dyn Clock) -> io::Result<()> {
+    let mut written = 0;
+    let mut give_up_at = None;     // Set by the first timeout of this batch
+    while written < batch.len() {
+        match stream.write(&batch[written..]) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Connection stopped accepting data")),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => match write_retry {
+                WriteRetryPolicy::Disconnect => return Err(e),
+                WriteRetryPolicy::Retry(deadline) => {
+                    let give_up_at = *give_up_at.get_or_insert_with(|| {
+                        warn!("Write to client {} timed out; retrying for up to {:?}.", addr, deadline);
+                        clock.now() + deadline
+                    });
+                    if clock.now() >= give_up_at {
+                        return Err(e);        // The client didn't catch up in time
+                    }
+                }
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Gives a closing connection's writer thread up to WRITER_FLUSH_TIMEOUT to send what is queued and exit, then closes
+// the socket, which also wakes a writer still blocked on a client that stopped reading
+fn close_after_flush(writer: thread::JoinHandle<()>, finished: mpsc::Receiver<()>, stream: Option<TcpStream>) {
+    let _ = finished.recv_timeout(WRITER_FLUSH_TIMEOUT);      // Nothing is ever sent, it returns once the writer exits
+    if let Some(stream) = stream {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    let _ = writer.join();
+}
+
+// Tells a refused client the server is full, so it can stop retrying, then closes the connection
+// Best effort: a fresh socket has room for one small frame, a failed write just closes it
+fn reject_at_capacity(mut stream: TcpStream, max_clients: usize) {
+    let rejection = ServerMessage {
+        message: Some(server_message::Message::AtCapacity(AtCapacity { max_clients: max_clients as u32 })),
+        ..Default::default()
+    };
+    let _ = write_frame(&mut stream, &rejection.encode_to_vec());
+}
+
+// Tells a client the server can't serve it right now, e.g. no thread could be started for it, then closes the connection
+// Best effort, like reject_at_capacity
+fn reject_busy(mut stream: &TcpStream) {
+    let rejection = ServerMessage {
+        message: Some(server_message::Message::ServerBusy(ServerBusy {})),
+        ..Default::default()
+    };
+    let _ = write_frame(&mut stream, &rejection.encode_to_vec());
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// Accepts a pending connection from the first listener that has one, WouldBlock if none has
+fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    for listener in listeners {
+        match listener.accept() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+    }
+    Err(io::Error::from(ErrorKind::WouldBlock))
+}
+
+// Appends a frame to the connection's recording, a failed write is logged and the connection carries on
+fn record(tap: &Option<Arc<Recorder>>, direction: Direction, frame: &[u8], addr: SocketAddr) {
+    if let Some(tap) = tap {
+        if let Err(e) = tap.record(direction, frame) {
+            warn!("Failed to record traffic of {}: {}", addr, e);
+        }
+    }
+}
+
+//Client Struct
+struct Client {               //The stream field holds the read half of the TCP connection to the client.
+    stream: TcpStream,
+    writer: SharedWriter,                // Write half, also used by Publish from other connections
+    addr: SocketAddr,                    // Peer address, identifies the connection in the subscription registry
+    retries: usize, // Track retry attempts for errors
+    settings: Arc<Settings>,             // Server-wide options
+    context: ConnectionContext,          // Negotiated version, auth state and handler state, passed to every handler call
+    inflight: Arc<AtomicUsize>,          // Server-wide count of messages being processed, reported while draining
+    subscriptions: Arc<Subscriptions>,   // Server-wide topic subscriptions
+    active_handlers: Arc<AtomicUsize>,   // Server-wide count of running handler calls
+    connected_at: Instant,               // For max_connection_lifetime, from the server clock
+    last_activity: Arc<Mutex<Instant>>,  // When the last frame arrived, for idle_timeout, shared with the registry entry
+    session: Option<String>,             // Session token, issued on the first Subscribe or restored by Resubscribe
+    admin: Option<Arc<Admin>>,           // Set on admin port connections, which only accept admin messages
+    chunks: HashMap<u64, (u32, String)>, // Echo chunks being reassembled: next expected index and content so far, by message id
+    small_frames: usize,                 // Consecutive frames under the adaptive nodelay threshold, until nodelay is on
+    streams: HashMap<u64, (CancellationToken, thread::JoinHandle<()>)>,   // Streams started by StreamRequest, by request id
+    tap: Option<Arc<Recorder>>,          // Records every frame received, shared with the response writer
+    received_bytes: Arc<AtomicU64>,      // Bytes read from this connection so far, shared with the writer for max_bytes_per_connection
+    priority: u8,                        // Priority of the message being handled, orders worker pool jobs
+    expires_at: u64,                     // expires_at_unix_nanos of the message being handled, 0 if it doesn't expire
+    next_seq: u64,                       // seq the next ClientMessage must carry under strict sequencing
+    draining: Arc<AtomicBool>,           // Set while the server drains, requests then follow the draining request policy
+    requests: Arc<Mutex<Vec<u64>>>,      // correlation_id of each request read but not answered yet, shared with the registry entry
+    read_ahead: VecDeque<(usize, Result<ClientMessage, prost::DecodeError>)>,   // Pipelined frames read before their turn, with their length
+}
+
+//Client Implementation
+impl Client {
+    // 1- new() Method
+    pub fn new(stream: TcpStream, writer: SharedWriter, addr: SocketAddr, server: &Server, admin_port: bool) -> Self {
+        let now = server.settings.clock.now();
+        let (tap, received_bytes) = {
+            let writer = writer.lock().unwrap();
+            (writer.tap.clone(), writer.received_bytes.clone())
+        };
+        Client {
+            stream,     //Constructs a new Client instance with the provided TcpStream
+            writer,
+            addr,
+            retries: 0,
+            settings: server.settings.clone(),
+            context: ConnectionContext::new(addr, server.settings.protocol_version),
+            inflight: server.inflight.clone(),
+            subscriptions: server.subscriptions.clone(),
+            active_handlers: server.active_handlers.clone(),
+            connected_at: now,
+            last_activity: Arc::new(Mutex::new(now)),
+            session: None,
+            admin: admin_port.then(|| server.admin.clone()),
+            chunks: HashMap::new(),
+            small_frames: 0,
+            streams: HashMap::new(),
+            tap,
+            received_bytes,
+            priority: 0,
+            expires_at: 0,
+            next_seq: 0,
+            draining: server.draining.clone(),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            read_ahead: VecDeque::new(),
+        }
+    }
+
+    // 2- handle() Method
+    // Reads one framed ClientMessage and answers it, returns Ok(false) once the client has disconnected
+    // Error behavior: a frame whose body doesn't decode gets no response and is skipped, the connection is dropped
+    // after more than MAX_DECODE_FAILURES consecutive failures. A frame cut short by EOF closes the connection.
+    pub fn handle(&mut self) -> io::Result<bool> {
+        if self.quota_exceeded()? {
+            return Ok(false);
+        }
+        let (len, decoded) = match self.read_ahead.pop_front() {
+            Some(frame) => frame,
+            None => {
+                if !self.wait_for_frame()? {
+                    return Ok(false);
+                }
+                // Read one frame from the client, the header within the header timeout and the body before the frame deadline
+                if self.settings.header_timeout.is_some() {
+                    self.stream.set_read_timeout(self.settings.header_timeout)?;
+                }
+                let header = read_header(&mut self.stream);
+                if self.settings.header_timeout.is_some() {
+                    self.stream.set_read_timeout(None)?;
+                }
+                let len = match header {
+                    Ok(Some(len)) => len,
+                    Ok(None) => {
+                        info!("Client disconnected.");
+                        return Ok(false);
+                    }
+                    // The client closed partway through a length prefix, nothing was misread as a length
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        warn!("Client {} disconnected with a truncated frame header: {}", self.addr, e);
+                        return Ok(false);
+                    }
+                    // Part of a prefix then silence is a stalled or broken client, not an idle one
+                    Err(e) if PartialHeader::find(&e).is_some() && self.settings.header_timeout.is_some() => {
+                        warn!("Client {} stalled within the header timeout: {}; closing.", self.addr, e);
+                        return Ok(false);
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) && self.settings.header_timeout.is_some() => {
+                        info!("Client {} sent no message within the header timeout; closing.", self.addr);
+                        return Ok(false);
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.received_bytes.fetch_add(HEADER_LEN as u64, Ordering::SeqCst);
+                *self.last_activity.lock().unwrap() = self.settings.clock.now();
+                if len == 0 {
+                    record(&self.tap, Direction::Inbound, &[], self.addr);
+                    return Ok(true);      // Zero-length frames are client heartbeats, there is nothing to decode or answer
+                }
+                self.track_small_frames(len)?;
+                let oversized = len > self.settings.max_message_size;
+                if oversized && self.settings.large_message_policy == LargeMessagePolicy::Disconnect {
+                    warn!("Rejected a {} byte message from {}, the limit is {} bytes; disconnecting.", len, self.addr, self.settings.max_message_size);
+                    let _ = self.writer.lock().unwrap().send(server_message::Message::Error(Error {     // Best effort, the close may reset it
+                        reason: format!("Message of {} bytes exceeds the {} byte limit", len, self.settings.max_message_size),
+                    }));
+                    return Ok(false);       // The body is never read, so it doesn't count as received
+                }
+                self.received_bytes.fetch_add(len as u64, Ordering::SeqCst);       // A discarded body counts too, it was transferred
+                if oversized && self.settings.large_message_policy == LargeMessagePolicy::Reject {
+                    self.discard_body(len)?;
+                    return self.reject_large(len);
+                }
+                let decoded = if oversized {
+                    match self.read_large_echo(len)? {
+                        LargeEcho::Truncated(decoded) => decoded,
+                        LargeEcho::Answered(open) => return Ok(open),
+                    }
+                } else {
+                    let frame = match self.settings.frame_deadline {
+                        Some(deadline) => {
+                            let mut frame = Vec::new();       // Grows with the bytes actually received, not the declared length
+                            BodyReader::new(&self.stream, len, Some(deadline), &*self.settings.clock).read_to_end(&mut frame)?;
+                            frame
+                        }
+                        None => read_body(&mut self.stream, len)?,
+                    };
+                    record(&self.tap, Direction::Inbound, &frame, self.addr);
+                    ClientMessage::decode(&frame[..])
+                };
+                self.track_request(&decoded);
+                (len, decoded)
+            }
+        };
+//Message Handling: process() decides the answer to the decoded ClientMessage and write_action() sends it. Errors are logged if decoding fails
+        match decoded {
+            Ok(request) => {
+                self.retries = 0;
+                let _inflight = InFlight::start(&self.inflight);    // Counted until the response is queued
+                self.read_ahead()?;       // Requests pipelined behind this one become visible while it is handled
+                let kind = request.message.as_ref().map_or("None", message_kind);
+                let handled_at = self.settings.clock.now();
+                #[cfg(feature = "tracing")]
+                let _span = tracing::info_span!("message", message_type = kind).entered();
+                self.priority = request.priority.min(u8::MAX as u32) as u8;     // Larger values count as the highest
+                self.expires_at = request.expires_at_unix_nanos;
+                let action = match self.out_of_sequence(request.seq) {
+                    Some(reason) => HandlerAction::Respond(server_message::Message::Error(Error { reason })),
+                    // Waited in the socket buffer behind slower requests. An unauthenticated client is told to authenticate instead
+                    None if is_expired(self.expires_at) && !self.unauthenticated() => self.expire(),
+                    None if self.admin.is_none() && self.draining.load(Ordering::SeqCst) => self.while_draining(request.message),
+                    None => self.process(request.message),
+                };
+                let mut writer = self.writer.lock().unwrap();
+                writer.sent_at = request.sent_at_unix_nanos;     // Echoed on every response to this request
+                writer.correlation_id = request.correlation_id;
+                writer.priority = self.priority;
+                let result = AuditResult::of(&action);
+                let open = write_action(&mut writer, action);
+                writer.sent_at = 0;
+                writer.correlation_id = 0;
+                writer.priority = 0;
+                drop(writer);
+                if request.correlation_id != 0 {
+                    let mut requests = self.requests.lock().unwrap();
+                    if let Some(index) = requests.iter().position(|id| *id == request.correlation_id) {
+                        requests.remove(index);
+                    }
+                }
+                if let Some(sink) = &self.settings.audit_sink {
+                    sink(&AuditRecord {
+                        peer: self.addr,
+                        message_type: kind,
+                        size: len,
+                        result: if open.is_err() { AuditResult::Failed } else { result },
+                        latency: self.settings.clock.now().saturating_duration_since(handled_at),
+                    });
+                }
+                #[cfg(feature = "tracing")]
+                tracing::info!(latency_us = self.settings.clock.now().saturating_duration_since(handled_at).as_micros() as u64, "Message handled");
+                if !open? {
+                    return Ok(false);
+                }
+            }
+            Err(e) => return self.decode_failed(e),
+        }
+
+        Ok(true)
+    }
+
+    // Counts a frame whose body didn't decode, it gets no response. Too many in a row drop the connection
+    fn decode_failed(&mut self, e: impl std::fmt::Display) -> io::Result<bool> {
+        self.retries += 1;
+        error!(
+            "Failed to decode message (attempt {}): {}",
+            self.retries, e
+        );
+        if self.retries > MAX_DECODE_FAILURES {
+            warn!("Too many decoding errors; disconnecting client.");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Maximum retries reached",
+            ));
+        }
+        Ok(true)
+    }
+
+    // Lists the id of a request that was just read, see Server::inflight_requests. Requests without one aren't listed
+    fn track_request(&self, decoded: &Result<ClientMessage, prost::DecodeError>) {
+        if let Ok(request) = decoded {
+            if request.correlation_id != 0 {
+                self.requests.lock().unwrap().push(request.correlation_id);
+            }
+        }
+    }
+
+    // Reads the frames that already arrived whole behind the request being handled, without blocking
+    // Partial and oversized frames, and those past MAX_READ_AHEAD_BYTES, stay in the socket buffer until their turn
+    fn read_ahead(&mut self) -> io::Result<()> {
+        let mut buffered: usize = self.read_ahead.iter().map(|(len, _)| len).sum();
+        while let Some(len) = peek_frame_len(&self.stream, self.settings.max_message_size.min(MAX_READ_AHEAD_BYTES - buffered))? {
+            read_header(&mut self.stream)?;      // Arrived whole, neither read blocks
+            let frame = read_body(&mut self.stream, len)?;
+            self.received_bytes.fetch_add((HEADER_LEN + len) as u64, Ordering::SeqCst);
+            *self.last_activity.lock().unwrap() = self.settings.clock.now();
+            record(&self.tap, Direction::Inbound, &frame, self.addr);
+            if len == 0 {
+                continue;       // Heartbeat
+            }
+            self.track_small_frames(len)?;
+            let decoded = ClientMessage::decode(&frame[..]);
+            self.track_request(&decoded);
+            self.read_ahead.push_back((len, decoded));
+            buffered += len;
+        }
+        Ok(())
+    }
+
+    // Tells the client the server is stopping, once the request in flight (if any) was answered
+    // Best effort, the client may already be gone
+    fn goodbye(&mut self) {
+        if let Err(e) = self.writer.lock().unwrap().send(server_message::Message::Goodbye(Goodbye {})) {
+            warn!("Failed to say goodbye to {}: {}", self.addr, e);
+        }
+    }
+
+    // Under strict sequencing, checks `seq` against the one expected next and returns why it is out of sequence, if it is
+    fn out_of_sequence(&mut self, seq: u64) -> Option<String> {
+        if !self.settings.strict_sequencing {
+            return None;
+        }
+        if seq == self.next_seq {
+            self.next_seq = seq.wrapping_add(1);
+            return None;
+        }
+        let kind = if seq > self.next_seq { "gap" } else { "regression" };
+        warn!("Sequence {} from {}: expected {}, got {}.", kind, self.addr, self.next_seq, seq);
+        let reason = format!("Sequence {}: expected {}, got {}", kind, self.next_seq, seq);
+        if seq > self.next_seq {
+            self.next_seq = seq.wrapping_add(1);      // Reported once, the messages after it are checked against it
+        }
+        Some(reason)
+    }
+
+    // Answer to a request that expired before it was handled, according to the expired request policy
+    fn expire(&self) -> HandlerAction {
+        info!("Dropped an expired request from {}.", self.addr);
+        match self.settings.expired_request_policy {
+            ExpiredRequestPolicy::Drop => HandlerAction::Ignore,
+            ExpiredRequestPolicy::Reply => HandlerAction::Respond(server_message::Message::Expired(Expired {})),
+        }
+    }
+
+    // Returns true while the server requires authentication and this connection hasn't authenticated
+    fn unauthenticated(&self) -> bool {
+        self.settings.auth_verifier.is_some() && !self.context.authenticated
+    }
+
+    // Answer to a request that arrived while the server drains, according to the draining request policy
+    fn while_draining(&mut self, message: Option<client_message::Message>) -> HandlerAction {
+        match self.settings.draining_request_policy {
+            DrainingRequestPolicy::Serve => self.process(message),
+            DrainingRequestPolicy::Reject => {
+                info!("Rejected a request from {}, the server is draining.", self.addr);
+                HandlerAction::Respond(server_message::Message::ShuttingDown(ShuttingDown {}))
+            }
+            DrainingRequestPolicy::Ignore => {
+                info!("Ignored a request from {}, the server is draining.", self.addr);
+                HandlerAction::Ignore
+            }
+        }
+    }
+
+    // Sends QuotaExceeded and returns true once the connection transferred more than max_bytes_per_connection
+    // Checked between frames, so the response that crossed the quota is still delivered
+    fn quota_exceeded(&mut self) -> io::Result<bool> {
+        self.writer.lock().unwrap().check_quota()
+    }
+
+    // Turns Nagle off once the client sent SMALL_MESSAGE_RUN small frames in a row, it stays off for the connection
+    // A larger frame restarts the run, bulk senders keep Nagle on
+    fn track_small_frames(&mut self, len: usize) -> io::Result<()> {
+        let Some(threshold) = self.settings.adaptive_nodelay else {
+            return Ok(());
+        };
+        if self.small_frames >= SMALL_MESSAGE_RUN {
+            return Ok(());        // Already enabled
+        }
+        if len > threshold {
+            self.small_frames = 0;
+            return Ok(());
+        }
+        self.small_frames += 1;
+        if self.small_frames == SMALL_MESSAGE_RUN {
+            self.stream.set_nodelay(true)?;      // Shared with the writer thread's clone
+            info!("Enabled TCP_NODELAY for {} after {} small messages.", self.addr, SMALL_MESSAGE_RUN);
+            if let Some(listener) = &self.settings.event_listener {
+                listener(&ServerEvent::NodelayEnabled { connection: self.addr });
+            }
+        }
+        Ok(())
+    }
+
+    // Waits until the next frame starts arriving, returns Ok(false) once the idle timeout or the lifetime expired
+    // Without either timeout the header read below simply blocks
+    fn wait_for_frame(&mut self) -> io::Result<bool> {
+        if self.settings.idle_timeout.is_none() && self.settings.max_connection_lifetime.is_none() {
+            return Ok(true);
+        }
+        loop {
+            let now = self.settings.clock.now();
+            if self.settings.max_connection_lifetime.is_some_and(|lifetime| now >= self.connected_at + lifetime) {
+                info!("Connection {} reached its maximum lifetime; closing.", self.addr);
+                return Ok(false);
+            }
+            if self.settings.idle_timeout.is_some_and(|idle| now >= *self.last_activity.lock().unwrap() + idle) {
+                info!("Connection {} was idle too long; closing.", self.addr);
+                return Ok(false);
+            }
+            self.stream.set_read_timeout(Some(TIMEOUT_POLL))?;
+            match self.stream.peek(&mut [0u8; 1]) {
+                Ok(_) => break,       // Data or EOF, either way read_header() handles it
+                Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.stream.set_read_timeout(None)?;
+        Ok(true)
+    }
+
+    // Reads and drops a frame body without buffering it, still bounded by the frame deadline
+    fn discard_body(&self, len: usize) -> io::Result<()> {
+        let mut body = BodyReader::new(&self.stream, len, self.settings.frame_deadline, &*self.settings.clock);
+        io::copy(&mut body, &mut io::sink()).map(drop)
+    }
+
+    // Answers an oversized message whose body was dropped with an Error, the connection stays usable
+    fn reject_large(&self, len: usize) -> io::Result<bool> {
+        warn!("Rejected a {} byte message, the limit is {} bytes.", len, self.settings.max_message_size);
+        self.writer.lock().unwrap().send(server_message::Message::Error(Error {
+            reason: format!("Message of {} bytes exceeds the {} byte limit", len, self.settings.max_message_size),
+        }))?;
+        Ok(true)
+    }
+
+    // Reads an oversized message under the Truncate and Stream policies, never holding more than max_message_size of it
+    // Only an EchoMessage can be cut or streamed, any other oversized message is rejected as under Reject. Truncate
+    // keeps the first bytes of the content and skips the rest, Stream hands the content to process() one chunk at a time
+    // as it arrives. The envelope fields follow the content on the wire, so the chunks of a stream go out without them
+    fn read_large_echo(&mut self, len: usize) -> io::Result<LargeEcho> {
+        let settings = self.settings.clone();
+        let mut body = BodyReader::new(self.stream.try_clone()?, len, settings.frame_deadline, &*settings.clock);
+        let Some(content_len) = echo_content_len(&mut body)? else {
+            io::copy(&mut body, &mut io::sink())?;
+            return self.reject_large(len).map(LargeEcho::Answered);
+        };
+        let content = match self.settings.large_message_policy {
+            LargeMessagePolicy::Truncate(limit) => {
+                let mut content = Vec::new();
+                (&mut body).take(limit.min(content_len) as u64).read_to_end(&mut content)?;
+                io::copy(&mut (&mut body).take((content_len - content.len()) as u64), &mut io::sink())?;
+                if let Err(e) = std::str::from_utf8(&content) {
+                    match e.error_len() {
+                        None => content.truncate(e.valid_up_to()),     // Cut inside a character
+                        Some(_) => return self.discard_undecodable(&mut body, e),
+                    }
+                }
+                Some(content)
+            }
+            _ => {
+                let _inflight = InFlight::start(&self.inflight);    // Counted until the last chunk is queued
+                let mut pending: Vec<u8> = Vec::new();    // Content read but not sent yet, at most a split character between chunks
+                let mut left = content_len;
+                while left > 0 {
+                    let wanted = self.settings.max_message_size.saturating_sub(pending.len()).clamp(1, left);
+                    let read = (&mut body).take(wanted as u64).read_to_end(&mut pending)?;
+                    left -= read;
+                    let valid = match std::str::from_utf8(&pending) {
+                        Ok(_) => pending.len(),
+                        Err(e) if e.error_len().is_none() && left > 0 => e.valid_up_to(),      // Completed by the next chunk
+                        Err(e) => return self.discard_undecodable(&mut body, e),
+                    };
+                    if valid == 0 {
+                        continue;       // A limit below one character still makes progress
+                    }
+                    let content = String::from_utf8(pending.drain(..valid).collect()).expect("Checked above");
+                    let action = self.process(Some(client_message::Message::EchoMessage(EchoMessage { content })));
+                    if !write_action(&mut self.writer.lock().unwrap(), action)? {
+                        return Ok(LargeEcho::Answered(false));
+                    }
+                }
+                None
+            }
+        };
+        // The envelope fields after the content are as small as those of any message, a larger rest isn't one
+        if body.remaining > self.settings.max_message_size {
+            io::copy(&mut body, &mut io::sink())?;
+            return self.reject_large(len).map(LargeEcho::Answered);
+        }
+        let mut envelope = Vec::new();
+        body.read_to_end(&mut envelope)?;
+        let decoded = ClientMessage::decode(&envelope[..]);
+        match (decoded, content) {
+            (Ok(mut request), Some(content)) => {
+                let content = String::from_utf8(content).expect("Checked above");
+                request.message = Some(client_message::Message::EchoMessage(EchoMessage { content }));
+                Ok(LargeEcho::Truncated(Ok(request)))
+            }
+            (Err(e), Some(_)) => Ok(LargeEcho::Truncated(Err(e))),
+            // Streamed: the chunks were answered already, only the sequence is left to check
+            (Ok(request), None) => {
+                if let Some(reason) = self.out_of_sequence(request.seq) {
+                    self.writer.lock().unwrap().send(server_message::Message::Error(Error { reason }))?;
+                }
+                Ok(LargeEcho::Answered(true))
+            }
+            (Err(e), None) => self.decode_failed(e).map(LargeEcho::Answered),
+        }
+    }
+
+    // Skips the rest of a large echo whose content isn't UTF-8, it gets no response like any undecodable frame
+    fn discard_undecodable(&mut self, body: &mut BodyReader<TcpStream>, e: std::str::Utf8Error) -> io::Result<LargeEcho> {
+        io::copy(body, &mut io::sink())?;
+        self.decode_failed(format!("Echo content is not UTF-8: {}", e)).map(LargeEcho::Answered)
+    }
+
+    // 3- process() Method
+    // Decides how to answer one decoded message without touching the socket: Ping, Auth, subscriptions and admin messages
+    // are answered here, unauthenticated messages are rejected, everything else goes to the MessageHandler
+    fn process(&mut self, message: Option<client_message::Message>) -> HandlerAction {
+        match message {
+            // Liveness probe, answered even before authentication
+            Some(client_message::Message::Ping(_)) => HandlerAction::Respond(server_message::Message::Pong(Pong {})),
+            Some(client_message::Message::Hello(hello)) => {
+                self.context.version = hello.version.clamp(1, self.settings.protocol_version);
+                let version = self.context.version;
+                HandlerAction::Respond(server_message::Message::HelloResponse(HelloResponse {
+                    version,
+                    auth_required: self.settings.auth_verifier.is_some(),
+                    subscriptions: version >= message_version(&client_message::Message::Subscribe(Subscribe::default())),
+                    streaming: version >= message_version(&client_message::Message::StreamRequest(StreamRequest::default())),
+                }))
+            }
+            Some(client_message::Message::Auth(auth)) => match &self.settings.auth_verifier {
+                Some(verify) if !verify(&auth.token) => {
+                    warn!("Rejected Auth with an invalid token.");
+                    HandlerAction::Respond(server_message::Message::Unauthorized(Unauthorized {
+                        reason: "Invalid token".to_string(),
+                    }))
+                }
+                _ => {
+                    self.context.authenticated = true;
+                    HandlerAction::Respond(server_message::Message::AuthResponse(AuthResponse { authenticated: true }))
+                }
+            },
+            // Until a valid Auth arrives, everything else is rejected
+            Some(_) if self.unauthenticated() => {
+                warn!("Rejected message from an unauthenticated client.");
+                HandlerAction::Respond(server_message::Message::Unauthorized(Unauthorized {
+                    reason: "Authentication required".to_string(),
+                }))
+            }
+            // A message from a newer protocol than negotiated is answered, not treated as garbage
+            Some(message) if message_version(&message) > self.context.version => {
+                warn!("Rejected {}, unsupported in protocol version {}.", message_kind(&message), self.context.version);
+                HandlerAction::Respond(server_message::Message::UnsupportedOperation(UnsupportedOperation {
+                    op: message_kind(&message).to_string(),
+                    version: self.context.version,
+                }))
+            }
+            Some(message) if admin::is_admin_message(&message) => match &self.admin {
+                Some(admin) => HandlerAction::Respond(admin.process(message)),
+                None => {
+                    warn!("Rejected an admin message on the data port.");
+                    HandlerAction::Respond(server_message::Message::Error(Error {
+                        reason: "Admin messages are only accepted on the admin port".to_string(),
+                    }))
+                }
+            },
+            // Control traffic only, data is served on the data port
+            Some(_) if self.admin.is_some() => {
+                warn!("Rejected a data message on the admin port.");
+                HandlerAction::Respond(server_message::Message::Error(Error {
+                    reason: "Admin port only accepts admin messages".to_string(),
+                }))
+            }
+            // Streamed pieces are echoed one by one, so neither side has to hold the whole message
+            Some(client_message::Message::EchoChunk(chunk)) if chunk.stream_reply => {
+                HandlerAction::Respond(server_message::Message::EchoChunk(chunk))
+            }
+            // Pieces of an echo larger than one frame, handled as one EchoMessage once the final piece arrived
+            Some(client_message::Message::EchoChunk(chunk)) => match self.reassemble(chunk) {
+                Ok(Some(content)) => self.process(Some(client_message::Message::EchoMessage(EchoMessage { content }))),
+                Ok(None) => HandlerAction::Ignore,
+                Err(reason) => {
+                    warn!("Dropped a chunked echo: {}", reason);
+                    HandlerAction::Respond(server_message::Message::Error(Error { reason }))
+                }
+            },
+            Some(client_message::Message::Subscribe(subscribe)) => {
+                let session = self.session.get_or_insert_with(|| self.subscriptions.new_session()).clone();
+                self.subscriptions.subscribe(&session, &subscribe.topic, self.addr, &self.writer);
+                HandlerAction::Respond(server_message::Message::Subscribed(Subscribed {
+                    session,
+                    topics: vec![subscribe.topic],
+                }))
+            }
+            // A reconnecting client replays its subscriptions under the session it was issued, only topics subscribed
+            // under that session are restored
+            Some(client_message::Message::Resubscribe(resubscribe)) => {
+                let Some(topics) = self.subscriptions.resubscribe(&resubscribe.session, &resubscribe.topics, self.addr, &self.writer) else {
+                    warn!("Rejected Resubscribe with an unknown session.");
+                    return HandlerAction::Respond(server_message::Message::Unauthorized(Unauthorized {
+                        reason: "Unknown session".to_string(),
+                    }));
+                };
+                self.session = Some(resubscribe.session.clone());
+                HandlerAction::Respond(server_message::Message::Subscribed(Subscribed {
+                    session: resubscribe.session,
+                    topics,
+                }))
+            }
+            Some(client_message::Message::Publish(publish)) => {
+                let delivered = self.subscriptions.publish(&publish.topic, &publish.content);
+                HandlerAction::Respond(server_message::Message::Published(Published { delivered }))
+            }
+            // Streams run on their own thread, so this connection keeps reading and can see a Cancel
+            // A running stream holds a handler permit, so max_concurrent_handlers bounds stream threads too
+            Some(client_message::Message::StreamRequest(request)) => {
+                self.streams.retain(|_, (_, stream)| !stream.is_finished());
+                let refused = if request.count > MAX_STREAM_ITEMS || Duration::from_millis(request.interval_ms as u64) > MAX_STREAM_INTERVAL {
+                    Some(format!("Streams are limited to {} items {:?} apart", MAX_STREAM_ITEMS, MAX_STREAM_INTERVAL))
+                } else if self.streams.contains_key(&request.request_id) {
+                    Some(format!("Stream {} is already running", request.request_id))
+                } else if self.streams.len() >= MAX_STREAMS_PER_CONNECTION {
+                    Some(format!("At most {} streams run at once on a connection", MAX_STREAMS_PER_CONNECTION))
+                } else {
+                    None
+                };
+                if let Some(reason) = refused {
+                    warn!("Refused stream {} from {}: {}", request.request_id, self.addr, reason);
+                    return HandlerAction::Respond(server_message::Message::Error(Error { reason }));
+                }
+                let Some(permit) = HandlerPermit::try_acquire(&self.active_handlers, self.settings.max_concurrent_handlers) else {
+                    warn!("Too many concurrent handler calls; answering ServerBusy.");
+                    return HandlerAction::Respond(server_message::Message::ServerBusy(ServerBusy {}));
+                };
+                let token = CancellationToken::default();
+                match spawn_stream(self.settings.thread_builder(), request.clone(), token.clone(), self.writer.clone(), permit) {
+                    Ok(stream) => {
+                        self.streams.insert(request.request_id, (token, stream));
+                        HandlerAction::Ignore
+                    }
+                    Err(e) => {
+                        error!("Failed to start a stream thread for {}: {}", self.addr, e);
+                        HandlerAction::Respond(server_message::Message::ServerBusy(ServerBusy {}))
+                    }
+                }
+            }
+            Some(client_message::Message::Cancel(cancel)) => {
+                match self.streams.remove(&cancel.request_id) {
+                    Some((token, _)) => token.cancel(),      // The stream answers with a cancelled StreamEnd
+                    None => warn!("Cancel for request {}, which isn't streaming.", cancel.request_id),
+                }
+                HandlerAction::Ignore
+            }
+            Some(_) if self.settings.inject_fault() => {
+                warn!("Injecting a fault for {}.", self.addr);
+                match self.settings.fault_action {
+                    FaultAction::Error => HandlerAction::Respond(server_message::Message::Error(Error {
+                        reason: "Injected fault".to_string(),
+                    })),
+                    FaultAction::Disconnect => HandlerAction::Close,
+                }
+            }
+            Some(message) => {
+                let kind = message_kind(&message);
+                let operands = match &message {
+                    client_message::Message::AddRequest(add) if self.settings.echo_add_operands => Some((add.a, add.b)),
+                    _ => None,
+                };
+                let pool = self.settings.worker_pools.get(kind);
+                // A pooled message type is bounded by its pool's threads instead
+                let _permit = match pool {
+                    Some(_) => None,
+                    None => match HandlerPermit::try_acquire(&self.active_handlers, self.settings.max_concurrent_handlers) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            warn!("Too many concurrent handler calls; answering ServerBusy.");
+                            return HandlerAction::Respond(server_message::Message::ServerBusy(ServerBusy {}));
+                        }
+                    },
+                };
+                let started = self.settings.clock.now();
+                let action = match pool {
+                    Some(pool) => pool
+                        .run(message, self.priority, self.expires_at, &mut self.context)   // Waits for a free thread of the pool
+                        .unwrap_or_else(|| self.expire()),
+                    None => self.settings.handler.handle(message, &mut self.context),
+                };
+                let elapsed = self.settings.clock.now().saturating_duration_since(started);
+                if self.settings.slow_handler_threshold.is_some_and(|threshold| elapsed > threshold) {
+                    warn!("Slow handler: {} took {:?}", kind, elapsed);
+                }
+                match (operands, action) {
+                    (Some((a, b)), HandlerAction::Respond(server_message::Message::AddResponse(sum))) => {
+                        HandlerAction::Respond(server_message::Message::AddResponse(AddResponse { a, b, ..sum }))
+                    }
+                    (_, action) => action,
+                }
+            }
+            // An envelope without a known message, e.g. from a newer client
+            None => match self.settings.unknown_message_policy {
+                UnknownMessagePolicy::Ignore => {
+                    warn!("Received a ClientMessage without content; ignoring.");
+                    HandlerAction::Ignore
+                }
+                UnknownMessagePolicy::Reject => {
+                    warn!("Received a ClientMessage without content; rejecting.");
+                    HandlerAction::Respond(server_message::Message::Error(Error {
+                        reason: "ClientMessage has no content".to_string(),
+                    }))
+                }
+                UnknownMessagePolicy::Disconnect => {
+                    warn!("Received a ClientMessage without content; disconnecting client.");
+                    HandlerAction::Close
+                }
+            },
+        }
+    }
+
+    // Appends a chunk to its message, returns the whole content once the final chunk arrived
+    // A chunk out of sequence, or one taking the message past max_reassembled_size, drops the partial message. A new
+    // message is refused while MAX_OPEN_CHUNKED_MESSAGES others are unfinished
+    fn reassemble(&mut self, chunk: EchoChunk) -> Result<Option<String>, String> {
+        if !chunk.data.is_empty() {
+            self.chunks.remove(&chunk.message_id);
+            return Err("Binary chunks are only echoed with stream_reply".to_string());   // An EchoMessage has no binary field
+        }
+        if !self.chunks.contains_key(&chunk.message_id) && self.chunks.len() >= MAX_OPEN_CHUNKED_MESSAGES {
+            return Err(format!("At most {} chunked messages may be open at once", MAX_OPEN_CHUNKED_MESSAGES));
+        }
+        let (next_index, content) = self.chunks.entry(chunk.message_id).or_default();
+        if chunk.index != *next_index {
+            let expected = *next_index;
+            self.chunks.remove(&chunk.message_id);
+            return Err(format!("Chunk {} of message {} arrived out of order, expected {}", chunk.index, chunk.message_id, expected));
+        }
+        let limit = self.settings.max_reassembled_size();
+        if content.len() + chunk.content.len() > limit {
+            self.chunks.remove(&chunk.message_id);
+            return Err(format!("Chunked message {} exceeds the {} byte limit", chunk.message_id, limit));
+        }
+        content.push_str(&chunk.content);
+        *next_index += 1;
+        if !chunk.r#final {
+            return Ok(None);
+        }
+        Ok(self.chunks.remove(&chunk.message_id).map(|(_, content)| content))
+    }
+}
+
+// Stops the streams still running once the connection is gone
+impl Drop for Client {
+    fn drop(&mut self) {
+        for (token, _) in self.streams.values() {
+            token.cancel();
+        }
+    }
+}
+
+// Sends the items of a StreamRequest, then a StreamEnd, a Cancel ends the wait between two items at once
+// The permit is released when the stream ends
+fn spawn_stream(
+    builder: thread::Builder,
+    request: StreamRequest,
+    token: CancellationToken,
+    writer: SharedWriter,
+    permit: HandlerPermit,
+) -> io::Result<thread::JoinHandle<()>> {
+    builder.spawn(move || {
+        let _permit = permit;
+        let interval = Duration::from_millis(request.interval_ms as u64);
+        for index in 0..request.count {
+            if (index > 0 && token.wait(interval)) || token.is_cancelled() {
+                break;
+            }
+            let item = server_message::Message::StreamItem(StreamItem {
+                request_id: request.request_id,
+                index,
+                content: request.content.clone(),
+            });
+            if writer.lock().unwrap().send(item).is_err() {
+                return;         // The connection is gone
+            }
+        }
+        let cancelled = token.is_cancelled();
+        if cancelled {
+            info!("Stream {} cancelled.", request.request_id);
+        }
+        let _ = writer.lock().unwrap().send(server_message::Message::StreamEnd(StreamEnd { request_id: request.request_id, cancelled }));
+    })
+}
+
+// Every name message_kind() returns, the message types a worker pool can be given
+const MESSAGE_KINDS: [&str; 15] = [
+    "EchoMessage", "AddRequest", "Auth", "Subscribe", "Resubscribe", "Publish", "Ping", "ClientInfoRequest", "MetricsRequest",
+    "Kick", "Broadcast", "EchoChunk", "Hello", "StreamRequest", "Cancel",
+];
+
+// Name of a message variant, for logs
+fn message_kind(message: &client_message::Message) -> &'static str {
+    match message {
+        client_message::Message::EchoMessage(_) => "EchoMessage",
+        client_message::Message::AddRequest(_) => "AddRequest",
+        client_message::Message::Auth(_) => "Auth",
+        client_message::Message::Subscribe(_) => "Subscribe",
+        client_message::Message::Resubscribe(_) => "Resubscribe",
+        client_message::Message::Publish(_) => "Publish",
+        client_message::Message::Ping(_) => "Ping",
+        client_message::Message::ClientInfoRequest(_) => "ClientInfoRequest",
+        client_message::Message::MetricsRequest(_) => "MetricsRequest",
+        client_message::Message::Kick(_) => "Kick",
+        client_message::Message::Broadcast(_) => "Broadcast",
+        client_message::Message::EchoChunk(_) => "EchoChunk",
+        client_message::Message::Hello(_) => "Hello",
+        client_message::Message::StreamRequest(_) => "StreamRequest",
+        client_message::Message::Cancel(_) => "Cancel",
+    }
+}
+
+// Protocol version that introduced a message variant
+// 1: echo, add, auth, ping and version negotiation. 2: subscriptions, chunked echoes and admin messages. 3: cancellable streams
+fn message_version(message: &client_message::Message) -> u32 {
+    match message {
+        client_message::Message::EchoMessage(_)
+        | client_message::Message::AddRequest(_)
+        | client_message::Message::Auth(_)
+        | client_message::Message::Ping(_)
+        | client_message::Message::Hello(_) => 1,
+        client_message::Message::Subscribe(_)
+        | client_message::Message::Resubscribe(_)
+        | client_message::Message::Publish(_)
+        | client_message::Message::EchoChunk(_)
+        | client_message::Message::ClientInfoRequest(_)
+        | client_message::Message::MetricsRequest(_)
+        | client_message::Message::Kick(_)
+        | client_message::Message::Broadcast(_) => 2,
+        client_message::Message::StreamRequest(_) | client_message::Message::Cancel(_) => 3,
+    }
+}
+
+// Carries out a HandlerAction on the socket, returns Ok(false) when the connection should close
+fn write_action(writer: &mut ResponseWriter, action: HandlerAction) -> io::Result<bool> {
+    match action {
+        HandlerAction::Respond(response) => writer.send(response)?,
+        HandlerAction::RespondMany(responses) => {
+            writer.start_batch();
+            let sent = responses.into_iter().try_for_each(|response| writer.send(response));
+            let flushed = writer.flush_batch();
+            sent.and(flushed)?;
+        }
+        HandlerAction::RespondAndClose(response) => {
+            writer.send(response)?;
+            info!("Handler closed the connection after responding.");
+            return Ok(false);
+        }
+        HandlerAction::Close => {
+            info!("Handler closed the connection.");
+            return Ok(false);
+        }
+        HandlerAction::Ignore => {}
+    }
+    Ok(true)
+}
+
+// Outcome of read_large_echo: a truncated request still to handle, or the message answered already
+enum LargeEcho {
+    Truncated(Result<ClientMessage, prost::DecodeError>),
+    Answered(bool),       // false if the connection is to be closed
+}
+
+// Reads the start of a ClientMessage body holding an EchoMessage, up to where its content begins, and returns the
+// content length. None if the body starts with anything else, which prost, writing fields in order, only does for
+// another message type
+fn echo_content_len(body: &mut BodyReader<TcpStream>) -> io::Result<Option<usize>> {
+    const ECHO_FIELD: u64 = 1 << 3 | 2;         // Field 1, length-delimited, in ClientMessage and in EchoMessage alike
+    if body.remaining == 0 || read_varint(body)? != ECHO_FIELD {
+        return Ok(None);
+    }
+    let echo_len = read_varint(body)?;
+    if echo_len > body.remaining as u64 || echo_len == 0 || read_varint(body)? != ECHO_FIELD {
+        return Ok(None);
+    }
+    let content_len = read_varint(body)?;
+    let content_header = 1 + prost::encoding::encoded_len_varint(content_len) as u64;
+    if echo_len != content_header + content_len {
+        return Ok(None);        // EchoMessage holds nothing but its content
+    }
+    Ok(Some(content_len as usize))
+}
+
+// Reads one protobuf varint
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7F) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "Varint longer than 10 bytes"))
+}
+
+// The rest of a frame body as a Read, failing with TimedOut once the frame deadline has passed and with
+// UnexpectedEof if the connection closes before the body is complete. Reads the connection's stream or a clone of it
+struct BodyReader<'a, S: Borrow<TcpStream>> {
+    stream: S,
+    remaining: usize,            // Body bytes not read yet
+    len: usize,                  // Whole body length, for the log
+    deadline: Option<Instant>,   // From the frame deadline, None waits indefinitely
+    clock: &'a dyn Clock,
+}
+
+impl<'a, S: Borrow<TcpStream>> BodyReader<'a, S> {
+    // Reader for the next `len` bytes of a body, `deadline` counted from now
+    fn new(stream: S, len: usize, deadline: Option<Duration>, clock: &'a dyn Clock) -> Self {
+        let deadline = deadline.map(|deadline| clock.now() + deadline);
+        BodyReader { stream, remaining: len, len, deadline, clock }
+    }
+}
+
+impl<S: Borrow<TcpStream>> Read for BodyReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let wanted = buf.len().min(self.remaining);
+        if wanted == 0 {
+            return Ok(0);
+        }
+        loop {
+            if let Some(deadline) = self.deadline {
+                let remaining = deadline.saturating_duration_since(self.clock.now());
+                if remaining.is_zero() {
+                    warn!("Frame not completed before its deadline ({} of {} bytes); dropping connection.", self.len - self.remaining, self.len);
+                    return Err(io::Error::new(ErrorKind::TimedOut, "Frame not completed before its deadline"));
+                }
+                self.stream.borrow().set_read_timeout(Some(remaining))?;
+            }
+            match self.stream.borrow().read(&mut buf[..wanted]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed mid-frame")),
+                Ok(n) => {
+                    self.remaining -= n;
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}   // Deadline checked at the top of the loop
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}     // A signal interrupted the read, retry
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S: Borrow<TcpStream>> Drop for BodyReader<'_, S> {
+    fn drop(&mut self) {
+        if self.deadline.is_some() {
+            let _ = self.stream.borrow().set_read_timeout(None);      // Waiting for the next header is unbounded
+        }
+    }
+}
+
+//BindAddr: what the listeners bind to, a host name and port resolved at bind time or an address used as is
+//A host name gets a listener on every address it resolves to, so a client reaches the server whichever one its resolver
+//picks. "localhost" always covers both 127.0.0.1 and ::1, resolvers disagree on which one it means.
+enum BindAddr {
+    Host(String),
+    Socket(SocketAddr),
+    Inherited,         // Nothing to bind, the server only uses listeners handed to it (Server::from_listener)
+}
+
+impl BindAddr {
+    // Binds one listener per address, all on the same port
+    // Only the first address must bind, the others are skipped with a warning (an address family the host lacks)
+    fn listen(&self, reuse_port: bool) -> io::Result<Vec<TcpListener>> {
+        let mut addrs = match self {
+            BindAddr::Host(host) => resolve_all(host)?,
+            BindAddr::Socket(addr) => vec![*addr],     // Nothing to resolve
+            BindAddr::Inherited => return Ok(Vec::new()),
+        };
+        let first = bind_listener(addrs.remove(0), reuse_port)?;
+        let port = first.local_addr()?.port();       // The one picked for port 0
+        let mut listeners = vec![first];
+        for mut addr in addrs {
+            if addr.port() == 0 {
+                addr.set_port(port);
+            }
+            match bind_listener(addr, reuse_port) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => warn!("Not listening on {}: {}", addr, e),
+            }
+        }
+        Ok(listeners)
+    }
+}
+
+// Binds a listener, with SO_REUSEPORT so another server can bind the same port while this one is open
+fn bind_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    if !reuse_port {
+        return TcpListener::bind(addr);
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    set_reuse_port(&socket)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_address(true)?;        // As TcpListener::bind does
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "SO_REUSEPORT is not available on this platform"))
+}
+
+// Returns the length of the next frame if it arrived whole and is at most `max_len`, without reading it or waiting
+fn peek_frame_len(stream: &TcpStream, max_len: usize) -> io::Result<Option<usize>> {
+    let mut header = [0u8; HEADER_LEN];
+    if peek_now(stream, HEADER_LEN)? < HEADER_LEN {
+        return Ok(None);
+    }
+    stream.peek(&mut header)?;      // Already there, doesn't block
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        return Ok(None);
+    }
+    Ok((peek_now(stream, HEADER_LEN + len)? == HEADER_LEN + len).then_some(len))
+}
+
+// Returns how many of the next `len` bytes can be read right away, the stream stays blocking for the writer's clone
+#[cfg(unix)]
+fn peek_now(stream: &TcpStream, len: usize) -> io::Result<usize> {
+    let mut buf = vec![std::mem::MaybeUninit::<u8>::uninit(); len];
+    match SockRef::from(stream).recv_with_flags(&mut buf, libc::MSG_PEEK | libc::MSG_DONTWAIT) {
+        Ok(available) => Ok(available),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn peek_now(_stream: &TcpStream, _len: usize) -> io::Result<usize> {
+    Ok(0)       // No read-ahead, requests are read one at a time
+}
+
+// Resolves a "host:port" string to every address it names, in resolver order, never empty
+fn resolve_all(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in host.to_socket_addrs()? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    if let (true, Some(port)) = (name.eq_ignore_ascii_case("localhost"), addrs.first().map(SocketAddr::port)) {
+        for loopback in [IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)] {
+            if !addrs.iter().any(|addr| addr.ip() == loopback) {
+                addrs.push(SocketAddr::new(loopback, port));
+            }
+        }
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(ErrorKind::AddrNotAvailable, format!("{} did not resolve to any address", host)));
+    }
+    Ok(addrs)
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Host(addr) => f.write_str(addr),
+            BindAddr::Socket(addr) => addr.fmt(f),
+            BindAddr::Inherited => f.write_str("inherited listeners"),
+        }
+    }
+}
+
+//Server Struct
+pub struct Server {
+    bind_addr: BindAddr,                  // Address the client listeners bind to
+    listeners: OnceLock<Vec<TcpListener>>,   //Listen for incoming connections, one per bind address, bound by build() or, for a deferred server, by run()
+    inherited: Mutex<Vec<TcpListener>>,   // Listeners handed over by another server, added to the bound ones
+    reuse_port: bool,                     // Bind with SO_REUSEPORT, see ServerBuilder::reuse_port
+    handed_off: AtomicBool,               // Set by hand_off(), the client listeners are left to the server that inherited them
+    pool_error: Option<io::Error>,        // Why a worker pool couldn't start its threads, the server then never runs
+    is_running: Arc<AtomicBool>,          // Shared running state, Ensures a shared, atomic flag to signal when the server is running.
+    client_threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>, // Track active client threads
+    client_count: Arc<AtomicUsize>, // Track the current number of clients connections using AtomicUsize.
+    client_count_changed: Arc<(Mutex<()>, Condvar)>,   // Notified whenever client_count changes, see wait_for_clients()
+    max_clients: usize,            // Maximum allowed clients connections
+    connections: Registry,                // Open client connections, so stop() can close them
+    admin_bind_addr: Option<String>,      // Address of the admin listener, see ServerBuilder::admin_address
+    admin_listener: OnceLock<TcpListener>, // Separate port for admin messages, bound with the main listener
+    admin_connections: Registry,          // Open admin connections, not counted against max_clients
+    admin: Arc<Admin>,                    // Answers admin messages, shares the registry and counters
+    settings: Arc<Settings>,              // Options shared with every connection handler
+    accept_queue: Mutex<VecDeque<(TcpStream, SocketAddr)>>,   // Accepted connections waiting for a free slot
+    accept_queue_capacity: usize,         // Connections allowed to wait while the server is at capacity
+    accept_order: AcceptOrder,            // Which waiting connection is served first
+    max_accepts_per_iteration: usize,     // Connections the accept loop takes before checking is_running again
+    max_connections_per_ip: Option<usize>, // Cap on connections from a single address, None means unlimited
+    evict_idle_on_capacity: Option<Duration>,   // At capacity, a new connection closes the longest-idle one if idle this long instead of being refused
+    draining: Arc<AtomicBool>,            // Set by drain(), new connections are refused
+    stop_when_idle: AtomicBool,           // Set by shutdown(true), stop() once the accept queue and all connections are gone
+    inflight: Arc<AtomicUsize>,           // Messages being processed across all connections
+    subscriptions: Arc<Subscriptions>,    // Topic subscriptions of all connections
+    active_handlers: Arc<AtomicUsize>,    // Handler calls running across all connections
+    response_writes: Arc<AtomicUsize>,    // Socket writes made by the writer threads
+    recordings: AtomicU64,                // Number of the next recording file, see recording::recording_path
+    rejections: RejectionCounters,        // Refused connections by reason, see rejection_stats()
+}
+
+// Connections refused so far, one counter per reason, read through Server::rejection_stats()
+#[derive(Default)]
+struct RejectionCounters {
+    at_capacity: AtomicUsize,
+    per_ip_limit: AtomicUsize,
+    draining: AtomicUsize,
+    server_busy: AtomicUsize,
+}
+
+//AcceptOrder: discipline of the accept queue when the server is at capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum AcceptOrder {
+    #[default]
+    Fifo,     // Oldest waiting connection first
+    Lifo,     // Newest first, its client is the least likely to have given up already
+}
+
+//BackpressurePolicy: what the server does when a client doesn't read its responses fast enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum BackpressurePolicy {
+    #[default]
+    Block,        // Stop handling the connection's messages until its queue has room again
+    Disconnect,   // Close the connection
+}
+
+//WriteRetryPolicy: what the server does when writing a response times out, see ServerBuilder::write_timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum WriteRetryPolicy {
+    #[default]
+    Disconnect,        // Close the connection
+    Retry(Duration),   // Keep writing the rest of the response until this long after the first timeout, then close it
+}
+
+//UnknownMessagePolicy: what the server does with a ClientMessage that decodes but carries no known message
+//An empty envelope encodes to zero bytes and is a heartbeat, so this applies to envelopes with only unknown fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum UnknownMessagePolicy {
+    #[default]
+    Ignore,       // No response, the connection stays open
+    Reject,       // Answer with an Error, the connection stays open
+    Disconnect,   // Treat it as a protocol violation and close the connection
+}
+
+//ExpiredRequestPolicy: what the server does with a request whose expires_at_unix_nanos passed while it was queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum ExpiredRequestPolicy {
+    #[default]
+    Drop,         // No response, the client already gave up on it
+    Reply,        // Answer with Expired instead of handling it
+}
+
+//DrainingRequestPolicy: what happens to a request arriving on an open connection once the server is draining
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum DrainingRequestPolicy {
+    #[default]
+    Serve,        // Handle it as usual, the connection winds down when its client disconnects
+    Reject,       // Answer with ShuttingDown instead of handling it
+    Ignore,       // Drop it without a response
+}
+
+//FaultAction: what a request picked by fault injection gets instead of the handler's answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum FaultAction {
+    #[default]
+    Error,        // Answer with an Error, the connection stays open
+    Disconnect,   // Close the connection without answering
+}
+
+//FaultRate: fraction of requests fault injection picks, in [0, 1] and never NaN, so it compares like an integer
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize)]
+#[serde(transparent)]
+pub struct FaultRate(f64);
+
+impl FaultRate {
+    // Clamps `rate` to [0, 1], NaN counts as 0
+    pub fn new(rate: f64) -> Self {
+        FaultRate(if rate.is_nan() { 0.0 } else { rate.clamp(0.0, 1.0) })
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl Eq for FaultRate {}       // new() keeps NaN out
+
+//LargeMessagePolicy: what the server does with a frame larger than max_message_size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum LargeMessagePolicy {
+    #[default]
+    Reject,           // Discard the body unread and answer with an Error
+    Truncate(usize),  // Handle an EchoMessage with only this many bytes of its content, the rest is read and dropped
+    Stream,           // Handle an EchoMessage as several of at most max_message_size bytes, each as it arrives
+                      // Under both, any other message type above the limit is rejected as under Reject
+    Disconnect,       // Answer with an Error and close as soon as the length prefix is read, the body is never received
+}
+
+//ServerConfig: snapshot of the effective configuration, see Server::config()
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerConfig {
+    pub address: Option<SocketAddr>,            // None if the listener address can't be read
+    pub max_clients: usize,
+    pub max_connections_per_ip: Option<usize>,
+    pub accept_queue_capacity: usize,
+    pub evict_idle_on_capacity_ms: Option<u128>,
+    pub reuse_port: bool,
+    pub accept_order: AcceptOrder,
+    pub max_accepts_per_iteration: usize,
+    pub auth_required: bool,
+    pub frame_deadline_ms: Option<u128>,
+    pub header_timeout_ms: Option<u128>,
+    pub slow_handler_threshold_ms: Option<u128>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub max_message_size: usize,
+    pub max_reassembled_size: usize,
+    pub large_message_policy: LargeMessagePolicy,
+    pub max_pending_responses: usize,
+    pub response_coalesce_window_ms: Option<u128>,
+    pub buffered_writes: bool,
+    pub adaptive_nodelay_threshold: Option<usize>,
+    pub backpressure_policy: BackpressurePolicy,
+    pub write_timeout_ms: Option<u128>,
+    pub write_retry: WriteRetryPolicy,
+    pub unknown_message_policy: UnknownMessagePolicy,
+    pub expired_request_policy: ExpiredRequestPolicy,
+    pub draining_request_policy: DrainingRequestPolicy,
+    pub strict_sequencing: bool,
+    pub echo_add_operands: bool,
+    pub fault_injection_rate: FaultRate,
+    pub fault_action: FaultAction,
+    pub max_concurrent_handlers: Option<usize>,
+    pub worker_pools: Vec<WorkerPoolConfig>,
+    pub idle_timeout_ms: Option<u128>,
+    pub max_connection_lifetime_ms: Option<u128>,
+    pub max_bytes_per_connection: Option<u64>,
+    pub status_interval_ms: Option<u128>,
+    pub thread_join_timeout_ms: Option<u128>,
+    pub thread_stack_size: Option<usize>,
+    pub admin_address: Option<SocketAddr>,     // None without an admin listener
+    pub protocol_version: u32,
+    pub record_dir: Option<PathBuf>,           // None unless traffic is being recorded
+}
+
+//ConnectionSlot: one unit of client_count, taken when a connection is accepted.
+//Decrementing in Drop pairs every fetch_add with exactly one fetch_sub, including when a handler panics.
+struct ConnectionSlot {
+    client_count: Arc<AtomicUsize>,
+    changed: Arc<(Mutex<()>, Condvar)>,
+}
+
+impl ConnectionSlot {
+    fn acquire(client_count: &Arc<AtomicUsize>, changed: &Arc<(Mutex<()>, Condvar)>) -> Self {
+        client_count.fetch_add(1, Ordering::SeqCst);
+        notify_count_changed(changed);
+        ConnectionSlot {
+            client_count: client_count.clone(),
+            changed: changed.clone(),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let previous = self.client_count.fetch_sub(1, Ordering::SeqCst);
+        debug_assert!(previous > 0, "client_count underflow: released a slot that was never acquired");
+        notify_count_changed(&self.changed);
+    }
+}
+
+// Wakes wait_for_clients() callers, taking the lock so a caller between its check and its wait can't miss the change
+fn notify_count_changed(changed: &(Mutex<()>, Condvar)) {
+    let _guard = changed.0.lock().unwrap();
+    changed.1.notify_all();
+}
+
+//InFlight: one message between decoding and its response being written, the count drops even if writing fails
+struct InFlight {
+    inflight: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    fn start(inflight: &Arc<AtomicUsize>) -> Self {
+        inflight.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            inflight: inflight.clone(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.inflight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+//HandlerPermit: one running handler call, taken only while fewer than the configured maximum are running
+struct HandlerPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl HandlerPermit {
+    fn try_acquire(active: &Arc<AtomicUsize>, max: Option<usize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| match max {
+                Some(max) if running >= max => None,
+                _ => Some(running + 1),
+            })
+            .ok()?;
+        Some(HandlerPermit {
+            active: active.clone(),
+        })
+    }
+}
+
+impl Drop for HandlerPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+//WorkerPoolConfig: a worker pool and the message types whose handler calls it runs, see ServerBuilder::worker_pool
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorkerPoolConfig {
+    pub message_types: Vec<String>,     // Variant names, e.g. "EchoMessage" or "AddRequest"
+    pub threads: usize,
+}
+
+//ServerEvent: something operators may want to observe, see ServerBuilder::on_event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    // A connection's response queue was full, `action` is what the backpressure policy did about it
+    // Reported once per episode, a Block is reported again only after the queue accepted a response
+    Backpressure { connection: SocketAddr, action: BackpressurePolicy },
+    // Adaptive nodelay turned Nagle off for a connection sending many small messages
+    NodelayEnabled { connection: SocketAddr },
+}
+
+//AuditRecord: one handled message, passed to the audit sink once its answer was queued, see ServerBuilder::audit_sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub peer: SocketAddr,
+    pub message_type: &'static str,     // Variant name, e.g. "EchoMessage", "None" for an envelope without a message
+    pub size: usize,                    // Frame body bytes received
+    pub result: AuditResult,
+    pub latency: Duration,              // From decoding the message to queueing its answer
+}
+
+//AuditResult: how a handled message was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditResult {
+    Responded,    // One or more responses were queued
+    Ignored,      // Nothing was sent back
+    Closed,       // The connection was closed, after a response or without one
+    Failed,       // Queueing the response failed, the connection is dropped
+}
+
+impl AuditResult {
+    fn of(action: &HandlerAction) -> Self {
+        match action {
+            HandlerAction::Respond(_) | HandlerAction::RespondMany(_) => AuditResult::Responded,
+            HandlerAction::RespondAndClose(_) | HandlerAction::Close => AuditResult::Closed,
+            HandlerAction::Ignore => AuditResult::Ignored,
+        }
+    }
+}
+
+//ConnectionInfo: a connected client, see Server::for_each_client()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub address: SocketAddr,     // Peer address
+}
+
+//RejectionStats: connections the server refused, by reason, see Server::rejection_stats()
+//One counter for each way this server refuses a connection. It has no rate limiting, address filtering, pausing or
+//client identity, so there are no RateLimited, Forbidden, Paused or DuplicateClient refusals to count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RejectionStats {
+    pub at_capacity: usize,      // Max clients reached and no room in the accept queue, answered AtCapacity
+    pub per_ip_limit: usize,     // Their address already had max_connections_per_ip connections
+    pub draining: usize,         // Arrived or were still queued after drain() or shutdown(true)
+    pub server_busy: usize,      // Their threads couldn't be started, answered ServerBusy
+}
+
+//DrainStatus: work left on a draining server, see Server::drain()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrainStatus {
+    pub remaining_connections: usize,    // Connections still open
+    pub remaining_inflight: usize,       // Messages received but not yet answered
+}
+
+//ServerBuilder: collects the optional settings before binding the listener
+pub struct ServerBuilder {
+    addr: BindAddr,
+    max_clients: usize,
+    settings: Settings,
+    accept_queue_capacity: usize,
+    accept_order: AcceptOrder,
+    max_accepts_per_iteration: usize,
+    max_connections_per_ip: Option<usize>,
+    evict_idle_on_capacity: Option<Duration>,
+    admin_addr: Option<String>,
+    inherited: Vec<TcpListener>,
+    reuse_port: bool,
+}
+
+impl ServerBuilder {
+    // Sets the maximum number of concurrent client connections
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    // Requires every connection to send a valid Auth first, `verifier` decides which tokens are valid
+    pub fn require_auth<F>(mut self, verifier: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.settings.auth_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    // Calls `listener` for every ServerEvent, from the thread where the event happened, so it should return quickly
+    pub fn on_event<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&ServerEvent) + Send + Sync + 'static,
+    {
+        self.settings.event_listener = Some(Arc::new(listener));
+        self
+    }
+
+    // Calls `sink` after every handled message with its peer, type, size, result and latency, for an audit trail kept
+    // apart from the logs. It runs on the connection's handler thread, so a slow sink delays that connection
+    pub fn audit_sink<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(&AuditRecord) + Send + Sync + 'static,
+    {
+        self.settings.audit_sink = Some(Arc::new(sink));
+        self
+    }
+
+    // Calls `hook` once per accept loop iteration, before the loop sleeps, for maintenance tied to the server's own loop
+    // It runs on the accept loop's thread, so connections wait while it does: keep it short. Iterations happen about
+    // every 10 ms while idle and more often under load
+    pub fn accept_loop_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Server) + Send + Sync + 'static,
+    {
+        self.settings.accept_loop_hook = Some(Arc::new(hook));
+        self
+    }
+
+    // Uses the default handler echoing every EchoMessage `repeat` times (0 sends no echo at all)
+    // This replaces a handler set earlier with handler()
+    pub fn echo_repeat(mut self, repeat: u32) -> Self {
+        self.settings.handler = Arc::new(DefaultHandler::with_echo_repeat(repeat));
+        self
+    }
+
+    // Replaces the default echo/add handler
+    pub fn handler<H: MessageHandler + 'static>(mut self, handler: H) -> Self {
+        self.settings.handler = Arc::new(handler);
+        self
+    }
+
+    // Bounds how long a frame body may take to arrive once its length prefix was read, None disables the deadline
+    // Protects against clients declaring a large frame and trickling it slowly to hold a buffer
+    pub fn frame_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.settings.frame_deadline = deadline;
+        self
+    }
+
+    // Bounds the wait for the next message's length prefix, a connection that sends none in time is closed
+    // Meant to be generous, clients may idle between messages, while body_timeout keeps a started frame short
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.header_timeout = Some(timeout);
+        self
+    }
+
+    // Same as frame_deadline(Some(timeout)), the counterpart of header_timeout for the body
+    pub fn body_timeout(self, timeout: Duration) -> Self {
+        self.frame_deadline(Some(timeout))
+    }
+
+    // Logs a warning with the message type and duration whenever the handler takes longer than `threshold`
+    pub fn slow_handler_threshold(mut self, threshold: Duration) -> Self {
+        self.settings.slow_handler_threshold = Some(threshold);
+        self
+    }
+
+    // Frames with a larger body are handled according to the large message policy
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.settings.max_message_size = size;
+        self
+    }
+
+    // Largest echo a client may send in EchoChunks, a message growing past it is dropped and answered with Error
+    // Defaults to max_message_size, raise it to echo logical messages larger than one frame
+    pub fn max_reassembled_size(mut self, size: usize) -> Self {
+        self.settings.max_reassembled_size = Some(size);
+        self
+    }
+
+    // Copies the operands of every AddRequest onto its AddResponse, so a client can check the sum answers its own request
+    pub fn echo_add_operands(mut self, enabled: bool) -> Self {
+        self.settings.echo_add_operands = enabled;
+        self
+    }
+
+    // Chooses how frames above max_message_size are handled, Reject by default
+    pub fn large_message_policy(mut self, policy: LargeMessagePolicy) -> Self {
+        self.settings.large_message_policy = policy;
+        self
+    }
+
+    // Caps the responses a connection may have queued but not yet written (at least one), reaching it applies the
+    // backpressure policy
+    pub fn max_pending_responses(mut self, cap: usize) -> Self {
+        self.settings.max_pending_responses = cap;
+        self
+    }
+
+    // Buffers responses for up to `window` after the first one and writes them together, fewer syscalls and segments
+    // for bursts of small responses at the cost of up to `window` extra latency. Mostly useful with TCP_NODELAY on
+    pub fn response_coalesce_window(mut self, window: Duration) -> Self {
+        self.settings.response_coalesce_window = Some(window);
+        self
+    }
+
+    // Writes the responses of a handler returning several at once (RespondMany) in one socket write instead of one
+    // each, without the latency of a coalescing window since nothing waits for responses that haven't been produced
+    pub fn buffered_writes(mut self, enabled: bool) -> Self {
+        self.settings.buffered_writes = enabled;
+        self
+    }
+
+    // Accepted connections start with Nagle's algorithm on, and switch to TCP_NODELAY once they send a run of
+    // frames no larger than `small_message_size` bytes, so chatty clients get low latency and bulk senders keep batching
+    pub fn adaptive_nodelay(mut self, small_message_size: usize) -> Self {
+        self.settings.adaptive_nodelay = Some(small_message_size);
+        self
+    }
 
-//IMPORTS
-use crate::message::EchoMessage;  //A protobuf-generated message type used for encoding and decoding data.
-use log::{error, info, warn};     //log macros: error!, info!, warn! are used for logging.
-use prost::Message;               //Used for encoding/decoding Protocol Buffers
-use std::{
-    io::{self, ErrorKind, Read, Write},      //Handles I/O (reading/writing to streams)
-    net::{TcpListener, TcpStream},           //Provides networking utilities like TcpListener (server-side socket) and TcpStream (client-side connection).
-    sync::{                              //Includes synchronization primitives
-        atomic::{AtomicBool, Ordering, AtomicUsize},     //Manages a shared flag for server state, atomic types for managing client counts safely 
-        Arc, Mutex,                             //Ensures thread-safe sharing of resources
-    },
-    thread,                       //Used for creating threads
-    time::Duration,             // implementing delays.
-};
+    // Chooses what happens when a connection's response queue is full, Block by default
+    pub fn backpressure_policy(mut self, policy: BackpressurePolicy) -> Self {
+        self.settings.backpressure_policy = policy;
+        self
+    }
 
-//Client Struct
-struct Client {               //Shared, thread-safe stream. The stream field holds the TCP connection to the client.
-    stream: Arc<Mutex<TcpStream>>,
-    retries: usize, // Track retry attempts for errors
-}
+    // Bounds every socket write of a response, a write that times out applies the write retry policy
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.write_timeout = Some(timeout);
+        self
+    }
 
-//Client Implementation
-impl Client {
-    // 1- new() Method
-    pub fn new(stream: TcpStream) -> Self {       
-        Client {
-            stream: Arc::new(Mutex::new(stream)),     //Constructs a new Client instance with the provided TcpStream
-            retries: 0,
-        }                         
+    // Chooses what happens when a response write times out, Disconnect by default
+    pub fn write_retry(mut self, policy: WriteRetryPolicy) -> Self {
+        self.settings.write_retry = policy;
+        self
     }
-    
-    // 2- handle() Method
-    pub fn handle(&mut self) -> io::Result<()> {          
-        let mut buffer = vec![0; 512];                         // 512-byte buffer to store incoming data, Reuse this buffer across read calls instead of re-allocating
-        let mut stream = self.stream.lock().unwrap();     // Lock the stream
-        // Read data from the client
-        let bytes_read = stream.read(&mut buffer)?;        //Read client data
-        if bytes_read == 0 {
-            info!("Client disconnected.");
-            return Ok(());
-        }
-//Message Handling: Decodes data into an EchoMessage, If successful, logs the content, serializes it back to bytes (encode_to_vec), and sends it to the client. Errors are logged if decoding fails
-        match EchoMessage::decode(&buffer[..bytes_read]) { 
-            Ok(message) => {
-                info!("Received: {}", message.content);
-                // Echo back the message
-                let payload = message.encode_to_vec();                     //Serialize the response
-                stream.write_all(&payload)?;        //Send it back
-            }               
-            Err(e) => {
-                self.retries += 1;
-                error!(
-                    "Failed to decode message (attempt {}): {}", 
-                    self.retries, e
-                );
-                if self.retries > 3 {
-                    warn!("Too many decoding errors; disconnecting client.");
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Maximum retries reached",
-                    ));
-                }
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput, e.to_string()));   // Map DecodeError to a readable string
-                
-            }
-        }
 
-        Ok(())
+    // Requires the ClientMessages of each connection to carry seq 0, 1, 2... A gap or a regression is logged and the
+    // message answered with an Error instead of being handled, after a gap the count resumes from the seq received
+    pub fn strict_sequencing(mut self, enabled: bool) -> Self {
+        self.settings.strict_sequencing = enabled;
+        self
     }
-}
 
-//Server Struct
-pub struct Server {
-    listener: TcpListener,                //Listens for incoming connections
-    is_running: Arc<AtomicBool>,          // Shared running state, Ensures a shared, atomic flag to signal when the server is running.
-    client_threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>, // Track active client threads
-    client_count: Arc<AtomicUsize>, // Track the current number of clients connections using AtomicUsize.
-    max_clients: usize,            // Maximum allowed clients connections
-}
+    // Chooses what happens to a ClientMessage without a known message, Ignore by default
+    pub fn unknown_message_policy(mut self, policy: UnknownMessagePolicy) -> Self {
+        self.settings.unknown_message_policy = policy;
+        self
+    }
 
-impl Server {
-    // Creates a new server instance
-    pub fn new(addr: &str, max_clients: usize) -> io::Result<Self> {      //new() Method : Initializes the server by binding it to the provided address and setting its initial state as stopped.
-        let listener = TcpListener::bind(addr)?;                 // Bind to address
+    // Chooses what happens to requests arriving on open connections after drain() or shutdown(true), Serve by default
+    pub fn draining_request_policy(mut self, policy: DrainingRequestPolicy) -> Self {
+        self.settings.draining_request_policy = policy;
+        self
+    }
+
+    // Chooses what happens to requests that are past their expires_at_unix_nanos when the server gets to them, Drop by default
+    pub fn expired_request_policy(mut self, policy: ExpiredRequestPolicy) -> Self {
+        self.settings.expired_request_policy = policy;
+        self
+    }
+
+    // Answers a `rate` fraction (0.0 to 1.0) of requests with an injected fault instead of calling the handler, to test
+    // how clients cope with failures. Hello, Auth, Ping and the other messages the server answers itself are spared
+    pub fn fault_injection_rate(mut self, rate: f64) -> Self {
+        self.settings.fault_injection_rate = FaultRate::new(rate);
+        self
+    }
+
+    // Seeds the choice of faulty requests, the same seed and request order fail the same requests
+    pub fn fault_injection_seed(mut self, seed: u64) -> Self {
+        self.settings.fault_rng = Mutex::new(seed);
+        self
+    }
+
+    // Chooses what an injected fault does, Error by default
+    pub fn fault_action(mut self, action: FaultAction) -> Self {
+        self.settings.fault_action = action;
+        self
+    }
+
+    // Limits how many handler calls run at once across all connections, messages beyond it get ServerBusy
+    // Independent of max_clients, it protects the CPU from expensive handlers rather than limiting connections
+    pub fn max_concurrent_handlers(mut self, max: usize) -> Self {
+        self.settings.max_concurrent_handlers = Some(max);
+        self
+    }
+
+    // Runs the handler for the given message types (variant names such as "AddRequest") on a pool of `threads` threads
+    // of their own, so a flood of one slow type waits in its pool while other types are still handled right away.
+    // Pooled types aren't counted against max_concurrent_handlers. build() fails with InvalidInput for an unknown type, a
+    // type assigned to two pools or a pool of 0 threads, and with the spawn error if a pool thread can't be started
+    pub fn worker_pool(mut self, message_types: &[&str], threads: usize) -> Self {
+        self.settings.worker_pool_configs.push(WorkerPoolConfig {
+            message_types: message_types.iter().map(|kind| kind.to_string()).collect(),
+            threads,
+        });
+        self
+    }
+
+    // Records the frames every connection receives and sends, with timestamps, to a file in `dir` named by
+    // recording::recording_path(), see recording::replay() to play the client side back. Existing recordings are never
+    // overwritten. For debugging, it costs a file write per frame
+    pub fn record_traffic(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.settings.record_dir = Some(dir.into());
+        self
+    }
+
+    // Closes connections that send no frame (heartbeats included) for `timeout`
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Closes connections once they have been open for `lifetime`, however active they are
+    pub fn max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        self.settings.max_connection_lifetime = Some(lifetime);
+        self
+    }
+
+    // Closes connections once the bytes they received and sent, length prefixes included, add up to more than `max_bytes`
+    // The client is told with a QuotaExceeded message
+    pub fn max_bytes_per_connection(mut self, max_bytes: u64) -> Self {
+        self.settings.max_bytes_per_connection = Some(max_bytes);
+        self
+    }
+
+    // Pushes a StatusUpdate (active clients, uptime) every `interval` to connections that subscribed to STATUS_TOPIC
+    pub fn status_updates(mut self, interval: Duration) -> Self {
+        self.settings.status_interval = Some(interval);
+        self
+    }
+
+    // Bounds how long run() waits for the connection threads once stopped, `timeout` of real time in total however many
+    // there are, whatever the server clock. A thread still running then (e.g. a handler that never returns) is left detached and logged instead of stalling
+    // the shutdown
+    pub fn thread_join_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.thread_join_timeout = Some(timeout);
+        self
+    }
+
+    // Sets the stack size of each connection's handler and writer threads, lower it to fit more connections in memory
+    pub fn thread_stack_size(mut self, bytes: usize) -> Self {
+        self.settings.thread_stack_size = Some(bytes);
+        self
+    }
+
+    // Caps the protocol version the server negotiates, PROTOCOL_VERSION by default
+    // Messages introduced after the negotiated version are answered with UnsupportedOperation
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.settings.protocol_version = version.clamp(1, PROTOCOL_VERSION);
+        self
+    }
+
+    // Replaces the system clock used for deadlines and timeouts, tests pass a MockClock to expire them instantly
+    pub fn clock<C: Clock + 'static>(mut self, clock: Arc<C>) -> Self {
+        self.settings.clock = clock;
+        self
+    }
+
+    // Sets SO_RCVBUF/SO_SNDBUF on every accepted socket, None keeps the OS default
+    // Larger buffers cut write stalls for large payloads such as the 10 MB echo
+    pub fn socket_buffer_sizes(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.settings.recv_buffer_size = recv;
+        self.settings.send_buffer_size = send;
+        self
+    }
+
+    // Lets up to `capacity` connections wait for a free slot instead of being refused at capacity
+    pub fn accept_queue_capacity(mut self, capacity: usize) -> Self {
+        self.accept_queue_capacity = capacity;
+        self
+    }
+
+    // Chooses which waiting connection is served when a slot frees up
+    pub fn accept_order(mut self, order: AcceptOrder) -> Self {
+        self.accept_order = order;
+        self
+    }
+
+    // Lets the accept loop take up to `max` waiting connections in a row before it checks for stop() and runs its hook
+    // again, 1 by default. Higher values admit a burst faster, the cap keeps a connection storm from delaying shutdown
+    pub fn max_accepts_per_iteration(mut self, max: usize) -> Self {
+        self.max_accepts_per_iteration = max.max(1);
+        self
+    }
+
+    // Refuses connections from an address that already has `cap` open (or queued) connections
+    pub fn max_connections_per_ip(mut self, cap: usize) -> Self {
+        self.max_connections_per_ip = Some(cap);
+        self
+    }
+
+    // Binds the listeners with SO_REUSEPORT, so a replacement server can bind the same port before this one stops
+    // Unix only, binding fails with Unsupported elsewhere
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.reuse_port = enabled;
+        self
+    }
+
+    // Also accepts connections on a listener another server handed over, see Server::try_clone_listeners
+    pub fn inherit_listener(mut self, listener: TcpListener) -> Self {
+        self.inherited.push(listener);
+        self
+    }
+
+    // When the server is full and the accept queue has no room, closes the connection that has gone longest
+    // without a frame to make room for the new one, instead of refusing it. Only a connection idle for at least
+    // `min_idle` is evicted, a server full of active ones still refuses the newcomer
+    pub fn evict_idle_on_capacity(mut self, min_idle: Duration) -> Self {
+        self.evict_idle_on_capacity = Some(min_idle);
+        self
+    }
+
+    // Also listens on `addr` for admin messages (client list, metrics, kick, broadcast), which the data port refuses
+    // Admin connections go through the same auth verifier and don't count against max_clients
+    pub fn admin_address(mut self, addr: &str) -> Self {
+        self.admin_addr = Some(addr.to_string());
+        self
+    }
+
+    // Binds the listener and creates the server
+    pub fn build(self) -> io::Result<Server> {
+        let server = self.build_deferred();
+        server.bind_listeners()?;
+        Ok(server)
+    }
+
+    // Creates the server without binding, run() binds and returns any bind error
+    pub fn build_deferred(mut self) -> Server {
+        let mut pool_error = None;
+        for config in &self.settings.worker_pool_configs {
+            let pool = match WorkerPool::new(&config.message_types, config.threads, self.settings.handler.clone()) {
+                Ok(pool) => Arc::new(pool),
+                Err(e) => {
+                    pool_error = Some(e);       // Returned by bind_listeners(), from build() or run()
+                    break;
+                }
+            };
+            for kind in &config.message_types {
+                self.settings.worker_pools.insert(kind.clone(), pool.clone());
+            }
+        }
         let is_running = Arc::new(AtomicBool::new(false));        // Initialize running flag
         let client_threads = Arc::new(Mutex::new(Vec::new())); // Initialize client thread tracker
         let client_count = Arc::new(AtomicUsize::new(0));
-        Ok(Server {
-            listener,
+        let connections: Registry = Arc::new(Mutex::new(HashMap::new()));
+        let inflight = Arc::new(AtomicUsize::new(0));
+        let active_handlers = Arc::new(AtomicUsize::new(0));
+        let admin = Arc::new(Admin {
+            connections: connections.clone(),
+            client_count: client_count.clone(),
+            inflight: inflight.clone(),
+            active_handlers: active_handlers.clone(),
+        });
+        Server {
+            bind_addr: self.addr,
+            listeners: OnceLock::new(),
+            inherited: Mutex::new(self.inherited),
+            reuse_port: self.reuse_port,
+            handed_off: AtomicBool::new(false),
+            pool_error,
             is_running,
             client_threads,
             client_count,
-            max_clients,
-        })
+            client_count_changed: Arc::new((Mutex::new(()), Condvar::new())),
+            max_clients: self.max_clients,
+            connections,
+            admin_bind_addr: self.admin_addr,
+            admin_listener: OnceLock::new(),
+            admin_connections: Arc::new(Mutex::new(HashMap::new())),
+            admin,
+            settings: Arc::new(self.settings),
+            accept_queue: Mutex::new(VecDeque::new()),
+            accept_queue_capacity: self.accept_queue_capacity,
+            accept_order: self.accept_order,
+            max_accepts_per_iteration: self.max_accepts_per_iteration,
+            max_connections_per_ip: self.max_connections_per_ip,
+            evict_idle_on_capacity: self.evict_idle_on_capacity,
+            draining: Arc::new(AtomicBool::new(false)),
+            stop_when_idle: AtomicBool::new(false),
+            inflight,
+            subscriptions: Arc::new(Subscriptions::new()),
+            active_handlers,
+            response_writes: Arc::new(AtomicUsize::new(0)),
+            recordings: AtomicU64::new(0),
+            rejections: RejectionCounters::default(),
+        }
+    }
+}
+
+impl Server {
+    // Creates a new server instance
+    pub fn new(addr: &str, max_clients: usize) -> io::Result<Self> {      //new() Method : Initializes the server by binding it to the provided address and setting its initial state as stopped.
+        Server::builder(addr).max_clients(max_clients).build()
+    }
+
+    // Creates a server that only binds when run() is called, so a busy port fails there instead of here
+    pub fn deferred(addr: &str, max_clients: usize) -> Self {
+        Server::builder(addr).max_clients(max_clients).build_deferred()
+    }
+
+    // Creates a server accepting on a listener bound elsewhere, for instance one handed over by a server being replaced
+    pub fn from_listener(listener: TcpListener, max_clients: usize) -> io::Result<Self> {
+        Server::builder_for(BindAddr::Inherited).max_clients(max_clients).inherit_listener(listener).build()
+    }
+
+    // Creates a server bound to an already parsed address, no host name resolution takes place
+    pub fn bind(addr: SocketAddr, max_clients: usize) -> io::Result<Self> {
+        Server::builder_for(BindAddr::Socket(addr)).max_clients(max_clients).build()
+    }
+
+    // Starts building a server with optional settings, see ServerBuilder
+    pub fn builder(addr: &str) -> ServerBuilder {
+        Server::builder_for(BindAddr::Host(addr.to_string()))
+    }
+
+    fn builder_for(addr: BindAddr) -> ServerBuilder {
+        ServerBuilder {
+            addr,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            settings: Settings::default(),
+            accept_queue_capacity: 0,
+            accept_order: AcceptOrder::default(),
+            max_accepts_per_iteration: 1,
+            max_connections_per_ip: None,
+            evict_idle_on_capacity: None,
+            admin_addr: None,
+            inherited: Vec::new(),
+            reuse_port: false,
+        }
+    }
+
+    // Returns the address the server is bound to, useful when binding to port 0
+    // For a host name with several addresses it is the first one, all of them share its port
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners()?[0].local_addr()
+    }
+
+    // Returns every address the server listens on for client connections
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners()?.iter().map(TcpListener::local_addr).collect()
+    }
+
+    // Returns the address of the admin listener, None if the server has none
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_listener.get().and_then(|listener| listener.local_addr().ok())
+    }
+
+    // Returns the number of currently connected clients
+    pub fn active_client_count(&self) -> usize {
+        self.client_count.load(Ordering::SeqCst)
+    }
+
+    // Returns how many worker pool threads are running, pools start all their threads when the server is built
+    pub fn handler_thread_count(&self) -> usize {
+        let mut pools: Vec<&Arc<WorkerPool>> = Vec::new();
+        for pool in self.settings.worker_pools.values() {
+            if !pools.iter().any(|seen| Arc::ptr_eq(seen, pool)) {
+                pools.push(pool);     // One pool serves every type assigned to it
+            }
+        }
+        pools.iter().map(|pool| pool.thread_count()).sum()
+    }
+
+    // Blocks until exactly `n` clients are connected, returns false if that didn't happen within `timeout`
+    // Lets tests wait for connections (or, with a lower `n`, disconnections) without sleeping
+    pub fn wait_for_clients(&self, n: usize, timeout: Duration) -> bool {
+        let (lock, changed) = &*self.client_count_changed;
+        let deadline = Instant::now() + timeout;
+        let mut guard = lock.lock().unwrap();
+        while self.client_count.load(Ordering::SeqCst) != n {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            guard = changed.wait_timeout(guard, remaining).unwrap().0;
+        }
+        true
+    }
+
+    // Returns how many connections were refused so far, for each reason
+    pub fn rejection_stats(&self) -> RejectionStats {
+        RejectionStats {
+            at_capacity: self.rejections.at_capacity.load(Ordering::SeqCst),
+            per_ip_limit: self.rejections.per_ip_limit.load(Ordering::SeqCst),
+            draining: self.rejections.draining.load(Ordering::SeqCst),
+            server_busy: self.rejections.server_busy.load(Ordering::SeqCst),
+        }
+    }
+
+    // Returns the number of open connections from `ip`, for diagnostics and rate limiting
+    pub fn connection_count_for_ip(&self, ip: IpAddr) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|addr| addr.ip() == ip)
+            .count()
+    }
+
+    // Returns the correlation ids of the requests a connection has received and not answered yet, oldest first, for
+    // diagnosing stuck pipelined clients. Pipelined requests that arrived whole are read ahead of their turn and
+    // listed too. Requests without a correlation id aren't listed. Empty for unknown peers.
+    pub fn inflight_requests(&self, connection_id: SocketAddr) -> Vec<u64> {
+        match self.connections.lock().unwrap().get(&connection_id) {
+            Some(connection) => connection.requests.lock().unwrap().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // Calls `callback` for every connected client. The registry is copied and unlocked first, so a slow callback
+    // never holds up accepts, clients connecting or leaving meanwhile may be missed or still be listed
+    pub fn for_each_client<F: FnMut(&ConnectionInfo)>(&self, mut callback: F) {
+        let mut snapshot: Vec<ConnectionInfo> = self
+            .connections
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|&address| ConnectionInfo { address })
+            .collect();
+        snapshot.sort_by_key(|info| info.address);
+        for info in &snapshot {
+            callback(info);
+        }
+    }
+
+    // Returns the number of accepted connections waiting for a free slot
+    pub fn queued_connection_count(&self) -> usize {
+        self.accept_queue.lock().unwrap().len()
+    }
+
+    // Returns how many socket writes responses took so far across all connections, for tuning response_coalesce_window
+    pub fn response_writes(&self) -> usize {
+        self.response_writes.load(Ordering::SeqCst)
+    }
+
+    // Returns the effective configuration, for diagnostics
+    pub fn config(&self) -> ServerConfig {
+        ServerConfig {
+            address: self.local_addr().ok(),
+            max_clients: self.max_clients,
+            max_connections_per_ip: self.max_connections_per_ip,
+            accept_queue_capacity: self.accept_queue_capacity,
+            evict_idle_on_capacity_ms: self.evict_idle_on_capacity.map(|min_idle| min_idle.as_millis()),
+            reuse_port: self.reuse_port,
+            accept_order: self.accept_order,
+            max_accepts_per_iteration: self.max_accepts_per_iteration,
+            auth_required: self.settings.auth_verifier.is_some(),
+            frame_deadline_ms: self.settings.frame_deadline.map(|deadline| deadline.as_millis()),
+            header_timeout_ms: self.settings.header_timeout.map(|timeout| timeout.as_millis()),
+            slow_handler_threshold_ms: self.settings.slow_handler_threshold.map(|threshold| threshold.as_millis()),
+            recv_buffer_size: self.settings.recv_buffer_size,
+            send_buffer_size: self.settings.send_buffer_size,
+            max_message_size: self.settings.max_message_size,
+            max_reassembled_size: self.settings.max_reassembled_size(),
+            large_message_policy: self.settings.large_message_policy,
+            max_pending_responses: self.settings.max_pending_responses,
+            response_coalesce_window_ms: self.settings.response_coalesce_window.map(|window| window.as_millis()),
+            buffered_writes: self.settings.buffered_writes,
+            adaptive_nodelay_threshold: self.settings.adaptive_nodelay,
+            backpressure_policy: self.settings.backpressure_policy,
+            write_timeout_ms: self.settings.write_timeout.map(|timeout| timeout.as_millis()),
+            write_retry: self.settings.write_retry,
+            unknown_message_policy: self.settings.unknown_message_policy,
+            expired_request_policy: self.settings.expired_request_policy,
+            draining_request_policy: self.settings.draining_request_policy,
+            strict_sequencing: self.settings.strict_sequencing,
+            echo_add_operands: self.settings.echo_add_operands,
+            fault_injection_rate: self.settings.fault_injection_rate,
+            fault_action: self.settings.fault_action,
+            max_concurrent_handlers: self.settings.max_concurrent_handlers,
+            worker_pools: self.settings.worker_pool_configs.clone(),
+            idle_timeout_ms: self.settings.idle_timeout.map(|idle| idle.as_millis()),
+            max_connection_lifetime_ms: self.settings.max_connection_lifetime.map(|lifetime| lifetime.as_millis()),
+            max_bytes_per_connection: self.settings.max_bytes_per_connection,
+            status_interval_ms: self.settings.status_interval.map(|interval| interval.as_millis()),
+            thread_join_timeout_ms: self.settings.thread_join_timeout.map(|timeout| timeout.as_millis()),
+            thread_stack_size: self.settings.thread_stack_size,
+            admin_address: self.admin_addr(),
+            protocol_version: self.settings.protocol_version,
+            record_dir: self.settings.record_dir.clone(),
+        }
+    }
+
+    // Returns true while the accept loop is running
+    pub fn is_running(&self) -> bool {
+        self.is_running.load(Ordering::SeqCst)
     }
 
     //run() Method
     // Runs the server, listening for incoming connections and handling them
+    // Fails immediately with AlreadyExists ("AlreadyRunning") if another thread is already running it
     pub fn run(&self) -> io::Result<()> {
-        self.is_running.store(true, Ordering::SeqCst);             // Set running flag
-        // Set the listener to non-blocking mode
-        self.listener.set_nonblocking(true)?;               //Make the listener non-blocking to avoid halting the program if there are no incoming connections.
-        info!("Server is running on {}", self.listener.local_addr()?);  
+        self.serve(None)
+    }
+
+    //run_with_ready() Method
+    // Same as run(), but sends the first listener's address on `ready` once it is bound and listening, right before the
+    // accept loop starts, so a caller can connect without polling. `ready` is dropped unsent if the server fails to start
+    pub fn run_with_ready(&self, ready: mpsc::Sender<SocketAddr>) -> io::Result<()> {
+        self.serve(Some(ready))
+    }
+
+    fn serve(&self, ready: Option<mpsc::Sender<SocketAddr>>) -> io::Result<()> {
+        // Set running flag, a second accept loop on the same listener is refused
+        if self
+            .is_running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            warn!("run() called while the server is already running.");
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "Server is already running"));
+        }
+        // A deferred server binds here, then the listeners are set to non-blocking mode
+        let listeners = match self.bind_listeners().and_then(|_| self.set_listeners_nonblocking()) {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                error!("Failed to start listening on {}: {}", self.bind_addr, e);
+                self.is_running.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        for listener in listeners {
+            info!("Server is running on {}", listener.local_addr()?);
+        }
+        if let Some(addr) = self.admin_addr() {
+            info!("Admin port listening on {}", addr);
+        }
+        if let Some(interval) = self.settings.status_interval {
+            match self.spawn_status_updates(interval) {
+                Ok(status) => self.client_threads.lock().unwrap().push(status),      // Joined with the connection threads on stop
+                Err(e) => {
+                    error!("Failed to start the status update thread: {}", e);
+                    self.is_running.store(false, Ordering::SeqCst);
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(ready) = ready {
+            let _ = ready.send(listeners[0].local_addr()?);    // The caller may have stopped waiting
+        }
 
        // Connection Handling Loop
         while self.is_running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((mut stream, addr)) => {
-
-                    let current_clients = self.client_count.load(Ordering::SeqCst);
-                    if current_clients >= self.max_clients {
-                        warn!("Connection refused: Max clients reached. Address: {}", addr);
-                        
-                        let _ = stream.write_all(b"Server is at full capacity.\n");
-
-                        continue;
-                    }
-
-                    info!("New client connected: {}", addr);
-                    self.client_count.fetch_add(1, Ordering::SeqCst);
-
-                    let mut client = Client::new(stream);    // New client instance
-                    // Handle each client in a separate thread
-                    let is_running = self.is_running.clone();
-                    let client_threads = self.client_threads.clone();
-                    let client_count = self.client_count.clone();
-                    let handle = thread::spawn(move || {
-                        while is_running.load(Ordering::SeqCst) {
-                            if let Err(e) = client.handle() {
-                            error!("Error handling client ({}): {}", addr, e);
-                                break;   // Disconnect on error
-                            }   
-                        }
-                    // Decrement client count on disconnection
-                    client_count.fetch_sub(1, Ordering::SeqCst);
-                    info!("Client handler thread exiting for {}", addr);
-                });
-                client_threads.lock().unwrap().push(handle); // Track thread
+            // Accept and register under the registry lock, so stop() never misses a half-accepted connection
+            let mut connections = self.connections.lock().unwrap();
+            if !self.is_running.load(Ordering::SeqCst) {
+                break;
             }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    // No incoming connections, sleep briefly to reduce CPU usage
-                    thread::sleep(Duration::from_millis(10));       // Tuned for quicker response
+            let mut queue = self.accept_queue.lock().unwrap();
+            // Up to max_accepts_per_iteration connections, then the loop checks is_running and runs its hook again
+            let mut accepted = false;
+            for _ in 0..self.max_accepts_per_iteration {
+                let incoming = if self.handed_off.load(Ordering::SeqCst) {
+                    Err(io::Error::from(ErrorKind::WouldBlock))     // The server that inherited the listeners accepts
+                } else {
+                    accept_any(listeners)
+                };
+                let admitted = match incoming {
+                    Ok((stream, addr)) if self.draining.load(Ordering::SeqCst) => {
+                        warn!("Connection refused: Server is draining. Address: {}", addr);
+                        self.rejections.draining.fetch_add(1, Ordering::SeqCst);
+                        drop(stream);        // Closes the connection
+                        true
+                    }
+                    Ok((stream, addr)) => {
+                        let has_free_slot = self.client_count.load(Ordering::SeqCst) < self.max_clients;
+                        let from_same_ip = connections.keys().chain(queue.iter().map(|(_, queued)| queued))
+                            .filter(|other| other.ip() == addr.ip())
+                            .count();
+                        if self.max_connections_per_ip.is_some_and(|cap| from_same_ip >= cap) {
+                            warn!("Connection refused: Too many connections from {}.", addr.ip());
+                            self.rejections.per_ip_limit.fetch_add(1, Ordering::SeqCst);
+                        } else if has_free_slot || queue.len() < self.accept_queue_capacity {
+                            queue.push_back((stream, addr));      // Served below, in accept_order
+                        } else if self.evict_idle_on_capacity.is_some_and(|min_idle| self.evict_longest_idle(min_idle, &mut connections)) {
+                            queue.push_back((stream, addr));      // Served once the evicted connection releases its slot
+                        } else {
+                            warn!("Connection refused: Max clients reached. Address: {}", addr);
+                            self.rejections.at_capacity.fetch_add(1, Ordering::SeqCst);
+                            reject_at_capacity(stream, self.max_clients);
+                        }
+                        true
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);   // Log unexpected errors
+                        false
+                    }
+                };
+
+                // Hand queued connections to handler threads while there are free slots
+                while self.client_count.load(Ordering::SeqCst) < self.max_clients {
+                    let next = match self.accept_order {
+                        AcceptOrder::Fifo => queue.pop_front(),
+                        AcceptOrder::Lifo => queue.pop_back(),
+                    };
+                    match next {
+                        Some((stream, addr)) => self.start_handler(stream, addr, &mut connections, false),
+                        None => break,
+                    }
                 }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);   // Log unexpected errors
+                if !admitted {
+                    break;
                 }
+                accepted = true;
+            }
+            let idle = queue.is_empty() && self.client_count.load(Ordering::SeqCst) == 0;
+            drop(queue);
+            drop(connections);
+            if idle && self.stop_when_idle.load(Ordering::SeqCst) {
+                info!("Accept queue drained and all connections closed.");
+                self.stop();
+                break;
+            }
+            let admin_accepted = self.accept_admin();
+            if let Some(hook) = &self.settings.accept_loop_hook {
+                hook(self);
+            }
+
+            if !accepted && !admin_accepted {
+                // No incoming connections, sleep briefly to reduce CPU usage
+                self.settings.clock.sleep(Duration::from_millis(10));       // Tuned for quicker response
             }
         }
         self.cleanup_threads(); // Ensure proper cleanup on server stop
@@ -153,12 +2570,346 @@ impl Server {
         Ok(())
     }
 
+    // Sends a StatusUpdate to STATUS_TOPIC subscribers every `interval` until the server stops
+    fn spawn_status_updates(&self, interval: Duration) -> io::Result<thread::JoinHandle<()>> {
+        let is_running = self.is_running.clone();
+        let subscriptions = self.subscriptions.clone();
+        let client_count = self.client_count.clone();
+        let clock = self.settings.clock.clone();
+        self.settings.thread_builder().spawn(move || {
+            let started = clock.now();
+            let mut next = started + interval;
+            while is_running.load(Ordering::SeqCst) {
+                let now = clock.now();
+                if now < next {
+                    clock.sleep((next - now).min(TIMEOUT_POLL));     // Short enough to notice stop() and a mock clock advancing
+                    continue;
+                }
+                let status = StatusUpdate {
+                    active_clients: client_count.load(Ordering::SeqCst) as u64,
+                    uptime_ms: now.duration_since(started).as_millis() as u64,
+                };
+                subscriptions.deliver(STATUS_TOPIC, || server_message::Message::StatusUpdate(status));
+                next += interval;
+            }
+        })
+    }
+
+    // Closes the data connection that has gone longest without a frame, returns false if there is none or it had one
+    // within `min_idle`
+    // It leaves the registry right away so the next arrival doesn't pick it again, its handler thread releases the slot
+    fn evict_longest_idle(&self, min_idle: Duration, connections: &mut HashMap<SocketAddr, Connection>) -> bool {
+        let now = self.settings.clock.now();
+        let Some(addr) = connections
+            .iter()
+            .map(|(addr, connection)| (*addr, *connection.last_activity.lock().unwrap()))
+            .min_by_key(|(_, last_activity)| *last_activity)
+            .filter(|(_, last_activity)| now.saturating_duration_since(*last_activity) >= min_idle)
+            .map(|(addr, _)| addr)
+        else {
+            return false;
+        };
+        if let Some(connection) = connections.remove(&addr) {
+            info!("Evicting idle client {} to make room for a new connection.", addr);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        true
+    }
+
+    // Binds the listeners that aren't bound yet
+    fn bind_listeners(&self) -> io::Result<()> {
+        // A pool for a misspelled message type would silently never run
+        let pooled: Vec<&String> = self.settings.worker_pool_configs.iter().flat_map(|config| &config.message_types).collect();
+        if let Some(kind) = pooled.iter().find(|kind| !MESSAGE_KINDS.contains(&kind.as_str())) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("Worker pool for unknown message type {}", kind)));
+        }
+        // The last pool would take every message of the type, the threads of the others would never run
+        if let Some(kind) = pooled.iter().enumerate().find(|(index, kind)| pooled[..*index].contains(kind)).map(|(_, kind)| kind) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("Message type {} assigned to two worker pools", kind)));
+        }
+        if self.settings.worker_pool_configs.iter().any(|config| config.threads == 0) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Worker pool without threads"));
+        }
+        if let Some(e) = &self.pool_error {
+            return Err(io::Error::new(e.kind(), format!("Failed to start a worker pool thread: {}", e)));
+        }
+        if self.listeners.get().is_none() {
+            let mut listeners = self.bind_addr.listen(self.reuse_port)?;     // Bind to address
+            listeners.append(&mut self.inherited.lock().unwrap());
+            if listeners.is_empty() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "No listener was handed over"));
+            }
+            let _ = self.listeners.set(listeners);
+        }
+        if let (Some(addr), None) = (&self.admin_bind_addr, self.admin_listener.get()) {
+            let _ = self.admin_listener.set(TcpListener::bind(addr)?);
+        }
+        Ok(())
+    }
+
+    // The bound client listeners, never empty, NotConnected for a deferred server that hasn't run yet
+    fn listeners(&self) -> io::Result<&[TcpListener]> {
+        self.listeners
+            .get()
+            .map(Vec::as_slice)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Server is not bound yet"))
+    }
+
+    // Makes the listeners non-blocking so the accept loop can poll all of them and check is_running, returns the client ones
+    fn set_listeners_nonblocking(&self) -> io::Result<&[TcpListener]> {
+        let listeners = self.listeners()?;
+        for listener in listeners {
+            listener.set_nonblocking(true)?;
+        }
+        if let Some(admin_listener) = self.admin_listener.get() {
+            admin_listener.set_nonblocking(true)?;
+        }
+        Ok(listeners)
+    }
+
+    // Applies the configured socket buffer sizes, write timeout and Nagle setting to an accepted stream
+    fn apply_socket_options(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.settings.adaptive_nodelay.is_some() {
+            stream.set_nodelay(false)?;        // Adaptive nodelay starts from Nagle on
+        }
+        let socket = SockRef::from(stream);
+        if let Some(size) = self.settings.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.settings.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        stream.set_write_timeout(self.settings.write_timeout)?;
+        Ok(())
+    }
+
+    // Accepts one pending admin connection, returns true if one was accepted
+    fn accept_admin(&self) -> bool {
+        let Some(admin_listener) = self.admin_listener.get() else {
+            return false;
+        };
+        // Registered under the admin registry lock, like data connections
+        let mut admin_connections = self.admin_connections.lock().unwrap();
+        if !self.is_running.load(Ordering::SeqCst) {
+            return false;
+        }
+        match admin_listener.accept() {
+            Ok((stream, addr)) => {
+                self.start_handler(stream, addr, &mut admin_connections, true);
+                true
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => {
+                error!("Error accepting admin connection: {}", e);
+                false
+            }
+        }
+    }
+
+    // Registers the connection and spawns its handler thread, the caller holds the lock of the matching registry
+    // Admin connections take no client slot
+    fn start_handler(&self, stream: TcpStream, addr: SocketAddr, registry: &mut HashMap<SocketAddr, Connection>, admin_port: bool) {
+        info!("New {} connected: {}", if admin_port { "admin client" } else { "client" }, addr);
+        // Accepted sockets must block, only the listener polls
+        let (tracked, writer, overflow) = match stream
+            .set_nonblocking(false)
+            .and_then(|_| self.apply_socket_options(&stream))
+            .and_then(|_| Ok((stream.try_clone()?, stream.try_clone()?, stream.try_clone()?)))
+        {
+            Ok(clones) => clones,
+            Err(e) => {
+                error!("Failed to set up connection ({}): {}", addr, e);
+                return;
+            }
+        };
+        let tap = self.settings.record_dir.as_ref().and_then(|dir| loop {
+            let path = recording::recording_path(dir, addr, self.recordings.fetch_add(1, Ordering::SeqCst));
+            match Recorder::create(&path) {
+                Ok(recorder) => break Some(Arc::new(recorder)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}     // Left by an earlier server, try the next number
+                Err(e) => {
+                    warn!("Not recording {}, failed to create {}: {}", addr, path.display(), e);
+                    break None;
+                }
+            }
+        });
+        let (frames, queue) = priority::channel(self.settings.max_pending_responses);
+        let (writer_thread, writer_finished) = match spawn_writer(
+            self.settings.thread_builder(),
+            writer,
+            queue,
+            addr,
+            self.settings.response_coalesce_window,
+            self.settings.write_retry,
+            self.response_writes.clone(),
+            tap.clone(),
+            self.settings.clock.clone(),
+        ) {
+            Ok(writer_thread) => writer_thread,
+            Err(e) => {
+                error!("Failed to start a writer thread for {}, refusing it: {}", addr, e);
+                self.rejections.server_busy.fetch_add(1, Ordering::SeqCst);
+                reject_busy(&stream);
+                return;
+            }
+        };
+        let writer = Arc::new(Mutex::new(ResponseWriter {
+            frames,
+            stream: overflow,
+            policy: self.settings.backpressure_policy,
+            queued: 0,
+            sent_at: 0,
+            correlation_id: 0,
+            priority: 0,
+            addr,
+            events: self.settings.event_listener.clone(),
+            full: false,
+            tap,
+            sent_bytes: 0,
+            received_bytes: Arc::new(AtomicU64::new(0)),
+            max_bytes: self.settings.max_bytes_per_connection,
+            over_quota: false,
+            buffered: self.settings.buffered_writes,
+            batch: None,
+            batch_bytes: 0,
+            batch_limit: self.settings.max_message_size,
+        }));
+        let mut client = Client::new(stream, writer.clone(), addr, self, admin_port);    // New client instance
+        registry.insert(addr, Connection {
+            stream: tracked,
+            writer,
+            last_activity: client.last_activity.clone(),
+            requests: client.requests.clone(),
+        });
+        let slot = (!admin_port).then(|| ConnectionSlot::acquire(&self.client_count, &self.client_count_changed));   // Released by the handler thread, exactly once
+
+        // Handle each client in a separate thread
+        let is_running = self.is_running.clone();
+        let connections = if admin_port { self.admin_connections.clone() } else { self.connections.clone() };
+        let subscriptions = self.subscriptions.clone();
+        let spawned = self.settings.thread_builder().spawn(move || {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::info_span!("connection", peer = %addr, admin = admin_port).entered();
+            while is_running.load(Ordering::SeqCst) {
+                match client.handle() {
+                    Ok(true) => {}
+                    Ok(false) => break,     // Client disconnected
+                    Err(e) => {
+                        error!("Error handling client ({}): {}", addr, e);
+                        break;   // Disconnect on error
+                    }
+                }
+            }
+            if !is_running.load(Ordering::SeqCst) {
+                client.goodbye();      // Queued behind the last response, the writer flushes both before the socket closes
+            }
+            // Decrement client count on disconnection
+            let connection = connections.lock().unwrap().remove(&addr);
+            subscriptions.remove_connection(addr);
+            drop(slot);
+            drop(client);      // The last ResponseWriter, the writer thread exits once the queue is flushed
+            close_after_flush(writer_thread, writer_finished, connection.map(|connection| connection.stream));
+            info!("Client handler thread exiting for {}", addr);
+        });
+        let mut threads = self.client_threads.lock().unwrap();
+        match spawned {
+            Ok(handle) => threads.push(handle), // Track thread
+            Err(e) => {
+                // The dropped closure released the slot and the writer, so the writer thread exits on its own
+                error!("Failed to start a handler thread for {}, refusing it: {}", addr, e);
+                self.rejections.server_busy.fetch_add(1, Ordering::SeqCst);
+                if let Some(connection) = registry.remove(&addr) {
+                    reject_busy(&connection.stream);
+                }
+            }
+        }
+    }
+
+    // Starts a graceful shutdown: new connections are refused and queued ones closed, open connections keep being served
+    // Poll draining_status() until it reaches zero, then call stop()
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        for (stream, _) in self.accept_queue.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);     // Queued connections have no work yet
+            self.rejections.draining.fetch_add(1, Ordering::SeqCst);
+        }
+        info!("Server is draining.");
+    }
+
+    // Duplicates the client listeners, to be passed to ServerBuilder::inherit_listener of a replacement server
+    pub fn try_clone_listeners(&self) -> io::Result<Vec<TcpListener>> {
+        self.listeners()?.iter().map(TcpListener::try_clone).collect()
+    }
+
+    // Stops accepting client connections without refusing any, once a replacement server accepts on the same listeners
+    // Connections already open keep being served, poll draining_status() until it reaches zero, then call stop()
+    pub fn hand_off(&self) {
+        self.handed_off.store(true, Ordering::SeqCst);
+        info!("Server handed its listeners off.");
+    }
+
+    // Returns true once drain() was called
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Returns the connections and messages still being served, pollable while draining
+    pub fn draining_status(&self) -> DrainStatus {
+        DrainStatus {
+            remaining_connections: self.client_count.load(Ordering::SeqCst),
+            remaining_inflight: self.inflight.load(Ordering::SeqCst),
+        }
+    }
+
+    // Shuts the server down. With `drain_accept_queue` set, new connections are refused but those already waiting in the
+    // accept queue are still served, the server stops once the queue is empty and every connection has closed.
+    // Without it this is stop(), queued connections are closed unserved.
+    pub fn shutdown(&self, drain_accept_queue: bool) {
+        if !drain_accept_queue {
+            self.stop();
+            return;
+        }
+        self.draining.store(true, Ordering::SeqCst);       // Refuses new connections, unlike drain() the queue is kept
+        self.stop_when_idle.store(true, Ordering::SeqCst);
+        info!("Server is shutting down after its accept queue.");
+    }
+
 //stop() Method to Safely stops the server
-    //Stops the server by setting the `is_running` flag to `false`
+    //Stops the server in a fixed order, each step only starting once the previous one can't be undone by a race:
+    // 1. is_running goes false: the accept loop stops accepting and queued connections are closed
+    // 2. Every connection stops reading: handler threads waiting for a request see EOF, one handling a request
+    //    finishes it and queues its response, none reads another request
+    // 3. Each handler thread then queues a Goodbye behind that response
+    // 4. The connection's writer thread flushes both and exits, which closes the socket
+    // 5. run() joins every connection thread (see ServerBuilder::thread_join_timeout) and returns
+    //Steps 1 and 2 happen here, so stop() doesn't block, the rest on the connection threads and in run()
     pub fn stop(&self) {
-        if self.is_running.load(Ordering::SeqCst) {
-            self.is_running.store(false, Ordering::SeqCst);  // Set running flag to false
-            info!("Shutdown signal sent.");
+        // One atomic transition, so of several concurrent callers exactly one shuts the server down
+        if self.is_running.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let connections = self.connections.lock().unwrap();
+            let admin_connections = self.admin_connections.lock().unwrap();
+            for connection in connections.values().chain(admin_connections.values()) {
+                let _ = connection.stream.shutdown(Shutdown::Read);     // Unblocks handler threads waiting on a read, writes still go out
+            }
+            for (stream, _) in self.accept_queue.lock().unwrap().drain(..) {
+                let _ = stream.shutdown(Shutdown::Both);     // Queued connections are never served
+            }
+            // Close connections still waiting in the accept backlog, unless another server now accepts from it
+            let listeners = self.listeners.get().filter(|_| !self.handed_off.load(Ordering::SeqCst));
+            for listener in listeners.into_iter().flatten() {
+                while let Ok((stream, _)) = listener.accept() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+            if let Some(admin_listener) = self.admin_listener.get() {
+                while let Ok((stream, _)) = admin_listener.accept() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+            match self.local_addr() {
+                Ok(addr) => info!("Shutdown signal sent to the server on {}.", addr),
+                Err(_) => info!("Shutdown signal sent."),
+            }
         } else {
             warn!("Server was already stopped or not running.");
         }
@@ -167,9 +2918,30 @@ impl Server {
     fn cleanup_threads(&self) {
         let mut threads = self.client_threads.lock().unwrap();
         info!("Cleaning up {} client threads.", threads.len());
+        // One deadline for every thread, so several stuck ones don't add up their timeouts. Real time rather than the
+        // server clock: the wait below blocks in real time, and a MockClock's deadline would never pass
+        let deadline = self.settings.thread_join_timeout.map(|timeout| (Instant::now() + timeout, timeout));
         for handle in threads.drain(..) {
-            if let Err(e) = handle.join() {
-                error!("Failed to join thread: {:?}", e);
+            let Some((deadline, timeout)) = deadline.filter(|_| !handle.is_finished()) else {
+                if let Err(e) = handle.join() {
+                    error!("Failed to join thread: {:?}", e);
+                }
+                continue;
+            };
+            // JoinHandle can't join with a timeout, a helper thread joins and reports back
+            let id = handle.thread().id();
+            let (joined, result) = mpsc::channel();
+            let helper = thread::Builder::new().spawn(move || {
+                let _ = joined.send(handle.join());
+            });
+            if let Err(e) = helper {
+                warn!("Abandoned thread {:?}, no thread to join it: {}", id, e);     // The handle went with the closure, detaching it
+                continue;
+            }
+            match result.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to join thread: {:?}", e),
+                Err(_) => warn!("Abandoned thread {:?}, still running {:?} after the server stopped.", id, timeout),
             }
         }
     }
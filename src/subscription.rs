@@ -0,0 +1,131 @@
+//Topic subscriptions: connections subscribe to topics and receive a Notification for every Publish on them.
+//Subscriptions belong to a session, so a client that reconnects can resubscribe with its session token.
+
+//IMPORTS
+use crate::message::{server_message, Notification};   //Protobuf-generated message types
+use crate::server::SharedWriter;                      //Response writer of a subscribed connection
+use log::warn;                                        //Logs failed deliveries
+use std::{
+    collections::{HashMap, HashSet, VecDeque},   //Topic and session registries, session issue order
+    hash::{BuildHasher, Hasher, RandomState}, //Unpredictable session tokens without an extra dependency
+    net::SocketAddr,                          //Identifies subscribed connections
+    sync::{
+        atomic::{AtomicU64, Ordering},        //Makes every session token unique
+        Mutex,
+    },
+};
+
+const MAX_SESSIONS: usize = 10_000;      // Sessions remembered for Resubscribe, the oldest is forgotten past this
+
+//Subscriptions: shared by every connection handler of a server
+pub(crate) struct Subscriptions {
+    sessions: Mutex<Sessions>,                                         // Session tokens issued by this server
+    topics: Mutex<HashMap<String, HashMap<SocketAddr, SharedWriter>>>, // Subscribed connections per topic
+    hasher: RandomState,                                               // Randomly keyed per server
+    next_session: AtomicU64,
+}
+
+//Sessions: the topics subscribed under each session, bounded by MAX_SESSIONS
+#[derive(Default)]
+struct Sessions {
+    topics: HashMap<String, HashSet<String>>,     // Topics subscribed under each session token
+    issued: VecDeque<String>,                     // Tokens in issue order, the front is forgotten first
+}
+
+impl Subscriptions {
+    pub(crate) fn new() -> Self {
+        Subscriptions {
+            sessions: Mutex::new(Sessions::default()),
+            topics: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+            next_session: AtomicU64::new(0),
+        }
+    }
+
+    // Issues a new session token
+    pub(crate) fn new_session(&self) -> String {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(self.next_session.fetch_add(1, Ordering::SeqCst));
+        let token = format!("{:016x}", hasher.finish());
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.issued.len() >= MAX_SESSIONS {
+            if let Some(oldest) = sessions.issued.pop_front() {
+                sessions.topics.remove(&oldest);
+            }
+        }
+        sessions.topics.insert(token.clone(), HashSet::new());
+        sessions.issued.push_back(token.clone());
+        token
+    }
+
+    // Subscribes the connection at `addr` to `topic` under `session`, subscribing twice has no extra effect
+    pub(crate) fn subscribe(&self, session: &str, topic: &str, addr: SocketAddr, writer: &SharedWriter) {
+        if let Some(topics) = self.sessions.lock().unwrap().topics.get_mut(session) {
+            topics.insert(topic.to_string());
+        }
+        self.add_subscriber(topic, addr, writer);
+    }
+
+    // Resubscribes the connection at `addr` to the `requested` topics that were subscribed under `session`, topics the
+    // session never subscribed are left out. Returns the restored topics, None if this server didn't issue `session`
+    pub(crate) fn resubscribe(&self, session: &str, requested: &[String], addr: SocketAddr, writer: &SharedWriter) -> Option<Vec<String>> {
+        let restored: Vec<String> = {
+            let sessions = self.sessions.lock().unwrap();
+            let topics = sessions.topics.get(session)?;
+            requested.iter().filter(|topic| topics.contains(*topic)).cloned().collect()
+        };
+        if restored.len() < requested.len() {
+            warn!("Resubscribe named {} topics its session never subscribed, ignoring them.", requested.len() - restored.len());
+        }
+        for topic in &restored {
+            self.add_subscriber(topic, addr, writer);
+        }
+        Some(restored)
+    }
+
+    fn add_subscriber(&self, topic: &str, addr: SocketAddr, writer: &SharedWriter) {
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .insert(addr, writer.clone());
+    }
+
+    // Drops every subscription of a closed connection, its session stays valid for Resubscribe
+    pub(crate) fn remove_connection(&self, addr: SocketAddr) {
+        let mut topics = self.topics.lock().unwrap();
+        for subscribers in topics.values_mut() {
+            subscribers.remove(&addr);
+        }
+        topics.retain(|_, subscribers| !subscribers.is_empty());
+    }
+
+    // Sends `content` to every subscriber of `topic`, returns how many received it
+    pub(crate) fn publish(&self, topic: &str, content: &str) -> u32 {
+        self.deliver(topic, || {
+            server_message::Message::Notification(Notification {
+                topic: topic.to_string(),
+                content: content.to_string(),
+            })
+        })
+    }
+
+    // Sends a message built by `message` to every subscriber of `topic`, returns how many received it
+    // The subscribers are copied and the registry unlocked first, so a subscriber whose queue is full (and blocks
+    // under BackpressurePolicy::Block) doesn't hold up subscribing, unsubscribing and publishing on other connections
+    pub(crate) fn deliver(&self, topic: &str, message: impl Fn() -> server_message::Message) -> u32 {
+        let subscribers: Vec<(SocketAddr, SharedWriter)> = match self.topics.lock().unwrap().get(topic) {
+            Some(subscribers) => subscribers.iter().map(|(addr, writer)| (*addr, writer.clone())).collect(),
+            None => return 0,
+        };
+        let mut delivered = 0;
+        for (addr, writer) in subscribers {
+            match writer.lock().unwrap().send(message()) {
+                Ok(()) => delivered += 1,
+                Err(e) => warn!("Failed to notify {} on topic {}: {}", addr, topic, e),
+            }
+        }
+        delivered
+    }
+}
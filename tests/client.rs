@@ -1,16 +1,39 @@
-
 //This code sets up a TCP client that can connect to a server, send and receive messages, and handle disconnections.
+#![allow(dead_code)]    // Shared by several test binaries, each one only uses part of the API
 
 //IMPORTS
-use embedded_recruitment_task::message::EchoMessage;      // embedded_recruitment_task Crate
+use embedded_recruitment_task::clock::{Clock, SystemClock};     // Time source for heartbeat scheduling and call deadlines
+use embedded_recruitment_task::server::STATUS_TOPIC;     // Topic of the server's status updates
+use embedded_recruitment_task::frame::{encode_client_message, read_frame, read_header, write_frame, HEADER_LEN};     // Length-prefixed framing shared with the server
+pub use embedded_recruitment_task::frame::EncodeError;     // A message send() couldn't encode because its body would exceed the client's frame size (see chunk_echoes)
+use embedded_recruitment_task::message::{client_message, server_message, AddRequest, AddResponse, Auth, Cancel, ClientMessage, EchoChunk, EchoMessage, Hello, Notification, Ping, Publish, Resubscribe, ServerMessage, StatusUpdate, StreamRequest, Subscribe};      // embedded_recruitment_task Crate
 use log::{error, info, warn};   // Imports logging macros error and info.
-use prost::Message;   //Imports the Message trait for encoding and decoding protocol buffer messages. 
+use prost::{bytes::BufMut, Message};   //Imports the Message trait for encoding and decoding protocol buffer messages, BufMut caps the encode buffer
+use socket2::SockRef;  //Socket buffer sizes, not exposed by std
 use std::{
-    io::{self, Read, Write},         //Imports I/O traits and types
-    net::{SocketAddr, TcpStream, ToSocketAddrs},    //Imports networking types and traits.
-    time::Duration,                //Imports the Duration type for handling timeouts
+    collections::{HashMap, HashSet, VecDeque},     //Notifications that arrived while waiting for a response, multiplexed calls by id, retryable server codes
+    fs::File,                             //send_file() and receive_to_file()
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},         //Imports I/O traits and types
+    path::Path,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},    //Imports networking types and traits.
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},   //Heartbeat stop flag and counter, correlation ids
+        mpsc::{self, RecvTimeoutError},                 //Multiplexed responses handed to the waiting caller
+        Arc, Mutex,                                     //Shared between the client and its heartbeat thread
+    },
+    thread::{self, JoinHandle},        //Heartbeat thread
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},         //Imports the Duration type for handling timeouts, wall-clock time for latency stamps
 };
 
+const DEFAULT_MAX_RETRIES: usize = 3;     // Attempts made by send_and_receive before giving up
+const HEARTBEAT_POLL: Duration = Duration::from_millis(10);     // How often the heartbeat thread checks whether it should stop
+const CHUNK_OVERHEAD: usize = 32;     // Upper bound on the encoded size of an EchoChunk without its content
+const FILE_CHUNK_SIZE: usize = 64 * 1024;     // File bytes per EchoChunk sent by send_file()
+const WAIT_POLL: Duration = Duration::from_millis(10);     // Delay between connect attempts in wait_for_server()
+
+// Inspects every received ServerMessage, an Err(reason) rejects it, see Client::response_validator
+pub type ResponseValidator = Arc<dyn Fn(&ServerMessage) -> Result<(), String> + Send + Sync>;
+
 // TCP/IP Client: Defines a struct to represent a TCP client.
 pub struct Client {
     ip: String,
@@ -19,26 +42,347 @@ pub struct Client {
     retries: usize,
     max_retries: usize,
     stream: Option<TcpStream>,
+    lazy_connect: bool,      // Connect on first use instead of requiring connect()
+    recv_buffer_size: Option<usize>,    // SO_RCVBUF applied on connect, None keeps the OS default
+    send_buffer_size: Option<usize>,    // SO_SNDBUF applied on connect, None keeps the OS default
+    linger: Option<Duration>,           // SO_LINGER applied on disconnect, None keeps the OS default
+    write_lock: Arc<Mutex<()>>,         // Held for every write so heartbeat frames never land inside another frame
+    heartbeat: Option<Heartbeat>,       // Running heartbeat thread, if enabled
+    session: Option<String>,            // Session token issued on the first subscribe
+    subscriptions: Vec<String>,         // Topics replayed by reconnect()
+    notifications: VecDeque<Notification>,   // Received while waiting for another response
+    status_updates: VecDeque<StatusUpdate>,  // Likewise, see subscribe_status()
+    peeked: Option<ServerMessage>,      // Read by peek_message(), returned by the next receive
+    validate_on_connect: bool,          // Require a Pong to a Ping before connect() succeeds
+    clock: Arc<dyn Clock>,              // Schedules heartbeats, times call_within()
+    max_frame_size: Option<usize>,      // Echoes that don't fit in one frame of this size are sent as EchoChunks
+    next_message_id: u64,               // Message id of the next chunked echo
+    fresh_connection: bool,             // The connection was opened and hasn't carried a request yet
+    last_connection_reused: bool,       // The last request went out on a connection that had carried one before
+    response_validator: Option<ResponseValidator>,   // Rejects received messages before they reach the caller
+    retry_backoff: Option<Duration>,    // Wait before the first retry of send_and_receive, doubled for each further one
+    retryable_server_codes: HashSet<ServerErrorCode>,   // Server rejections send_and_receive retries, the others are returned
+    jitter_fraction: f64,               // Backoffs vary randomly by up to this fraction either way
+    rng: u64,                           // SplitMix64 state for the jitter
+    phase_deadline: Option<Instant>,    // Set by call_within(), no read or write of the current phase may go past it
+    encode_buffer: Vec<u8>,             // Frame being sent, reused by every send so it only grows for a larger message
+    endpoints: Vec<(String, u32)>,      // Servers tried in turn by connect(), the one in ip/port first, empty without failover
+    endpoint: usize,                    // Index in endpoints of the server in ip/port
+    server_features: ServerFeatures,    // What the server announced in its HelloResponse on this connection
+    #[cfg(feature = "proxy")]
+    proxy: Option<SocketAddr>,          // SOCKS5 proxy connect() dials instead of the server
   }
 
+// Reads whatever is left on a non-blocking stream, it was closed by the server if that ends in EOF, a reset or a Goodbye
+fn closed_by_server(mut stream: &TcpStream) -> DisconnectOutcome {
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return DisconnectOutcome::AlreadyClosed,
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return DisconnectOutcome::AlreadyClosed,
+        }
+    }
+    let mut unread = &pending[..];
+    while let Ok(Some(frame)) = read_frame(&mut unread) {
+        if let Ok(ServerMessage { message: Some(server_message::Message::Goodbye(_)), .. }) = ServerMessage::decode(&frame[..]) {
+            return DisconnectOutcome::AlreadyClosed;
+        }
+    }
+    DisconnectOutcome::Clean
+}
+
+// Background thread writing zero-length frames on a cloned stream
+struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    sent: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
 //Implementation of Client
 impl Client {
-     // Creates a new client instance and connects to the server
-    pub fn new(ip: &str, port: u32, timeout_ms: u64, max_retries: usize) -> Self {   
+     // Creates a new client instance, connect() must be called before sending
+    pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
         Client {
             ip: ip.to_string(),   //Converts the IP address to a string.
             port,                 //Sets the port number.
             timeout: Duration::from_millis(timeout_ms),         //Converts the timeout from milliseconds to a Duration.
             retries: 0,
-            max_retries,
+            max_retries: DEFAULT_MAX_RETRIES,
             stream: None,                                  //Initializes the stream as None.
+            lazy_connect: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            linger: None,
+            write_lock: Arc::new(Mutex::new(())),
+            heartbeat: None,
+            session: None,
+            subscriptions: Vec::new(),
+            notifications: VecDeque::new(),
+            status_updates: VecDeque::new(),
+            peeked: None,
+            validate_on_connect: false,
+            clock: Arc::new(SystemClock),
+            max_frame_size: None,
+            next_message_id: 0,
+            fresh_connection: false,
+            last_connection_reused: false,
+            response_validator: None,
+            retry_backoff: None,
+            retryable_server_codes: HashSet::new(),
+            jitter_fraction: 0.0,
+            rng: unix_nanos(),
+            phase_deadline: None,
+            encode_buffer: Vec::new(),
+            endpoints: Vec::new(),
+            endpoint: 0,
+            server_features: ServerFeatures::default(),
+            #[cfg(feature = "proxy")]
+            proxy: None,
+        }
+    }
+
+    // Installs a check run on every message receive() returns, a rejected message becomes an InvalidData error
+    pub fn response_validator(mut self, validator: impl Fn(&ServerMessage) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        self.response_validator = Some(Arc::new(validator));
+        self
+    }
+
+    // Sets the socket buffer sizes applied on connect
+    pub fn socket_buffer_sizes(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.recv_buffer_size = recv;
+        self.send_buffer_size = send;
+        self
+    }
+
+    // Returns the (receive, send) buffer sizes the OS actually applied to the live stream
+    pub fn applied_buffer_sizes(&self) -> io::Result<(usize, usize)> {
+        match self.stream {
+            Some(ref stream) => {
+                let socket = SockRef::from(stream);
+                Ok((socket.recv_buffer_size()?, socket.send_buffer_size()?))
+            }
+            None => Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        }
+    }
+
+    // Sets SO_LINGER for disconnect, Some(Duration::ZERO) closes immediately with a reset instead of a FIN
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    // Local address of the live stream
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.stream {
+            Some(ref stream) => stream.local_addr(),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        }
+    }
+
+    // Toggles TCP_NODELAY on the live stream, true disables Nagle's algorithm for lower latency on small messages
+    pub fn set_nodelay(&mut self, on: bool) -> io::Result<()> {
+        match self.stream {
+            Some(ref stream) => stream.set_nodelay(on),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        }
+    }
+
+    // Makes connect() send a Ping and wait for the Pong, so a wrong or stale endpoint fails at connect time
+    pub fn validate_on_connect(mut self, enabled: bool) -> Self {
+        self.validate_on_connect = enabled;
+        self
+    }
+
+    // Makes connect() reach the server through the SOCKS5 proxy at `proxy`, which resolves the server's host name
+    #[cfg(feature = "proxy")]
+    pub fn socks5_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // Adds servers for connect() to fail over to: when the current one can't be reached the next is tried, round-robin,
+    // until one accepts or each has failed once. The server given to new() comes first
+    pub fn failover_endpoints(mut self, endpoints: &[(&str, u32)]) -> Self {
+        self.endpoints = vec![(self.ip.clone(), self.port)];
+        self.endpoints.extend(endpoints.iter().map(|(ip, port)| (ip.to_string(), *port)));
+        self.endpoint = 0;
+        self
+    }
+
+    // Returns the server the client is, or was last, connected to
+    pub fn endpoint(&self) -> (&str, u32) {
+        (&self.ip, self.port)
+    }
+
+    // Replaces the system clock used to schedule heartbeats and for the deadlines of call_within()
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Sets how many attempts send_and_receive makes before giving up, 3 by default
+    pub fn max_retries(mut self, attempts: usize) -> Self {
+        self.max_retries = attempts.max(1);
+        self
+    }
+
+    // Waits `base` before the first retry of send_and_receive, twice as long before each following one
+    pub fn retry_backoff(mut self, base: Duration) -> Self {
+        self.retry_backoff = Some(base);
+        self
+    }
+
+    // Makes send_and_receive retry a request the server answered with one of `codes`, like a lost connection
+    // Other rejections are permanent and returned at once, once attempts run out the last rejection is returned
+    pub fn with_retry_on(mut self, codes: &[ServerErrorCode]) -> Self {
+        self.retryable_server_codes = codes.iter().copied().collect();
+        self
+    }
+
+    // Spreads each backoff uniformly over ±`fraction` of it (0.2 for ±20%), so clients that lost the same server
+    // don't all come back at once. NaN means no jitter
+    pub fn jitter_fraction(mut self, fraction: f64) -> Self {
+        self.jitter_fraction = if fraction.is_nan() { 0.0 } else { fraction.clamp(0.0, 1.0) };
+        self
+    }
+
+    // Seeds the jitter, for reproducible backoffs, the default seed is the current time
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.rng = seed;
+        self
+    }
+
+    // Returns the wait before retry number `attempt` (1 for the first retry), jittered, zero without a retry backoff
+    pub fn backoff(&mut self, attempt: usize) -> Duration {
+        let Some(base) = self.retry_backoff else {
+            return Duration::ZERO;
+        };
+        let exponential = base.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let unit = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;     // Uniform in [0, 1)
+        let factor = 1.0 + self.jitter_fraction * (2.0 * unit - 1.0);
+        Duration::try_from_secs_f64(exponential.as_secs_f64() * factor).unwrap_or(Duration::MAX)    // A huge base saturates
+    }
+
+    // SplitMix64, good enough for jitter and needs no dependency
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Enables lazy mode: send/receive connect on demand when there is no active connection
+    pub fn lazy_connect(mut self, enabled: bool) -> Self {
+        self.lazy_connect = enabled;
+        self
+    }
+
+    // Connects if lazy mode is on and there is no stream yet, explicit-connect clients are left untouched
+    fn ensure_connected(&mut self) -> io::Result<()> {
+        if self.lazy_connect && self.stream.is_none() {
+            self.connect()?;
         }
+        Ok(())
     }
 
     //Connect Method: connect the client to the server
     pub fn connect(&mut self) -> io::Result<()> {
+        self.connect_within(self.timeout)
+    }
+
+    // Connects with `timeout` for the dial and the connection probe, later reads and writes use the client timeout
+    fn connect_within(&mut self, timeout: Duration) -> io::Result<()> {
         info!("Connecting to {}:{}", self.ip, self.port);
 
+        // Connect to the server with a timeout
+        let stream = self.dial_with_failover(timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let socket = SockRef::from(&stream);
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        self.stream = Some(stream);       //Stores the connected TcpStream.
+        self.server_features = ServerFeatures::default();      // Announced again by the next hello()
+
+        if self.validate_on_connect {
+            if let Err(e) = self.probe() {
+                warn!("Connection probe failed: {}", e);
+                self.stream = None;
+                return Err(e);
+            }
+        }
+
+        self.fresh_connection = true;
+        if let Some(ref stream) = self.stream {
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+        }
+        info!("Connected to the server!");
+        Ok(())
+    }
+
+    // Blocks until a server accepts connections on `addr`, retrying refused connects every WAIT_POLL
+    // Fails with TimedOut, carrying the last connect error, once `timeout` has elapsed
+    pub fn wait_for_server(addr: SocketAddr, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let error = match TcpStream::connect_timeout(&addr, remaining.max(Duration::from_millis(1))) {
+                Ok(_) => {
+                    info!("Server at {} is accepting connections", addr);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Server at {} not up after {:?}: {}", addr, timeout, error),
+                ));
+            }
+            thread::sleep(WAIT_POLL.min(remaining));
+        }
+    }
+
+    // Dials the current endpoint, then each failover endpoint in turn, the one that answers becomes the current one
+    // Each attempt gets the whole `timeout`, the error of the last one is returned if none answers
+    fn dial_with_failover(&mut self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut attempts = self.endpoints.len().max(1);
+        loop {
+            match self.dial(timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if attempts > 1 => {
+                    warn!("Failed to connect to {}:{}, trying the next endpoint: {}", self.ip, self.port, e);
+                    self.endpoint = (self.endpoint + 1) % self.endpoints.len();
+                    (self.ip, self.port) = self.endpoints[self.endpoint].clone();
+                    attempts -= 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Opens the TCP connection to the server, through the SOCKS5 proxy if one is set
+    fn dial(&self, timeout: Duration) -> io::Result<TcpStream> {
+        #[cfg(feature = "proxy")]
+        if let Some(proxy) = self.proxy {
+            return self.dial_socks5(proxy, timeout);
+        }
+
+        // A literal IP (IPv6 ones can't be joined to the port with a plain ':') is used as is
+        if let Ok(ip) = self.ip.parse::<IpAddr>() {
+            return TcpStream::connect_timeout(&SocketAddr::new(ip, self.port as u16), timeout);
+        }
         // Resolve the address
         let address = format!("{}:{}", self.ip, self.port);        // Formats the IP and port into a single string
         let socket_addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();   //Resolves the address to a list of SocketAddr instances
@@ -49,46 +393,243 @@ impl Client {
                 "Invalid IP or port",
             ));
         }
+        TcpStream::connect_timeout(&socket_addrs[0], timeout)
+    }
 
-        // Connect to the server with a timeout
-        let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;      
-        stream.set_read_timeout(Some(self.timeout))?;
-        stream.set_write_timeout(Some(self.timeout))?;
-        self.stream = Some(stream);       //Stores the connected TcpStream.
+    // Connects to the proxy and asks it to CONNECT to the server (RFC 1928, no authentication)
+    #[cfg(feature = "proxy")]
+    fn dial_socks5(&self, proxy: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
 
-        info!("Connected to the server!");
+        // Greeting: version 5, one method offered, "no authentication"
+        stream.write_all(&[0x05, 0x01, 0x00])?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice)?;
+        if choice != [0x05, 0x00] {
+            return Err(io::Error::new(ErrorKind::ConnectionRefused, "SOCKS5 proxy requires authentication"));
+        }
+
+        // CONNECT with the server's host name (address type 3), the proxy resolves it
+        let invalid = |what: &str| io::Error::new(ErrorKind::InvalidInput, format!("{} too long for SOCKS5", what));
+        let port = u16::try_from(self.port).map_err(|_| invalid("Port"))?;
+        let host_len = u8::try_from(self.ip.len()).map_err(|_| invalid("Host name"))?;
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, host_len];
+        request.extend_from_slice(self.ip.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        // Reply: version, status, reserved, then the bound address whose length depends on its type
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0x00 {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("SOCKS5 CONNECT failed with status {}", reply[1]),
+            ));
+        }
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            other => return Err(io::Error::new(ErrorKind::InvalidData, format!("Unknown SOCKS5 address type {}", other))),
+        };
+        let mut bound = vec![0u8; addr_len + 2];        // Address and port, unused
+        stream.read_exact(&mut bound)?;
+        info!("Connected through SOCKS5 proxy {}", proxy);
+        Ok(stream)
+    }
+
+    // Returns true if the last request was sent on a connection an earlier request already used,
+    // false if it opened the connection (lazily or right after connect()), for pool metrics
+    pub fn last_connection_reused(&self) -> bool {
+        self.last_connection_reused
+    }
+
+    // Sends a zero-length keepalive frame every `interval` on a background thread until disconnect or drop
+    // The server treats empty frames as a no-op, so they keep NAT mappings alive without producing responses
+    pub fn enable_heartbeat(&mut self, interval: Duration) -> io::Result<()> {
+        self.ensure_connected()?;
+        self.stop_heartbeat();
+        let mut stream = match self.stream {
+            Some(ref stream) => stream.try_clone()?,
+            None => return Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let write_lock = Arc::clone(&self.write_lock);
+        let clock = Arc::clone(&self.clock);
+        let thread = {
+            let stop = Arc::clone(&stop);
+            let sent = Arc::clone(&sent);
+            thread::spawn(move || {
+                let mut next = clock.now() + interval;
+                while !stop.load(Ordering::SeqCst) {
+                    let now = clock.now();
+                    if now < next {
+                        clock.sleep(HEARTBEAT_POLL.min(next - now));
+                        continue;
+                    }
+                    let _guard = write_lock.lock().unwrap();
+                    if let Err(e) = write_frame(&mut stream, &[]) {
+                        warn!("Heartbeat failed, stopping: {}", e);
+                        break;
+                    }
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    next += interval;
+                }
+            })
+        };
+        self.heartbeat = Some(Heartbeat { stop, sent, thread });
         Ok(())
     }
 
+    // Number of heartbeat frames sent by the current heartbeat thread
+    pub fn heartbeats_sent(&self) -> usize {
+        self.heartbeat
+            .as_ref()
+            .map_or(0, |heartbeat| heartbeat.sent.load(Ordering::SeqCst))
+    }
+
+    // Stops the heartbeat thread, if any, and waits for it to exit
+    fn stop_heartbeat(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop.store(true, Ordering::SeqCst);
+            let _ = heartbeat.thread.join();
+        }
+    }
+
+    // Sends a Ping and requires a Pong within the client timeout
+    fn probe(&mut self) -> io::Result<()> {
+        self.send(client_message::Message::Ping(Ping {}))?;
+        match self.receive()?.message {
+            Some(server_message::Message::Pong(_)) => Ok(()),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to the connection probe: {:?}", other),
+            )),
+        }
+    }
+
     //Disconnect Method: disconnect the client
-    pub fn disconnect(&mut self) -> io::Result<()> {
-        if let Some(stream) = self.stream.take() {     //Takes ownership of the stream, setting it to None.
-            stream.shutdown(std::net::Shutdown::Both)?;    //huts down the connection.
+    // Reports whether the connection was still open (Clean) or the server had already closed or reset it (AlreadyClosed)
+    pub fn disconnect(&mut self) -> io::Result<DisconnectOutcome> {
+        self.stop_heartbeat();
+        self.peeked = None;
+        let Some(stream) = self.stream.take() else {     //Takes ownership of the stream, setting it to None.
+            return Ok(DisconnectOutcome::AlreadyClosed);     // Nothing to close
+        };
+        // A pending EOF, reset or Goodbye means the server closed first, unread responses alone don't
+        stream.set_nonblocking(true)?;
+        let outcome = closed_by_server(&stream);
+        if let Some(linger) = self.linger {
+            SockRef::from(&stream).set_linger(Some(linger))?;
+        }
+        // A zero linger aborts on close, shutting down first would start a graceful FIN exchange instead
+        if self.linger != Some(Duration::ZERO) {
+            match stream.shutdown(std::net::Shutdown::Both) {    //Shuts down the connection.
+                Ok(()) => {}
+                Err(e) if outcome == DisconnectOutcome::AlreadyClosed => info!("Shutdown after the server closed: {}", e),
+                Err(e) => return Err(e),
+            }
         }
 
-        info!("Disconnected from the server!");    //Returns an error if the shutdown fails.
-        Ok(())
+        info!("Disconnected from the server ({:?})", outcome);    //Returns an error if the shutdown fails.
+        Ok(outcome)
+    }
+
+    // Turns the connection into a Multiplexer that many threads can send requests over at once
+    pub fn multiplexer(self) -> Result<Multiplexer, SplitError> {
+        let timeout = self.timeout;
+        let (sender, receiver) = self.split()?;
+        Ok(Multiplexer::new(sender.stream, receiver.stream, timeout))
+    }
+
+    // Splits the connection into a sending and a receiving half that can be used from different threads
+    // Notifications and status updates the client set aside, and a peeked message, are the receiver's first messages
+    // Fails if the client isn't connected or the socket can't be cloned, the SplitError then hands the client back intact
+    pub fn split(mut self) -> Result<(ClientSender, ClientReceiver), SplitError> {
+        let cloned = match &self.stream {
+            Some(stream) => stream.try_clone(),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        };
+        let reader = match cloned {
+            Ok(reader) => reader,
+            Err(error) => return Err(SplitError { client: Box::new(self), error }),
+        };
+        self.stop_heartbeat();
+        let writer = self.stream.take().expect("Stream was cloned above");
+        let sender = ClientSender { stream: writer, next_message_id: self.next_message_id };
+        let notifications = self.notifications.drain(..).map(server_message::Message::Notification);
+        let status_updates = self.status_updates.drain(..).map(server_message::Message::StatusUpdate);
+        let mut pending: VecDeque<ServerMessage> =
+            notifications.chain(status_updates).map(|message| ServerMessage { message: Some(message), ..Default::default() }).collect();
+        pending.extend(self.peeked.take());      // Read after the ones set aside
+        let receiver = ClientReceiver { stream: reader, pending };
+        Ok((sender, receiver))
+    }
+
+    //Send Method: wraps the message in a ClientMessage and sends it as one frame
+    pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+        self.send_envelope(ClientMessage {
+            message: Some(message),
+            ..Default::default()
+        })
+    }
+
+    // Sends a one-way message and returns without waiting for a response, for servers that don't answer it (a handler
+    // returning Ignore). The whole frame is in the socket's send buffer, flushed, when this returns
+    pub fn send_and_forget(&mut self, message: client_message::Message) -> io::Result<()> {
+        self.send(message)?;
+        match self.stream {
+            Some(ref mut stream) => stream.flush(),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "No active connection")),
+        }
     }
 
-    // generic message to send message to the server
-    //Send Method
-    pub fn send(&mut self,  content: &str) -> io::Result<()> {
+    // Sends a whole ClientMessage as one frame, for envelope fields send() leaves unset
+    pub fn send_envelope(&mut self, message: ClientMessage) -> io::Result<()> {
+        self.ensure_connected()?;
         if let Some(ref mut stream) = self.stream {
-            
-            // Construct and encode the EchoMessage
-            let message = EchoMessage {
-                content: content.to_string(),
-            };
-        
-            // Encode the message to a buffer
-            let mut buffer = Vec::new();
-            message.encode(&mut buffer);      // Encodes the message into a buffer
+            let _guard = self.write_lock.lock().unwrap();      // Also covers the peek, which toggles non-blocking mode on the shared socket
+            // A write to a connection the server already closed still succeeds locally, so check for EOF first
+            stream.set_nonblocking(true)?;
+            let peeked = stream.peek(&mut [0u8; 1]);
+            stream.set_nonblocking(false)?;
+            if let Ok(0) = peeked {
+                warn!("Server closed the connection.");
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Server closed the connection",
+                ));
+            }
 
-            // Send the buffer to the server
-            stream.write_all(&buffer)?;     //Writes the buffer to the stream
-            stream.flush()?;      //Ensures all data is sent.
+            // Encode the ClientMessage after room for its length prefix, a frame body larger than the frame size the
+            // client was given (or than a length prefix can hold) is an EncodeError
+            let limit = self.max_frame_size.unwrap_or(u32::MAX as usize);
+            self.encode_buffer.clear();
+            self.encode_buffer.extend_from_slice(&[0; HEADER_LEN]);
+            message.encode(&mut (&mut self.encode_buffer).limit(limit)).map_err(|e| {
+                error!("Failed to encode message: {}", e);
+                io::Error::new(ErrorKind::InvalidInput, EncodeError { required: e.required_capacity(), limit })
+            })?;
+            let len = (self.encode_buffer.len() - HEADER_LEN) as u32;
+            self.encode_buffer[..HEADER_LEN].copy_from_slice(&len.to_be_bytes());
 
-            info!("Sent message: {:?}", message);     
+            // Send the frame to the server, in one write so the header and body are not split into separate segments
+            match self.phase_deadline {
+                Some(deadline) => DeadlineStream { stream, deadline, clock: &*self.clock }.write_all(&self.encode_buffer)?,
+                None => stream.write_all(&self.encode_buffer)?,
+            }
+            // Only a request that went out counts, a failed send leaves the connection as fresh as it was
+            self.last_connection_reused = !std::mem::replace(&mut self.fresh_connection, false);
+
+            info!("Sent message: {:?}", message);
             Ok(())
         } else {
             Err(io::Error::new(
@@ -97,67 +638,1114 @@ impl Client {
             ))
         }
     }
-    
-    //Receive Method:Receives a message from the server
-    pub fn receive(&mut self) -> io::Result<EchoMessage> {
+
+    //Send Raw Method: writes caller-supplied bytes without encoding, for crafting malformed input
+    // When `add_length_prefix` is set the bytes are sent as one frame body, otherwise they go on the wire as-is
+    pub fn send_raw(&mut self, frame: &[u8], add_length_prefix: bool) -> io::Result<()> {
+        self.ensure_connected()?;
         if let Some(ref mut stream) = self.stream {
-            info!("Receiving message from the server...");
-            let mut buffer = vec![0u8; 512];
-            let bytes_read = stream.read(&mut buffer)?;          //eads data from the stream into a buffer.
-            if bytes_read == 0 {          //Checks if the server has disconnected.
-                warn!("Server disconnected.");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Server disconnected",
-                ));
+            let _guard = self.write_lock.lock().unwrap();
+            if add_length_prefix {
+                write_frame(stream, frame)?;
+            } else {
+                stream.write_all(frame)?;
             }
-
-            info!("Received {} bytes from the server", bytes_read);
-
-            // Decode the received message
-            EchoMessage::decode(&buffer[..bytes_read]).map_err(|e| {
-                error!("Failed to decode message: {}", e);
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to decode ServerMessage: {}", e),        //Returns an error if there is no active connection, if reading fails, or if decoding fails.
-                )
-            })
+            info!("Sent {} raw bytes", frame.len());
+            Ok(())
         } else {
-            error!("No active connection");
             Err(io::Error::new(
                 io::ErrorKind::NotConnected,
                 "No active connection",
             ))
         }
+    }
+
+    //Receive Method:Receives a message from the server
+    // Reads exactly one frame, in as many reads as its length prefix requires, a frame cut short fails with UnexpectedEof
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        if let Some(message) = self.peeked.take() {
+            return Ok(message);
+        }
+        info!("Receiving message from the server...");
+        let mut frame = Vec::new();
+        self.receive_frame(&mut frame)?;
+        info!("Received {} bytes from the server", frame.len());
+        self.check_received(&frame)
+    }
+
+    // Reads the next frame body into `buf`, under the phase deadline of call_within() if one is set
+    // The buffer grows with the bytes actually received rather than the declared length, like read_body()
+    fn receive_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.ensure_connected()?;
+        let Some(ref mut stream) = self.stream else {
+            error!("No active connection");
+            return Err(io::Error::new(ErrorKind::NotConnected, "No active connection"));
+        };
+        let read = match self.phase_deadline {
+            Some(deadline) => read_frame_into(&mut DeadlineStream { stream, deadline, clock: &*self.clock }, buf),
+            None => read_frame_into(stream, buf),
+        };
+        if !read? {          //The server has disconnected.
+            warn!("Server disconnected.");
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "Server disconnected"));
+        }
+        Ok(())
+    }
+
+    // Decodes a received frame body, fails with ServerAtCapacity for an AtCapacity rejection and with ResponseRejected
+    // for a message the response validator refuses
+    fn check_received(&self, frame: &[u8]) -> io::Result<ServerMessage> {
+        let message = ServerMessage::decode(frame).map_err(|e| {
+            error!("Failed to decode message: {}", e);
+            io::Error::new(ErrorKind::InvalidData, format!("Failed to decode ServerMessage: {}", e))
+        })?;
+        if let Some(server_message::Message::AtCapacity(rejection)) = &message.message {
+            warn!("Server refused the connection: at capacity.");
+            return Err(io::Error::new(ErrorKind::ConnectionRefused, ServerAtCapacity { max_clients: rejection.max_clients }));
+        }
+        if let Some(validator) = &self.response_validator {
+            validator(&message).map_err(|reason| {
+                warn!("Rejected a message from the server: {}", reason);
+                io::Error::new(ErrorKind::InvalidData, ResponseRejected { reason })
+            })?;
+        }
+        Ok(message)
+    }
+
+    // Capacity of the buffer send() encodes into, it stays put while messages are no larger than earlier ones
+    pub fn encode_buffer_capacity(&self) -> usize {
+        self.encode_buffer.capacity()
+    }
+
+    // Reads the next frame body into `buf` and returns its length, handing the caller the undecoded bytes
+    // The buffer is reused across calls, so it only reallocates when a frame is larger than any before it. The frame is
+    // read and checked like receive() does
+    pub fn receive_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        buf.clear();
+        if let Some(message) = self.peeked.take() {
+            message.encode(buf).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            return Ok(buf.len());
+        }
+        self.receive_frame(buf)?;
+        self.check_received(buf)?;
+        Ok(buf.len())
+    }
+
+    // Reads the next message without consuming it, the following receive()/receive_message() returns it
+    pub fn peek_message(&mut self) -> io::Result<&server_message::Message> {
+        if self.peeked.is_none() {
+            let message = self.receive()?;
+            self.peeked = Some(message);
+        }
+        match self.peeked.as_ref().and_then(|peeked| peeked.message.as_ref()) {
+            Some(message) => Ok(message),
+            None => Err(io::Error::new(ErrorKind::InvalidData, "ServerMessage without content")),
+        }
+    }
 
+    // Receives the next message and unwraps its content
+    pub fn receive_message(&mut self) -> io::Result<server_message::Message> {
+        self.receive()?
+            .message
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "ServerMessage without content"))
+    }
+
+    // Receives the next `count` messages, for requests the server answers more than once
+    pub fn receive_all(&mut self, count: usize) -> io::Result<Vec<ServerMessage>> {
+        (0..count).map(|_| self.receive()).collect()
+    }
 
-        
     // Send and receive with retries : Combines sending and receiving into a robust operation with retries.
-    pub fn send_and_receive(&mut self, content: &str) -> io::Result<EchoMessage> {
+    pub fn send_and_receive(&mut self, message: client_message::Message) -> io::Result<ServerMessage> {
         while self.retries < self.max_retries {
-            match self.send(content).and_then(|_| self.receive()) {
+            match self.send(message.clone()).and_then(|_| self.receive()) {
                 Ok(response) => {
+                    if let Some(code) = ServerErrorCode::of(&response).filter(|code| self.retryable_server_codes.contains(code)) {
+                        self.retries += 1;
+                        if self.retries < self.max_retries {
+                            warn!("Attempt {} answered with {:?}. Retrying...", self.retries, code);
+                            thread::sleep(self.backoff(self.retries));
+                            continue;
+                        }
+                        error!("Max retries reached. Giving up.");
+                    }
                     self.retries = 0; // Reset retries on success
                     return Ok(response);
                 }
+                // Not transient failures: the server stays full, the message stays too large, and a rejected response
+                // means the server already handled the request, sending it again would repeat it
+                Err(e) if ServerAtCapacity::find(&e).is_some() || EncodeError::find(&e).is_some() || ResponseRejected::find(&e).is_some() => {
+                    self.retries = 0;
+                    return Err(e);
+                }
                 Err(e) => {
                     self.retries += 1;
                     warn!(
                         "Attempt {} failed: {}. Retrying...",
                         self.retries, e
                     );
+                    // A lazy client opens a new connection for the next attempt instead of reusing the lost one
+                    if self.lazy_connect && matches!(e.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof) {
+                        self.stop_heartbeat();
+                        self.stream = None;
+                    }
 
                     if self.retries >= self.max_retries {
                         error!("Max retries reached. Giving up.");
+                        self.retries = 0;
                         return Err(e);
                     }
+                    thread::sleep(self.backoff(self.retries));
+                }
+            }
+        }
+
+        Err(io::Error::other("Unhandled error in send_and_receive"))
+    }
+
+    // Sends `message` and receives its response, connecting first if there is no connection, all within `budget`
+    // Each phase gets an equal share of the time left when it starts, so time a fast phase saves goes to the later ones.
+    // Fails with TimedOut once a phase runs out, without retrying, and drops the connection so a late response can't
+    // be taken for the answer to the next request
+    pub fn call_within(&mut self, message: client_message::Message, budget: Duration) -> io::Result<ServerMessage> {
+        let deadline = self.clock.now() + budget;
+        let result = self.call_before(message, deadline);
+        self.phase_deadline = None;
+        match result {
+            Ok(response) => {
+                if let Some(ref stream) = self.stream {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                }
+                Ok(response)
+            }
+            Err(e) => {
+                self.stop_heartbeat();
+                self.stream = None;
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                    warn!("Call ran out of its {:?} budget: {}", budget, e);
+                    return Err(io::Error::new(ErrorKind::TimedOut, format!("Call exceeded its {:?} budget", budget)));
                 }
+                Err(e)
+            }
+        }
+    }
+
+    fn call_before(&mut self, message: client_message::Message, deadline: Instant) -> io::Result<ServerMessage> {
+        if self.stream.is_none() {
+            let share = phase_timeout(deadline, 3, &*self.clock)?;
+            self.phase_deadline = Some(self.clock.now() + share);      // Bounds the connection probe
+            self.connect_within(share)?;
+        }
+        self.phase_deadline = Some(self.clock.now() + phase_timeout(deadline, 2, &*self.clock)?);
+        self.send(message)?;
+        self.phase_deadline = Some(self.clock.now() + phase_timeout(deadline, 1, &*self.clock)?);
+        self.receive()
+    }
+
+    // Makes echo() split content that doesn't fit in a frame of `max_message_size` bytes into EchoChunks,
+    // which the server reassembles, so echoes aren't bounded by the server's frame limit
+    pub fn chunk_echoes(mut self, max_message_size: usize) -> Self {
+        assert!(max_message_size > CHUNK_OVERHEAD, "Frame size too small for chunking");
+        self.max_frame_size = Some(max_message_size);
+        self
+    }
+
+    // Echoes `content` through the server and returns the echoed text
+    pub fn echo(&mut self, content: &str) -> io::Result<String> {
+        if let Some(max) = self.max_frame_size {
+            let whole = ClientMessage {
+                message: Some(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })),
+                ..Default::default()
+            };
+            if whole.encoded_len() > max {
+                return self.echo_chunked(content, max - CHUNK_OVERHEAD);
+            }
+        }
+        let response = self.send_and_receive(client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        }))?;
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => Ok(echo.content),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to EchoMessage: {:?}", other),
+            )),
+        }
+    }
+
+    // Sends `content` as EchoChunks of at most `chunk_size` bytes and returns the reassembled echo
+    fn echo_chunked(&mut self, content: &str, chunk_size: usize) -> io::Result<String> {
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        let mut rest = content;
+        let mut index = 0;
+        loop {
+            // Split on a char boundary, a chunk always makes progress since chunk_size exceeds a char
+            let mut end = chunk_size.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (piece, remaining) = rest.split_at(end);
+            self.send(client_message::Message::EchoChunk(EchoChunk {
+                message_id,
+                index,
+                content: piece.to_string(),
+                r#final: remaining.is_empty(),
+                ..Default::default()
+            }))?;
+            if remaining.is_empty() {
+                break;
+            }
+            rest = remaining;
+            index += 1;
+        }
+        match self.receive_reply()?.message {
+            Some(server_message::Message::EchoMessage(echo)) => Ok(echo.content),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to a chunked echo: {:?}", other),
+            )),
+        }
+    }
+
+    // Echoes `content` stamped with the current wall-clock time and returns the echoed text with its end-to-end latency,
+    // computed from the timestamp the server copied onto the response
+    pub fn timed_echo(&mut self, content: &str) -> io::Result<(String, Duration)> {
+        self.send_envelope(ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })),
+            sent_at_unix_nanos: unix_nanos(),
+            ..Default::default()
+        })?;
+        let response = self.receive_reply()?;
+        let latency = Duration::from_nanos(unix_nanos().saturating_sub(response.sent_at_unix_nanos));
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) if response.sent_at_unix_nanos != 0 => Ok((echo.content, latency)),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected or unstamped response to a timed EchoMessage: {:?}", other),
+            )),
+        }
+    }
+
+    // Streams the file at `path` to the server as a binary echo of FILE_CHUNK_SIZE pieces, without reading it whole
+    // The server echoes each piece right away, collect them with receive_to_file(). Echoed pieces wait in the server's
+    // response queue meanwhile, so a file above max_pending_responses pieces needs a split client: send_file() on the
+    // ClientSender while another thread runs receive_to_file() on the ClientReceiver
+    pub fn send_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        send_file_pieces(path.as_ref(), message_id, |message| self.send(message))
+    }
+
+    // Writes the pieces of a streamed binary echo to `path` as they arrive, returns the number of bytes written
+    pub fn receive_to_file(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        receive_file_pieces(path.as_ref(), || self.receive_reply())
+    }
+
+    // Asks the server to add `a` and `b` as int64, a sum outside int64 fails with an AddOverflow error instead of
+    // returning the saturated result, see add() for an exact sum of any size
+    pub fn checked_add(&mut self, a: i64, b: i64) -> io::Result<i64> {
+        let response = self.send_and_receive(client_message::Message::AddRequest(AddRequest { a, b, allow_big_result: false }))?;
+        match response.message {
+            Some(server_message::Message::AddResponse(add)) if add.overflow => {
+                Err(io::Error::new(ErrorKind::InvalidData, AddOverflow { a, b }))
+            }
+            Some(server_message::Message::AddResponse(add)) => Ok(add.result),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to AddRequest: {:?}", other),
+            )),
+        }
+    }
+
+    // Asks the server to add `a` and `b` and returns the result
+    pub fn add(&mut self, a: i64, b: i64) -> io::Result<i128> {
+        let response = self.send_and_receive(client_message::Message::AddRequest(AddRequest { a, b, allow_big_result: true }))?;
+        match response.message {
+            // A sum outside int64 comes back as a decimal string
+            Some(server_message::Message::AddResponse(add)) if !add.big_result.is_empty() => add.big_result.parse().map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData, format!("Invalid big_result {:?}: {}", add.big_result, e))
+            }),
+            Some(server_message::Message::AddResponse(add)) => Ok(add.result as i128),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to AddRequest: {:?}", other),
+            )),
+        }
+    }
+
+    // Authenticates the connection, fails with PermissionDenied if the server rejects the token
+    pub fn authenticate(&mut self, token: &str) -> io::Result<()> {
+        self.send(client_message::Message::Auth(Auth {
+            token: token.to_string(),
+        }))?;
+        match self.receive()?.message {
+            Some(server_message::Message::AuthResponse(response)) if response.authenticated => Ok(()),
+            Some(server_message::Message::Unauthorized(rejection)) => {
+                Err(io::Error::new(ErrorKind::PermissionDenied, rejection.reason))
             }
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to Auth: {:?}", other),
+            )),
         }
+    }
 
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unhandled error in send_and_receive",
-        ))
-    }    }    
+    // Offers protocol `version` and returns the version the server negotiated, see server_features() for the rest
+    pub fn hello(&mut self, version: u32) -> io::Result<u32> {
+        self.send(client_message::Message::Hello(Hello { version }))?;
+        match self.receive_reply()?.message {
+            Some(server_message::Message::HelloResponse(response)) => {
+                self.server_features = ServerFeatures {
+                    version: response.version,
+                    auth_required: response.auth_required,
+                    subscriptions: response.subscriptions,
+                    streaming: response.streaming,
+                };
+                Ok(response.version)
+            }
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to Hello: {:?}", other),
+            )),
+        }
+    }
+
+    // What the server supports on this connection, as announced by the last hello(), all false before one
+    pub fn server_features(&self) -> ServerFeatures {
+        self.server_features
+    }
+
+    // Asks for `count` StreamItems `interval` apart, read them with receive() until the StreamEnd
+    pub fn start_stream(&mut self, request_id: u64, content: &str, count: u32, interval: Duration) -> io::Result<()> {
+        self.send(client_message::Message::StreamRequest(StreamRequest {
+            request_id,
+            content: content.to_string(),
+            count,
+            interval_ms: interval.as_millis() as u32,
+        }))
+    }
+
+    // Cancels the stream started with `request_id`, the server ends it with a cancelled StreamEnd
+    pub fn cancel(&mut self, request_id: u64) -> io::Result<()> {
+        self.send(client_message::Message::Cancel(Cancel { request_id }))
+    }
+
+    // Subscribes to `topic`, the subscription is replayed by reconnect()
+    pub fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        self.send(client_message::Message::Subscribe(Subscribe {
+            topic: topic.to_string(),
+        }))?;
+        match self.receive_reply()?.message {
+            Some(server_message::Message::Subscribed(subscribed)) => {
+                self.session = Some(subscribed.session);
+                if !self.subscriptions.iter().any(|subscribed| subscribed == topic) {
+                    self.subscriptions.push(topic.to_string());
+                }
+                Ok(())
+            }
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to Subscribe: {:?}", other),
+            )),
+        }
+    }
+
+    // Publishes `content` on `topic`, returns how many subscribers received it
+    pub fn publish(&mut self, topic: &str, content: &str) -> io::Result<u32> {
+        self.send(client_message::Message::Publish(Publish {
+            topic: topic.to_string(),
+            content: content.to_string(),
+        }))?;
+        match self.receive_reply()?.message {
+            Some(server_message::Message::Published(published)) => Ok(published.delivered),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to Publish: {:?}", other),
+            )),
+        }
+    }
+
+    // Returns the next notification on any subscribed topic, waiting up to the client timeout
+    pub fn next_notification(&mut self) -> io::Result<Notification> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(notification);
+        }
+        loop {
+            match self.receive()?.message {
+                Some(server_message::Message::Notification(notification)) => return Ok(notification),
+                Some(server_message::Message::StatusUpdate(status)) => self.status_updates.push_back(status),
+                other => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unexpected message while waiting for a notification: {:?}", other),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Subscribes to the server's periodic StatusUpdates, see ServerBuilder::status_updates
+    pub fn subscribe_status(&mut self) -> io::Result<()> {
+        self.subscribe(STATUS_TOPIC)
+    }
+
+    // Returns the next StatusUpdate, waiting for one if none arrived yet
+    pub fn next_status_update(&mut self) -> io::Result<StatusUpdate> {
+        if let Some(status) = self.status_updates.pop_front() {
+            return Ok(status);
+        }
+        loop {
+            match self.receive()?.message {
+                Some(server_message::Message::StatusUpdate(status)) => return Ok(status),
+                Some(server_message::Message::Notification(notification)) => self.notifications.push_back(notification),
+                other => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unexpected message while waiting for a status update: {:?}", other),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Sends `samples` pings one after another on the current connection and aggregates their round-trip times
+    // Each Pong is read before the next Ping goes out, so none are left in the buffer
+    pub fn measure_rtt(&mut self, samples: usize) -> io::Result<RttStats> {
+        if samples == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "At least one sample is required"));
+        }
+        let mut rtts = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let started = Instant::now();
+            self.send(client_message::Message::Ping(Ping {}))?;
+            match self.receive_reply()?.message {
+                Some(server_message::Message::Pong(_)) => rtts.push(started.elapsed()),
+                other => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unexpected response to Ping: {:?}", other),
+                    ))
+                }
+            }
+        }
+        Ok(RttStats {
+            min: *rtts.iter().min().unwrap(),
+            max: *rtts.iter().max().unwrap(),
+            mean: rtts.iter().sum::<Duration>() / samples as u32,
+        })
+    }
+
+    // Receives the response to the last request, setting aside notifications and status updates that arrive first
+    fn receive_reply(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            let response = self.receive()?;
+            match response.message {
+                Some(server_message::Message::Notification(notification)) => self.notifications.push_back(notification),
+                Some(server_message::Message::StatusUpdate(status)) => self.status_updates.push_back(status),
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    // Opens a new connection and resubscribes to every topic subscribed so far under the same session
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let _ = self.disconnect();      // The old connection may already be gone
+        self.connect()?;
+        let session = match self.session {
+            Some(ref session) if !self.subscriptions.is_empty() => session.clone(),
+            _ => return Ok(()),
+        };
+        self.send(client_message::Message::Resubscribe(Resubscribe {
+            session,
+            topics: self.subscriptions.clone(),
+        }))?;
+        match self.receive_reply()?.message {
+            Some(server_message::Message::Subscribed(_)) => Ok(()),
+            Some(server_message::Message::Unauthorized(rejection)) => {
+                Err(io::Error::new(ErrorKind::PermissionDenied, rejection.reason))
+            }
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected response to Resubscribe: {:?}", other),
+            )),
+        }
+    }
+
+    // Drops the connection and everything buffered from it, then connects again, the recovery from a desync
+    // Unlike reconnect() nothing is resubscribed, the new connection starts as fresh as the first one
+    pub fn close_and_reconnect(&mut self) -> io::Result<()> {
+        let _ = self.disconnect();      // Clears the peeked message, the old connection may already be gone
+        self.notifications.clear();
+        self.status_updates.clear();
+        self.phase_deadline = None;
+        self.retries = 0;
+        self.connect()
+    }
+
+    // Batch send: writes every message before reading any response, responses come back in the same order
+    pub fn send_batch(&mut self, messages: Vec<client_message::Message>) -> io::Result<Vec<ServerMessage>> {
+        let count = messages.len();
+        for message in messages {
+            self.send(message)?;
+        }
+        self.receive_all(count)
+    }
+
+    // Starts a pipeline of typed requests, see Pipeline
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+}
+
+// Sends the file at `path` as the EchoChunks of one streamed binary echo, see Client::send_file
+fn send_file_pieces(path: &Path, message_id: u64, mut send: impl FnMut(client_message::Message) -> io::Result<()>) -> io::Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut index = 0;
+    let mut piece = read_piece(&mut file)?;
+    loop {
+        // Reading one piece ahead tells whether this one is the last
+        let next = if piece.len() == FILE_CHUNK_SIZE { read_piece(&mut file)? } else { Vec::new() };
+        let last = next.is_empty();
+        send(client_message::Message::EchoChunk(EchoChunk {
+            message_id,
+            index,
+            data: piece,
+            r#final: last,
+            stream_reply: true,
+            ..Default::default()
+        }))?;
+        if last {
+            return Ok(());
+        }
+        piece = next;
+        index += 1;
+    }
+}
+
+// Writes the echoed pieces `receive` returns to `path` until the final one, see Client::receive_to_file
+fn receive_file_pieces(path: &Path, mut receive: impl FnMut() -> io::Result<ServerMessage>) -> io::Result<u64> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut written = 0;
+    let mut next_index = 0;
+    loop {
+        match receive()?.message {
+            Some(server_message::Message::EchoChunk(chunk)) if chunk.index == next_index => {
+                file.write_all(&chunk.data)?;
+                written += chunk.data.len() as u64;
+                next_index += 1;
+                if chunk.r#final {
+                    file.flush()?;
+                    return Ok(written);
+                }
+            }
+            other => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected response while receiving a file: {:?}", other),
+                ))
+            }
+        }
+    }
+}
+
+// Reads up to FILE_CHUNK_SIZE bytes, fewer only at the end of the file
+fn read_piece(file: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut piece = Vec::with_capacity(FILE_CHUNK_SIZE);
+    file.take(FILE_CHUNK_SIZE as u64).read_to_end(&mut piece)?;
+    Ok(piece)
+}
+
+// Nanoseconds since the Unix epoch, the timestamp format of sent_at_unix_nanos
+fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+// Reads one frame body into `buf`, returns false on a clean EOF between frames
+// Like read_frame(), the buffer grows with the bytes received, so a bogus length prefix can't make it allocate up front
+fn read_frame_into<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let Some(len) = read_header(reader)? else {
+        return Ok(false);
+    };
+    buf.clear();
+    reader.take(len as u64).read_to_end(buf)?;
+    if buf.len() < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed mid-frame"));
+    }
+    Ok(true)
+}
+
+// A stream whose socket timeout is shrunk to the time left before `deadline` ahead of every read and write, so a peer
+// taking or sending data a little at a time can't stretch an operation past it
+struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+    clock: &'a dyn Clock,
+}
+
+impl DeadlineStream<'_> {
+    fn remaining(&self) -> io::Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(self.clock.now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "Deadline passed"));
+        }
+        Ok(remaining)
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        (&*self.stream).read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        (&*self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.stream).flush()
+    }
+}
+
+// Share of the time left before `deadline` for the next of `phases` remaining phases, TimedOut once none is left
+fn phase_timeout(deadline: Instant, phases: u32, clock: &dyn Clock) -> io::Result<Duration> {
+    let remaining = deadline.saturating_duration_since(clock.now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(ErrorKind::TimedOut, "Call budget spent"));
+    }
+    Ok((remaining / phases).max(Duration::from_millis(1)))      // A zero socket timeout is rejected
+}
+
+// Stops the heartbeat thread so it doesn't outlive the client
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.stop_heartbeat();
+    }
+}
+
+// How disconnect() found the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectOutcome {
+    Clean,            // Still open, this side closed it
+    AlreadyClosed,    // The server had closed or reset it first, or there was no connection
+}
+
+// Features the server announced in its HelloResponse, see Client::server_features()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerFeatures {
+    pub version: u32,             // Negotiated protocol version, 0 before hello()
+    pub auth_required: bool,      // Send authenticate() before anything else
+    pub subscriptions: bool,      // subscribe() and publish() are served
+    pub streaming: bool,          // start_stream() and cancel() are served
+}
+
+// Round-trip times measured by measure_rtt()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+
+//Pipeline: fluent builder queuing requests that are sent as one batch on execute()
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<client_message::Message>,
+}
+
+impl Pipeline<'_> {
+    // Queues an EchoMessage
+    pub fn echo(mut self, content: &str) -> Self {
+        self.requests.push(client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        }));
+        self
+    }
+
+    // Queues an AddRequest, a sum outside int64 comes back exact like Client::add
+    pub fn add(mut self, a: i64, b: i64) -> Self {
+        self.requests
+            .push(client_message::Message::AddRequest(AddRequest { a, b, allow_big_result: true }));
+        self
+    }
+
+    // Sends the queued requests and pairs each response with the request that produced it
+    pub fn execute(self) -> io::Result<PipelineResults> {
+        let kinds: Vec<bool> = self
+            .requests
+            .iter()
+            .map(|request| matches!(request, client_message::Message::EchoMessage(_)))
+            .collect();
+        let responses = self.client.send_batch(self.requests)?;
+
+        let mut results = Vec::with_capacity(responses.len());
+        for (index, (is_echo, response)) in kinds.into_iter().zip(responses).enumerate() {
+            let result = match (is_echo, response.message) {
+                (true, Some(server_message::Message::EchoMessage(echo))) => PipelineResult::Echo(echo),
+                (false, Some(server_message::Message::AddResponse(add))) => PipelineResult::Add(add),
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Pipeline response {} does not match its request", index),
+                    ))
+                }
+            };
+            results.push(result);
+        }
+        Ok(PipelineResults { results })
+    }
+}
+
+// One typed pipeline response
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineResult {
+    Echo(EchoMessage),
+    Add(AddResponse),
+}
+
+// Pipeline responses in submission order
+#[derive(Debug)]
+pub struct PipelineResults {
+    results: Vec<PipelineResult>,
+}
+
+impl PipelineResults {
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&PipelineResult> {
+        self.results.get(index)
+    }
+
+    // Echo content of the request at `index`, None if it was not an echo
+    pub fn echo(&self, index: usize) -> Option<&str> {
+        match self.results.get(index) {
+            Some(PipelineResult::Echo(echo)) => Some(&echo.content),
+            _ => None,
+        }
+    }
+
+    // Sum of the request at `index`, None if it was not an add
+    pub fn add(&self, index: usize) -> Option<i128> {
+        match self.results.get(index) {
+            Some(PipelineResult::Add(add)) if !add.big_result.is_empty() => add.big_result.parse().ok(),
+            Some(PipelineResult::Add(add)) => Some(add.result as i128),
+            _ => None,
+        }
+    }
+}
+
+// Sending half of a split client
+#[derive(Debug)]
+pub struct ClientSender {
+    stream: TcpStream,
+    next_message_id: u64,      // Continues the client's message ids
+}
+
+impl ClientSender {
+    // Wraps the message in a ClientMessage and sends it as one frame
+    pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+        self.stream.write_all(&encode_client_message(&message)?)
+    }
+
+    // Streams a file as Client::send_file does, the ClientReceiver collects the echo with receive_to_file()
+    pub fn send_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        send_file_pieces(path.as_ref(), message_id, |message| self.send(message))
+    }
+}
+
+// Receiving half of a split client
+#[derive(Debug)]
+pub struct ClientReceiver {
+    stream: TcpStream,
+    pending: VecDeque<ServerMessage>,   // Set aside or peeked on the client before the split, returned first
+}
+
+impl ClientReceiver {
+    // Receives the next message, waiting up to the client timeout
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        match read_frame(&mut self.stream)? {
+            Some(frame) => ServerMessage::decode(&frame[..]).map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData, format!("Failed to decode ServerMessage: {}", e))
+            }),
+            None => Err(io::Error::new(ErrorKind::ConnectionAborted, "Server disconnected")),
+        }
+    }
+
+    // Writes the pieces of a streamed binary echo to `path` as they arrive, see Client::receive_to_file
+    pub fn receive_to_file(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        receive_file_pieces(path.as_ref(), || self.receive())
+    }
+}
+
+// Returned by Client::split, holds the unsplit client so it stays usable
+pub struct SplitError {
+    client: Box<Client>,
+    error: io::Error,
+}
+
+impl SplitError {
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    // Gives the client back, unchanged by the failed split
+    pub fn into_client(self) -> Client {
+        *self.client
+    }
+}
+
+impl std::fmt::Debug for SplitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SplitError").field("error", &self.error).finish_non_exhaustive()
+    }
+}
+
+impl From<SplitError> for io::Error {
+    fn from(split: SplitError) -> Self {
+        split.error
+    }
+}
+
+// Error inside the io::Error a client returns when the server refused the connection because it is full
+// Retrying is pointless until a slot frees up, so send_and_receive() doesn't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerAtCapacity {
+    pub max_clients: u32,
+}
+
+impl std::fmt::Display for ServerAtCapacity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server is at capacity ({} clients)", self.max_clients)
+    }
+}
+
+impl std::error::Error for ServerAtCapacity {}
+
+impl ServerAtCapacity {
+    // Returns the rejection if `error` is one
+    pub fn find(error: &io::Error) -> Option<ServerAtCapacity> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<ServerAtCapacity>()).copied()
+    }
+}
+
+// A response rejecting the request instead of answering it, see Client::with_retry_on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerErrorCode {
+    Error,                  // The request was invalid or failed, e.g. a validation error from the handler
+    ServerBusy,             // Too many handler calls were running
+    Expired,                // The request expired before the server got to it
+    ShuttingDown,           // The server is draining
+    Unauthorized,
+    UnsupportedOperation,   // Not served at the negotiated protocol version
+}
+
+impl ServerErrorCode {
+    // Returns the code of `response` if it is a rejection
+    pub fn of(response: &ServerMessage) -> Option<ServerErrorCode> {
+        match response.message {
+            Some(server_message::Message::Error(_)) => Some(ServerErrorCode::Error),
+            Some(server_message::Message::ServerBusy(_)) => Some(ServerErrorCode::ServerBusy),
+            Some(server_message::Message::Expired(_)) => Some(ServerErrorCode::Expired),
+            Some(server_message::Message::ShuttingDown(_)) => Some(ServerErrorCode::ShuttingDown),
+            Some(server_message::Message::Unauthorized(_)) => Some(ServerErrorCode::Unauthorized),
+            Some(server_message::Message::UnsupportedOperation(_)) => Some(ServerErrorCode::UnsupportedOperation),
+            _ => None,
+        }
+    }
+}
+
+// Error inside the io::Error checked_add() returns when the server reports that the sum overflowed int64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddOverflow {
+    pub a: i64,
+    pub b: i64,
+}
+
+impl std::fmt::Display for AddOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} + {} overflows int64", self.a, self.b)
+    }
+}
+
+impl std::error::Error for AddOverflow {}
+
+impl AddOverflow {
+    // Returns the overflow if `error` is one
+    pub fn find(error: &io::Error) -> Option<AddOverflow> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<AddOverflow>()).copied()
+    }
+}
+
+// Error inside the io::Error receive() returns when the response validator rejected a message
+// The server already handled the request, so send_and_receive() doesn't send it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseRejected {
+    pub reason: String,      // Returned by the validator
+}
+
+impl std::fmt::Display for ResponseRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Response rejected: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ResponseRejected {}
+
+impl ResponseRejected {
+    // Returns the rejection if `error` is one
+    pub fn find(error: &io::Error) -> Option<ResponseRejected> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<ResponseRejected>()).cloned()
+    }
+}
+
+// Pool of idle lazy-connecting clients to one server, a released client keeps its connection for the next acquire
+pub struct ClientPool {
+    ip: String,
+    port: u32,
+    timeout_ms: u64,
+    idle: Vec<Client>,
+}
+
+impl ClientPool {
+    pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
+        ClientPool {
+            ip: ip.to_string(),
+            port,
+            timeout_ms,
+            idle: Vec::new(),
+        }
+    }
+
+    // Returns an idle client, or a new one that connects on first use
+    pub fn acquire(&mut self) -> Client {
+        self.idle
+            .pop()
+            .unwrap_or_else(|| Client::new(&self.ip, self.port, self.timeout_ms).lazy_connect(true))
+    }
+
+    // Returns a client to the pool for reuse
+    pub fn release(&mut self, client: Client) {
+        self.idle.push(client);
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+// Callers waiting for a response, by correlation id, None once the connection is gone
+type PendingCalls = Arc<Mutex<Option<HashMap<u64, mpsc::Sender<ServerMessage>>>>>;
+
+// One connection shared by many threads: each request is stamped with its own correlation id and written whole under a
+// lock, and a reader thread hands every response to the caller waiting for its id
+pub struct Multiplexer {
+    writer: Mutex<TcpStream>,
+    pending: PendingCalls,
+    next_id: AtomicU64,              // Correlation id of the next request, 0 is left for notifications
+    timeout: Duration,               // How long a call waits for its response
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Multiplexer {
+    fn new(writer: TcpStream, mut reader: TcpStream, timeout: Duration) -> Self {
+        let pending: PendingCalls = Arc::new(Mutex::new(Some(HashMap::new())));
+        let routes = pending.clone();
+        let reader = thread::spawn(move || {
+            let _ = reader.set_read_timeout(None);      // Idle stretches are normal, Drop shuts the socket down to stop this
+            loop {
+                let frame = match read_frame(&mut reader) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Multiplexed connection failed: {}", e);
+                        break;
+                    }
+                };
+                let message = match ServerMessage::decode(&frame[..]) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("Failed to decode message: {}", e);
+                        continue;
+                    }
+                };
+                let waiting = routes.lock().unwrap().as_mut().and_then(|calls| calls.remove(&message.correlation_id));
+                match waiting {
+                    Some(caller) => {
+                        let _ = caller.send(message);     // The caller may have timed out
+                    }
+                    None => info!("Dropped a message no call is waiting for: {:?}", message),
+                }
+            }
+            routes.lock().unwrap().take();        // Wakes every waiting caller with an error
+        });
+        Multiplexer {
+            writer: Mutex::new(writer),
+            pending,
+            next_id: AtomicU64::new(1),
+            timeout,
+            reader: Some(reader),
+        }
+    }
+
+    // Sends `message` and waits for the response to it, callable from any number of threads at once
+    pub fn call(&self, message: client_message::Message) -> io::Result<ServerMessage> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (reply, response) = mpsc::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(calls) => calls.insert(id, reply),
+            None => return Err(io::Error::new(ErrorKind::ConnectionAborted, "Server disconnected")),
+        };
+        let request = ClientMessage {
+            message: Some(message),
+            correlation_id: id,
+            ..Default::default()
+        };
+        if let Err(e) = write_frame(&mut *self.writer.lock().unwrap(), &request.encode_to_vec()) {
+            self.forget(id);
+            return Err(e);
+        }
+        match response.recv_timeout(self.timeout) {
+            Ok(message) => Ok(message),
+            Err(RecvTimeoutError::Timeout) => {
+                self.forget(id);
+                Err(io::Error::new(ErrorKind::TimedOut, format!("No response to request {}", id)))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(ErrorKind::ConnectionAborted, "Server disconnected")),
+        }
+    }
+
+    fn forget(&self, id: u64) {
+        if let Some(calls) = self.pending.lock().unwrap().as_mut() {
+            calls.remove(&id);
+        }
+    }
+}
+
+// Closes the connection and waits for the reader thread
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        let _ = self.writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
 }
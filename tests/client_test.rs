@@ -1,27 +1,60 @@
+#![allow(clippy::field_reassign_with_default)]    // Tests build messages field by field for readability
 
 //IMPORTS
 use embedded_recruitment_task::{                            //Imports various message types (client_message, server_message, AddRequest, EchoMessage) and the Server struct from the embedded_recruitment_task crate.
-    message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
+    clock::MockClock,
+    frame::{self, write_frame},
+    handler::{process, ConnectionContext, HandlerAction},
+    recording::{self, Direction},
+    message::{client_message, server_message, AddRequest, Broadcast, ClientInfoRequest, ClientMessage, EchoChunk, EchoMessage, Error, Kick, MetricsRequest, Ping, Publish, QuotaExceeded, Resubscribe, ServerBusy, ServerMessage, Subscribe},
+    server::{AcceptOrder, AuditRecord, AuditResult, BackpressurePolicy, ConnectionInfo, DrainStatus, DrainingRequestPolicy, ExpiredRequestPolicy, FaultAction, FaultRate, LargeMessagePolicy, RejectionStats, Server, ServerConfig, ServerEvent, UnknownMessagePolicy, WorkerPoolConfig, WriteRetryPolicy, PROTOCOL_VERSION},
 };
-use std::{        //Imports synchronization primitives (Arc) and threading utilities (thread, JoinHandle).
-    sync::Arc,
+use prost::Message;          //Encodes messages by hand for raw-frame tests
+use std::{        //Imports synchronization primitives (Arc), threading utilities (thread, JoinHandle) and raw sockets for malformed input.
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 mod client;       //Imports the client module
+mod logger;       //Captures server log messages
 
 fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {           //Spawns a new thread to run the server, Uses an Arc (atomic reference counted) pointer to share ownership of the Server instance across threads.
-    thread::spawn(move || {
-        server.run().expect("Server encountered an error");   //Panics with a message if the server encounters an error
-    })
+    let (ready, bound) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        server.run_with_ready(ready).expect("Server encountered an error");   //Panics with a message if the server encounters an error
+    });
+    // Wait for the accept loop to start, otherwise a quick stop() would race with run()
+    bound.recv().expect("Server failed to start");
+    handle
 }
 
 //Creates a new Server instance and wraps it in an Arc
 fn create_server() -> Arc<Server> {
-    Arc::new(Server::new("localhost:8080").expect("Failed to start server"))             //Initializes the server to listen on localhost:8080, Panics with a message if the server fails to start.
+    Arc::new(Server::new("localhost:0", 100).expect("Failed to start server"))             //Initializes the server on a free port so tests can run in parallel, Panics with a message if the server fails to start.
 }
 
+//Returns the port the server was bound to
+fn server_port(server: &Server) -> u32 {
+    server.local_addr().expect("Failed to read server address").port() as u32
+}
+
+//Polls `condition` for up to two seconds, returns whether it became true
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
 
 //Tests the client's ability to connect and disconnect from the server.
 #[test]
@@ -29,9 +62,10 @@ fn test_client_connection() {
     // Set up the server in a separate thread
     let server = create_server();                         //Creates the server.
     let handle = setup_server_thread(server.clone());     //Runs the server in a separate thread
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);            //Creates a new client instance
+    let mut client = client::Client::new("localhost", port, 1000);            //Creates a new client instance
     assert!(client.connect().is_ok(), "Failed to connect to the server");     //Connects the client to the server.
     assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");   //Disconnects the client from the server
    
@@ -46,9 +80,10 @@ fn test_client_echo_message() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepares an echo message with the content "Hello, World!"
@@ -89,9 +124,10 @@ fn test_multiple_echo_messages() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare a list of messages to be sent
@@ -117,7 +153,7 @@ fn test_multiple_echo_messages() {
         
            if let Some(server_message::Message::EchoMessage(echo)) = response.unwrap().message {
                 assert_eq!(
-                    echo.content, message_content,
+                    echo.content, *message_content,
                     "Echoed message content does not match"
                 );
             }else{
@@ -139,12 +175,13 @@ fn test_multiple_clients() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect multiple client instances
-    let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+    let mut clients = [
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
     ];
 
     for client in clients.iter_mut() {
@@ -175,7 +212,7 @@ fn test_multiple_clients() {
 
                if let Some(server_message::Message::EchoMessage(echo)) = response.unwrap().message {
                     assert_eq!(
-                        echo.content, message_content,
+                        echo.content, *message_content,
                         "Echoed message content does not match"
                     );
                 }else{
@@ -200,16 +237,17 @@ fn test_client_add_request() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
     let mut add_request = AddRequest::default();
     add_request.a = 10;
     add_request.b = 20;
-    let message = client_message::Message::AddRequest(add_request.clone());
+    let message = client_message::Message::AddRequest(add_request);
 
     // Send the message to the server
     assert!(client.send(message).is_ok(), "Failed to send message");
@@ -242,14 +280,22 @@ fn test_client_add_request() {
 fn test_invalid_message_handling() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
-    // Send an invalid message
-    let invalid_message = client_message::Message::Unknown;
-    assert!(client.send(invalid_message).is_err(), "Invalid message was not rejected");
+    // Send a frame whose body is not a valid ClientMessage
+    let mut raw = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to open raw connection");
+    write_frame(&mut raw, &[0xff, 0xff, 0xff]).expect("Failed to send invalid frame");
 
+    // The server logs the decode error and keeps serving, other connections are unaffected
+    let response = client.send_and_receive(client_message::Message::EchoMessage(EchoMessage {
+        content: "still alive".to_string(),
+    }));
+    assert!(response.is_ok(), "Server stopped serving after an invalid message");
+
+    drop(raw);
     client.disconnect().expect("Failed to disconnect");
     server.stop();
     handle.join().expect("Server thread panicked or failed to join");
@@ -260,10 +306,11 @@ fn test_invalid_message_handling() {
 fn test_stress_large_number_of_clients() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     let mut clients = Vec::new();
     for _ in 0..100 {
-        let mut client = client::Client::new("localhost", 8080, 1000);
+        let mut client = client::Client::new("localhost", port, 1000);
         assert!(client.connect().is_ok(), "Failed to connect client to server");
         clients.push(client);
     }
@@ -281,15 +328,14 @@ fn test_stress_large_number_of_clients() {
 fn test_timeout_handling() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
-    let mut client = client::Client::new("localhost", 8080, 1); // 1 ms timeout
+    let mut client = client::Client::new("localhost", port, 1); // 1 ms timeout
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
-    // Delay to trigger timeout
-    std::thread::sleep(std::time::Duration::from_millis(10));
-
-    let response = client.receive();
-    assert!(response.is_err(), "Timeout error was not triggered");
+    // Nothing was requested, so the receive times out on its own after 1 ms
+    let error = client.receive().expect_err("Timeout error was not triggered");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "Unexpected error: {}", error);
 
     client.disconnect().expect("Failed to disconnect");
     server.stop();
@@ -301,24 +347,26 @@ fn test_timeout_handling() {
 fn test_concurrent_add_requests() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
     ];
 
     for client in &mut clients {
         assert!(client.connect().is_ok(), "Failed to connect to the server");
     }
 
-    let add_requests = vec![(5, 7), (10, 20)];
+    let add_requests = [(5, 7), (10, 20)];
 
     let handles: Vec<_> = clients
-        .iter_mut()
+        .into_iter()
         .enumerate()
         .map(|(i, client)| {
             let (a, b) = add_requests[i];
             thread::spawn(move || {
+                let mut client = client;
                 let mut add_request = AddRequest::default();
                 add_request.a = a;
                 add_request.b = b;
@@ -336,15 +384,13 @@ fn test_concurrent_add_requests() {
                 } else {
                     panic!("Expected AddResponse, but got different message");
                 }
+                client
             })
         })
         .collect();
 
     for handle in handles {
-        handle.join().expect("Client thread panicked");
-    }
-
-    for client in &mut clients {
+        let mut client = handle.join().expect("Client thread panicked");
         client.disconnect().expect("Failed to disconnect");
     }
 
@@ -357,8 +403,9 @@ fn test_concurrent_add_requests() {
 fn test_graceful_shutdown_with_active_clients() {
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     server.stop();
@@ -368,12 +415,21 @@ fn test_graceful_shutdown_with_active_clients() {
 }
 
 //checks the server's behavior when there is a significant delay in sending or receiving a message.
+//The delays run on a mock clock: each stays below the idle timeout, so the connection must survive them without real sleeps.
 #[test]
 fn test_delayed_messages() {
-    let server = create_server();
+    let clock = Arc::new(MockClock::new());
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .idle_timeout(Duration::from_secs(3))
+            .clock(clock.clone())
+            .build()
+            .expect("Failed to start server"),
+    );
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare and send an echo message
@@ -382,14 +438,14 @@ fn test_delayed_messages() {
     let message = client_message::Message::EchoMessage(echo_message.clone());
 
     // Simulate a delay before sending the message
-    std::thread::sleep(std::time::Duration::from_secs(2));
+    clock.advance(Duration::from_secs(2));
     assert!(
         client.send(message).is_ok(),
         "Failed to send message after delay"
     );
 
     // Simulate a delay before receiving the response
-    std::thread::sleep(std::time::Duration::from_secs(2));
+    clock.advance(Duration::from_secs(2));
     let response = client.receive();
     assert!(
         response.is_ok(),
@@ -414,13 +470,14 @@ fn test_delayed_messages() {
 //nsures the server behaves correctly when the maximum client limit is reached and new connections are refused.
 #[test]
 fn test_connection_refusal() {
-    let server = create_server();
+    let server = Arc::new(Server::new("localhost:0", 2).expect("Failed to start server"));
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
-    // Assume the server allows a maximum of 2 clients (adjust if necessary)
+    // The server allows a maximum of 2 clients
     let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
     ];
 
     // Connect the maximum allowed number of clients
@@ -429,9 +486,16 @@ fn test_connection_refusal() {
     }
 
     // Attempt to connect an additional client beyond the limit
-    let mut additional_client = client::Client::new("localhost", 8080, 1000);
+    // The TCP handshake completes in the kernel, the server then sends an AtCapacity rejection and closes the connection
+    let mut additional_client = client::Client::new("localhost", port, 1000);
+    let refused = match additional_client.connect() {
+        Err(_) => true,
+        Ok(()) => additional_client
+            .receive()
+            .is_err_and(|e| client::ServerAtCapacity::find(&e) == Some(client::ServerAtCapacity { max_clients: 2 })),
+    };
     assert!(
-        additional_client.connect().is_err(),
+        refused,
         "Additional client was able to connect despite connection limit"
     );
 
@@ -450,9 +514,10 @@ fn test_large_echo_message() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Generate a large message content
@@ -491,3 +556,4072 @@ fn test_large_echo_message() {
     server.stop();
     handle.join().expect("Server thread panicked or failed to join");
 }
+
+//Tests the fluent pipeline API: mixed requests are sent as one batch and each result comes back typed, in submission order
+#[test]
+fn test_pipeline_mixed_requests() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let results = client
+        .pipeline()
+        .echo("a")
+        .add(1, 2)
+        .echo("b")
+        .add(i64::MAX, 1)
+        .execute()
+        .expect("Pipeline failed");
+
+    assert_eq!(results.len(), 4, "Expected one result per queued request");
+    assert_eq!(results.add(3), Some(i64::MAX as i128 + 1), "A sum outside int64 wasn't exact");
+    assert_eq!(results.echo(0), Some("a"));
+    assert_eq!(results.add(1), Some(3));
+    assert_eq!(results.echo(2), Some("b"));
+    assert_eq!(results.add(0), None, "First result should be typed as an echo");
+    assert_eq!(results.echo(1), None, "Second result should be typed as an add");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Tests that a server requiring authentication rejects messages until the connection sends a valid Auth
+#[test]
+fn test_require_auth() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .require_auth(|token| token == "secret")
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "Hello".to_string(),
+    });
+
+    // Unauthenticated echo is rejected
+    client.send(echo.clone()).expect("Failed to send message");
+    let response = client.receive().expect("Failed to receive response");
+    assert!(
+        matches!(response.message, Some(server_message::Message::Unauthorized(_))),
+        "Unauthenticated echo was not rejected"
+    );
+
+    // A wrong token is rejected too
+    let error = client.authenticate("wrong").expect_err("Invalid token was accepted");
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+    // After a valid Auth the echo succeeds
+    client.authenticate("secret").expect("Valid token was rejected");
+    client.send(echo).expect("Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(response)) => assert_eq!(response.content, "Hello"),
+        other => panic!("Expected EchoMessage after authenticating, got {:?}", other),
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Rapidly connects and drops more clients than the server accepts, the count must return exactly to zero
+#[test]
+fn test_client_count_returns_to_zero() {
+    let server = Arc::new(Server::new("localhost:0", 2).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let address = server.local_addr().expect("Failed to read server address");
+
+    for _ in 0..5 {
+        let streams: Vec<TcpStream> = (0..10)
+            .map(|_| TcpStream::connect(address).expect("Failed to connect"))
+            .collect();
+        thread::sleep(Duration::from_millis(30));      // Let the server accept some and refuse the rest
+        assert!(server.active_client_count() <= 2, "Server accepted more clients than allowed");
+        drop(streams);
+    }
+
+    // Handler threads release their slot as they notice the disconnects
+    wait_for(|| server.active_client_count() == 0);
+    assert_eq!(server.active_client_count(), 0, "Client count did not return to zero");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Sends truncated frames through send_raw: undecodable bodies are skipped until the decode-failure limit disconnects the client
+#[test]
+fn test_send_raw_truncated_frame() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // A valid echo with its last bytes cut off, sent as a correctly sized frame
+    let encoded = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "truncated".to_string(),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let truncated = &encoded[..encoded.len() - 4];
+    client.send_raw(truncated, true).expect("Failed to send raw frame");
+
+    // The same body with a hand-written length prefix
+    let mut framed = (truncated.len() as u32).to_be_bytes().to_vec();
+    framed.extend_from_slice(truncated);
+    client.send_raw(&framed, false).expect("Failed to send raw frame");
+
+    // Undecodable frames get no response, the connection keeps serving
+    let response = client
+        .send_and_receive(client_message::Message::EchoMessage(EchoMessage {
+            content: "after".to_string(),
+        }))
+        .expect("Connection was dropped after a truncated frame");
+    assert!(matches!(response.message, Some(server_message::Message::EchoMessage(echo)) if echo.content == "after"));
+
+    // More than three consecutive failures disconnect the client
+    for _ in 0..4 {
+        client.send_raw(truncated, true).expect("Failed to send raw frame");
+    }
+    let error = client.receive().expect_err("Server kept the connection after repeated decode failures");
+    assert!(matches!(error.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset));
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Declares a large frame and trickles its body: the per-frame deadline drops the connection long before the body is complete
+#[test]
+fn test_slow_trickle_hits_frame_deadline() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .frame_deadline(Some(Duration::from_millis(300)))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let declared: u32 = 8 * 1024 * 1024;
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to connect");
+    stream.write_all(&declared.to_be_bytes()).expect("Failed to send length prefix");
+    stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+    let started = Instant::now();
+    let mut sent = 0usize;
+    let closed = loop {
+        if started.elapsed() > Duration::from_secs(3) {
+            break false;
+        }
+        if stream.write_all(&[0u8; 64]).is_err() {
+            break true;       // Server reset the connection
+        }
+        sent += 64;
+        match stream.read(&mut [0u8; 1]) {
+            Ok(0) => break true,       // Server closed the connection
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Ok(_) => panic!("Server responded to an incomplete frame"),
+            Err(_) => break true,
+        }
+    };
+
+    assert!(closed, "Connection was not dropped while the body trickled in");
+    assert!(sent < declared as usize / 100, "Server waited for most of the body ({} bytes)", sent);
+    assert!(started.elapsed() < Duration::from_secs(2), "Deadline took too long to fire");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Fills the single slot, queues three more connections and records the order in which the queue serves them
+fn accept_queue_service_order(order: AcceptOrder) -> Vec<String> {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_clients(1)
+            .accept_queue_capacity(3)
+            .accept_order(order)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut holder = client::Client::new("localhost", port, 1000);
+    holder.connect().expect("Failed to connect");
+    assert!(wait_for(|| server.active_client_count() == 1));
+
+    // Each queued client sends its echo right away, it is only answered once the client is served
+    let mut waiting = Vec::new();
+    for (index, name) in ["first", "second", "third"].iter().enumerate() {
+        let mut client = client::Client::new("localhost", port, 100);
+        client.connect().expect("Failed to connect");
+        client
+            .send(client_message::Message::EchoMessage(EchoMessage { content: name.to_string() }))
+            .expect("Failed to send");
+        assert!(wait_for(|| server.queued_connection_count() == index + 1), "Connection was not queued");
+        waiting.push(client);
+    }
+
+    // Free the slot, then each served client releases it for the next one
+    holder.disconnect().expect("Failed to disconnect");
+    let mut served = Vec::new();
+    while !waiting.is_empty() {
+        let position = (0..waiting.len())
+            .cycle()
+            .take(50)
+            .find(|&i| match waiting[i].receive() {
+                Ok(response) => {
+                    if let Some(server_message::Message::EchoMessage(echo)) = response.message {
+                        served.push(echo.content);
+                    }
+                    true
+                }
+                Err(_) => false,      // Still queued
+            })
+            .expect("No queued connection was served");
+        waiting.remove(position).disconnect().expect("Failed to disconnect");
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    served
+}
+
+//Saturates the accept queue and checks both disciplines serve waiting connections in the configured order
+#[test]
+fn test_accept_order() {
+    assert_eq!(accept_queue_service_order(AcceptOrder::Fifo), ["first", "second", "third"]);
+    assert_eq!(accept_queue_service_order(AcceptOrder::Lifo), ["third", "second", "first"]);
+}
+
+//A lazy client connects on its first request, without an explicit connect()
+#[test]
+fn test_lazy_connect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000).lazy_connect(true);
+    assert_eq!(client.add(2, 3).expect("Lazy client failed to add"), 5);
+    assert!(wait_for(|| server.active_client_count() == 1), "Lazy client did not connect");
+
+    // Explicit-connect clients still report NotConnected
+    let mut eager = client::Client::new("localhost", port, 1000);
+    let error = eager
+        .send(client_message::Message::EchoMessage(EchoMessage::default()))
+        .expect_err("Client sent without a connection");
+    assert_eq!(error.kind(), ErrorKind::NotConnected);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Large socket buffers on both ends: the option is applied and a 10 MB echo completes
+#[test]
+fn test_socket_buffer_sizes() {
+    const BUFFER: usize = 1024 * 1024;
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .socket_buffer_sizes(Some(BUFFER), Some(BUFFER))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000).socket_buffer_sizes(Some(BUFFER), Some(BUFFER));
+    client.connect().expect("Failed to connect to the server");
+
+    let (recv, send) = client.applied_buffer_sizes().expect("Failed to read socket buffer sizes");
+    assert!(recv >= BUFFER && send >= BUFFER, "Socket buffer sizes were not applied: {} / {}", recv, send);
+
+    let content = "B".repeat(10_000_000);
+    assert_eq!(client.echo(&content).expect("Large echo failed"), content);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A handler returning RespondAndClose: the client gets the response, then the connection is closed
+#[test]
+fn test_handler_respond_and_close() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|message, _: &mut ConnectionContext| match message {
+                client_message::Message::EchoMessage(echo) => {
+                    HandlerAction::RespondAndClose(server_message::Message::EchoMessage(echo))
+                }
+                _ => HandlerAction::Close,
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("bye").expect("Failed to receive the response"), "bye");
+
+    let error = client.receive().expect_err("Connection stayed open after RespondAndClose");
+    assert_eq!(error.kind(), ErrorKind::ConnectionAborted, "Expected EOF after the response");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With a per-IP cap of two, a third connection from loopback is refused
+#[test]
+fn test_max_connections_per_ip() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_connections_per_ip(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let loopback = server.local_addr().unwrap().ip();
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        client.connect().expect("Failed to connect to the server");
+        clients.push(client);
+    }
+    assert!(wait_for(|| server.connection_count_for_ip(loopback) == 2));
+
+    let mut third = client::Client::new("localhost", port, 1000);
+    third.connect().expect("TCP connect failed");
+    let error = third.receive().expect_err("Third connection from the same IP was served");
+    assert!(matches!(error.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset));
+    assert_eq!(server.connection_count_for_ip(loopback), 2);
+
+    // The first two are unaffected
+    for client in &mut clients {
+        assert_eq!(client.add(1, 1).expect("Capped connection stopped working"), 2);
+        client.disconnect().expect("Failed to disconnect");
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With echo_repeat = 3 a single echo is answered exactly three times
+#[test]
+fn test_echo_repeat() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .echo_repeat(3)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 200);
+    client.connect().expect("Failed to connect to the server");
+    client
+        .send(client_message::Message::EchoMessage(EchoMessage::from("again")))
+        .expect("Failed to send message");
+
+    let responses = client.receive_all(3).expect("Failed to receive the repeated echoes");
+    for response in responses {
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "again"),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+
+    // Nothing beyond the third copy
+    let error = client.receive().expect_err("Received more than three echoes");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A second run() on a running server fails immediately instead of starting another accept loop
+#[test]
+fn test_run_twice_is_rejected() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let started = Instant::now();
+    let error = server.run().expect_err("Second run() was accepted");
+    assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    assert!(started.elapsed() < Duration::from_millis(500), "Second run() did not return immediately");
+
+    // The first accept loop keeps serving
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("still running").expect("Echo failed"), "still running");
+    client.disconnect().expect("Failed to disconnect");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Heartbeat frames are sent in the background and don't disturb interleaved echoes
+#[test]
+fn test_heartbeat_interleaved_with_echoes() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 200);
+    client.connect().expect("Failed to connect to the server");
+    client.enable_heartbeat(Duration::from_millis(5)).expect("Failed to enable heartbeat");
+
+    for i in 0..50 {
+        let content = format!("echo {}", i);
+        assert_eq!(client.echo(&content).expect("Echo failed"), content);
+        thread::sleep(Duration::from_millis(2));
+    }
+    assert!(wait_for(|| client.heartbeats_sent() >= 10), "Heartbeat frames were not sent");
+
+    // The server answers heartbeats with nothing
+    let error = client.receive().expect_err("Server answered a heartbeat");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+
+    client.disconnect().expect("Failed to disconnect");
+    assert_eq!(client.heartbeats_sent(), 0, "Heartbeat still running after disconnect");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A zero linger closes with a reset, so the client's port skips TIME_WAIT and can be bound again right away
+#[test]
+fn test_zero_linger_frees_port() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000).linger(Some(Duration::ZERO));
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("linger").expect("Echo failed"), "linger");
+    let local_addr = client.local_addr().expect("No local address");
+    client.disconnect().expect("Failed to disconnect");
+
+    // Reconnecting from the same address to the same server fails while that connection is still in TIME_WAIT
+    let socket = socket2::Socket::new(socket2::Domain::for_address(local_addr), socket2::Type::STREAM, None)
+        .expect("Failed to create socket");
+    socket.set_reuse_address(true).expect("Failed to set SO_REUSEADDR");
+    socket.bind(&local_addr.into()).expect("Failed to bind the released port");
+    socket
+        .connect(&server.local_addr().expect("No server address").into())
+        .expect("Port was not released immediately");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A draining server reports open connections and unanswered messages until both reach zero
+#[test]
+fn test_draining_status() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|message, _: &mut ConnectionContext| {
+                thread::sleep(Duration::from_millis(300));      // Keeps the message in flight long enough to observe it
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut slow = client::Client::new("localhost", port, 2000);
+    slow.connect().expect("Failed to connect to the server");
+    let mut idle = client::Client::new("localhost", port, 2000);
+    idle.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 2));
+
+    let request = thread::spawn(move || {
+        let content = slow.echo("in flight");
+        (slow, content)
+    });
+    assert!(wait_for(|| server.draining_status().remaining_inflight == 1), "Request was never in flight");
+
+    server.drain();
+    assert!(server.is_draining());
+    assert_eq!(
+        server.draining_status(),
+        DrainStatus { remaining_connections: 2, remaining_inflight: 1 }
+    );
+
+    // New connections are refused while draining
+    let mut late = client::Client::new("localhost", port, 500);
+    let refused = late.connect().and_then(|_| late.echo("too late"));
+    assert!(refused.is_err(), "Draining server accepted a new connection");
+
+    // The in-flight request still completes
+    let (mut slow, content) = request.join().expect("Request thread panicked");
+    assert_eq!(content.expect("In-flight echo failed"), "in flight");
+    assert!(wait_for(|| server.draining_status().remaining_inflight == 0));
+
+    slow.disconnect().expect("Failed to disconnect");
+    idle.disconnect().expect("Failed to disconnect");
+    assert!(
+        wait_for(|| server.draining_status() == DrainStatus { remaining_connections: 0, remaining_inflight: 0 }),
+        "Drain did not reach zero"
+    );
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Responses on a connection carry contiguous sequence numbers, so a pipelining client can detect drops or reordering
+#[test]
+fn test_response_sequence_numbers() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let requests = (0..50)
+        .map(|i| client_message::Message::EchoMessage(EchoMessage::from(format!("message {}", i))))
+        .collect();
+    let responses = client.send_batch(requests).expect("Batch failed");
+    let sequence: Vec<u64> = responses.iter().map(|response| response.seq).collect();
+    assert_eq!(sequence, (0..50).collect::<Vec<u64>>());
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//TCP_NODELAY can be toggled on a connected client without disturbing the connection
+#[test]
+fn test_set_nodelay() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    for on in [true, false, true] {
+        client.set_nodelay(on).expect("Failed to set TCP_NODELAY");
+        assert_eq!(client.echo("tiny").expect("Echo failed"), "tiny");
+        assert_eq!(client.add(1, 2).expect("Add failed"), 3);
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    assert_eq!(client.set_nodelay(true).expect_err("Disconnected client accepted nodelay").kind(), ErrorKind::NotConnected);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Handler calls slower than the threshold are logged with the message type, timed by the server clock
+#[test]
+fn test_slow_handler_is_logged() {
+    logger::init();
+    let clock = Arc::new(MockClock::new());
+    let handler_clock = clock.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .slow_handler_threshold(Duration::from_millis(20))
+            .clock(clock)
+            .handler(move |message, _: &mut ConnectionContext| {
+                if let client_message::Message::AddRequest(_) = message {
+                    handler_clock.advance(Duration::from_millis(100));       // A slow handler, without waiting for it
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.add(40, 2).expect("Add failed"), 42);
+    assert!(logger::contains(&["Slow handler", "AddRequest"]), "Slow handler was not logged");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//After a reconnect the client replays its subscriptions, notifications resume without subscribing again
+#[test]
+fn test_reconnect_resubscribes() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut subscriber = client::Client::new("localhost", port, 1000);
+    subscriber.connect().expect("Failed to connect to the server");
+    subscriber.subscribe("news").expect("Subscribe failed");
+    let mut publisher = client::Client::new("localhost", port, 1000);
+    publisher.connect().expect("Failed to connect to the server");
+
+    assert_eq!(publisher.publish("news", "first").expect("Publish failed"), 1);
+    assert_eq!(subscriber.next_notification().expect("No notification").content, "first");
+
+    subscriber.reconnect().expect("Reconnect failed");
+    assert!(wait_for(|| server.active_client_count() == 2), "Old connection was not closed");
+
+    assert_eq!(publisher.publish("news", "second").expect("Publish failed"), 1);
+    let notification = subscriber.next_notification().expect("Subscription did not resume");
+    assert_eq!((notification.topic.as_str(), notification.content.as_str()), ("news", "second"));
+
+    subscriber.disconnect().expect("Failed to disconnect");
+    publisher.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Resubscribe restores only the topics subscribed under the session, a topic the client adds to the list is left out
+#[test]
+fn test_resubscribe_checks_session_topics() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut first = client::Client::new("localhost", port, 1000);
+    first.connect().expect("Failed to connect to the server");
+    first.send(client_message::Message::Subscribe(Subscribe { topic: "news".to_string() })).expect("Failed to subscribe");
+    let session = match first.receive_message().expect("Failed to receive") {
+        server_message::Message::Subscribed(subscribed) => subscribed.session,
+        other => panic!("Unexpected response to Subscribe: {:?}", other),
+    };
+    first.disconnect().expect("Failed to disconnect");
+
+    let mut second = client::Client::new("localhost", port, 1000);
+    second.connect().expect("Failed to connect to the server");
+    second
+        .send(client_message::Message::Resubscribe(Resubscribe { session, topics: vec!["news".to_string(), "secret".to_string()] }))
+        .expect("Failed to resubscribe");
+    match second.receive_message().expect("Failed to receive") {
+        server_message::Message::Subscribed(subscribed) => assert_eq!(subscribed.topics, ["news"]),
+        other => panic!("Unexpected response to Resubscribe: {:?}", other),
+    }
+    let mut publisher = client::Client::new("localhost", port, 1000);
+    publisher.connect().expect("Failed to connect to the server");
+    assert_eq!(publisher.publish("news", "restored").expect("Publish failed"), 1);
+    assert_eq!(publisher.publish("secret", "never subscribed").expect("Publish failed"), 0);
+
+    second.disconnect().expect("Failed to disconnect");
+    publisher.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A subscriber that stopped reading blocks only the publish that reaches it under BackpressurePolicy::Block,
+//subscribing and publishing on other topics go on
+#[test]
+fn test_blocked_subscriber_does_not_stall_other_topics() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .backpressure_policy(BackpressurePolicy::Block)
+            .max_pending_responses(1)
+            .socket_buffer_sizes(None, Some(4096))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut stalled = client::Client::new("localhost", port, 1000);
+    stalled.connect().expect("Failed to connect to the server");
+    stalled.subscribe("flood").expect("Subscribe failed");      // Never reads its notifications
+    let flooder = thread::spawn(move || {
+        let mut publisher = client::Client::new("localhost", port, 1000);
+        publisher.connect().expect("Failed to connect to the server");
+        for _ in 0..200 {
+            if publisher.publish("flood", &"f".repeat(64 * 1024)).is_err() {
+                break;      // Blocked behind the stalled subscriber
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(300));     // Lets the flood fill the stalled subscriber's queue
+
+    let mut subscriber = client::Client::new("localhost", port, 1000);
+    subscriber.connect().expect("Failed to connect to the server");
+    subscriber.subscribe("other").expect("Subscribe stalled behind the blocked subscriber");
+    let mut publisher = client::Client::new("localhost", port, 1000);
+    publisher.connect().expect("Failed to connect to the server");
+    assert_eq!(publisher.publish("other", "through").expect("Publish stalled behind the blocked subscriber"), 1);
+    assert_eq!(subscriber.next_notification().expect("No notification").content, "through");
+
+    drop(stalled);      // Its close fails the blocked write, so the flood publish returns
+    flooder.join().expect("Flood thread panicked");
+    subscriber.disconnect().expect("Failed to disconnect");
+    publisher.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Peeking shows the next response without consuming it
+#[test]
+fn test_peek_message() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    client
+        .send(client_message::Message::AddRequest(AddRequest { a: 2, b: 3, ..Default::default() }))
+        .expect("Failed to send message");
+
+    let peeked = client.peek_message().expect("Failed to peek").clone();
+    assert!(matches!(peeked, server_message::Message::AddResponse(ref add) if add.result == 5));
+    assert_eq!(client.peek_message().expect("Failed to peek again"), &peeked);
+    assert_eq!(client.receive_message().expect("Failed to receive"), peeked);
+
+    // Nothing left once the peeked message is consumed
+    let error = client.receive().expect_err("Received a second response");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+// Starts a server handling messages above 1 MB with `policy`, returns it with its thread and a connected client
+fn large_message_setup(policy: LargeMessagePolicy) -> (Arc<Server>, JoinHandle<()>, client::Client) {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_message_size(1_000_000)
+            .large_message_policy(policy)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+    (server, handle, client)
+}
+
+// Sends a 10 MB echo and returns its content
+fn send_large_echo(client: &mut client::Client) -> String {
+    let content = "A".repeat(10_000_000);
+    client
+        .send(client_message::Message::EchoMessage(EchoMessage { content: content.clone() }))
+        .expect("Failed to send large message");
+    content
+}
+
+//Reject answers an oversized message with an Error and keeps the connection usable
+#[test]
+fn test_large_message_reject() {
+    let (server, handle, mut client) = large_message_setup(LargeMessagePolicy::Reject);
+
+    send_large_echo(&mut client);
+    match client.receive_message().expect("No response to the large message") {
+        server_message::Message::Error(error) => assert!(error.reason.contains("exceeds"), "Unexpected reason: {}", error.reason),
+        other => panic!("Expected an Error, got {:?}", other),
+    }
+    assert_eq!(client.echo("small").expect("Connection unusable after rejection"), "small");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Truncate echoes only the first bytes of an oversized message, cut on a character boundary, and skips the rest of it.
+//Oversized messages of other types are rejected
+#[test]
+fn test_large_message_truncate() {
+    let (server, handle, mut client) = large_message_setup(LargeMessagePolicy::Truncate(1024));
+
+    let content = send_large_echo(&mut client);
+    match client.receive_message().expect("No response to the large message") {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, content[..1024]),
+        other => panic!("Expected an EchoMessage, got {:?}", other),
+    }
+    assert_eq!(client.echo("small").expect("Connection unusable after truncation"), "small");
+
+    // 1024 bytes end inside the 512th two-byte character
+    let content = format!("a{}", "é".repeat(5_000_000));
+    client.send(client_message::Message::EchoMessage(EchoMessage { content: content.clone() })).expect("Failed to send");
+    match client.receive_message().expect("No response to the large message") {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, content[..1023]),
+        other => panic!("Expected an EchoMessage, got {:?}", other),
+    }
+
+    let chunk = EchoChunk { message_id: 1, content: "A".repeat(2_000_000), r#final: true, ..Default::default() };
+    client.send(client_message::Message::EchoChunk(chunk)).expect("Failed to send");
+    match client.receive_message().expect("No response to the large chunk") {
+        server_message::Message::Error(error) => assert!(error.reason.contains("exceeds"), "Unexpected reason: {}", error.reason),
+        other => panic!("Expected an Error, got {:?}", other),
+    }
+    assert_eq!(client.echo("small").expect("Connection unusable after rejection"), "small");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Stream echoes an oversized message back in chunks no larger than max_message_size
+#[test]
+fn test_large_message_stream() {
+    let (server, handle, mut client) = large_message_setup(LargeMessagePolicy::Stream);
+
+    let content = send_large_echo(&mut client);
+    let mut echoed = String::new();
+    let mut chunks = 0;
+    while echoed.len() < content.len() {
+        match client.receive_message().expect("Stream ended early") {
+            server_message::Message::EchoMessage(echo) => {
+                assert!(echo.content.len() <= 1_000_000, "Chunk of {} bytes", echo.content.len());
+                echoed.push_str(&echo.content);
+                chunks += 1;
+            }
+            other => panic!("Expected an EchoMessage, got {:?}", other),
+        }
+    }
+    assert_eq!(chunks, 10);
+    assert!(echoed == content, "Reassembled echo does not match");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Stream echoes each chunk as soon as its bytes arrived, before the rest of the message was even sent
+#[test]
+fn test_large_message_stream_echoes_as_it_arrives() {
+    let (server, handle, client) = large_message_setup(LargeMessagePolicy::Stream);
+    drop(client);
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to connect to the server");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let echo = client_message::Message::EchoMessage(EchoMessage { content: "A".repeat(10_000_000) });
+    let request = frame::encode_client_message(&echo).expect("Failed to encode");
+    let (first, rest) = request.split_at(2_500_000);
+    stream.write_all(first).expect("Failed to send the start of the message");
+    for _ in 0..2 {
+        let response = frame::read_frame(&mut stream).expect("No chunk before the rest was sent").expect("Closed");
+        match ServerMessage::decode(&response[..]).expect("Undecodable response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content.len(), 1_000_000),
+            other => panic!("Expected an EchoMessage, got {:?}", other),
+        }
+    }
+    stream.write_all(rest).expect("Failed to send the rest of the message");
+    let mut echoed = 2_000_000;
+    while echoed < 10_000_000 {
+        let response = frame::read_frame(&mut stream).expect("Stream ended early").expect("Closed");
+        match ServerMessage::decode(&response[..]).expect("Undecodable response").message {
+            Some(server_message::Message::EchoMessage(echo)) => echoed += echo.content.len(),
+            other => panic!("Expected an EchoMessage, got {:?}", other),
+        }
+    }
+    assert_eq!(echoed, 10_000_000);
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With buffered writes a streamed echo still goes out in writes of about max_message_size, not as one 10 MB write
+#[test]
+fn test_large_message_stream_with_buffered_writes() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_message_size(1_000_000)
+            .large_message_policy(LargeMessagePolicy::Stream)
+            .buffered_writes(true)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+
+    let content = send_large_echo(&mut client);
+    let echoed: String = client
+        .receive_all(10)
+        .expect("Stream ended early")
+        .into_iter()
+        .map(|chunk| match chunk.message {
+            Some(server_message::Message::EchoMessage(echo)) => echo.content,
+            other => panic!("Expected an EchoMessage, got {:?}", other),
+        })
+        .collect();
+    assert!(echoed == content, "Reassembled echo does not match");
+    assert_eq!(server.response_writes(), 10, "The chunks weren't written one batch each");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+// Starts a server answering every echo with 2000 responses of 16 KB, more than the socket buffers hold
+fn flooding_server(
+    configure: impl FnOnce(embedded_recruitment_task::server::ServerBuilder) -> embedded_recruitment_task::server::ServerBuilder,
+) -> (Arc<Server>, JoinHandle<()>) {
+    let server = Arc::new(
+        configure(Server::builder("localhost:0"))
+            .max_pending_responses(16)
+            .handler(|_, _: &mut ConnectionContext| {
+                let response = server_message::Message::EchoMessage(EchoMessage { content: "F".repeat(16 * 1024) });
+                HandlerAction::RespondMany(vec![response; 2000])
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    (server, handle)
+}
+
+//A client that doesn't read is disconnected once its response queue is full
+#[test]
+fn test_backpressure_disconnect() {
+    let (server, handle) = flooding_server(|builder| builder.backpressure_policy(BackpressurePolicy::Disconnect));
+    let mut client = client::Client::new("localhost", server_port(&server), 2000);
+    client.connect().expect("Failed to connect to the server");
+    client
+        .send(client_message::Message::EchoMessage(EchoMessage::from("flood")))
+        .expect("Failed to send message");
+
+    // Not reading at all: the queue fills up and the server drops the connection
+    assert!(wait_for(|| server.active_client_count() == 0), "Slow reader was not disconnected");
+    let mut received = 0;
+    while client.receive().is_ok() {
+        received += 1;
+    }
+    assert!(received < 2000, "Every response was delivered despite the cap");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Under the Block policy a slow reader still gets every response, in order
+#[test]
+fn test_backpressure_block() {
+    let (server, handle) = flooding_server(|builder| builder.backpressure_policy(BackpressurePolicy::Block));
+    let mut client = client::Client::new("localhost", server_port(&server), 2000);
+    client.connect().expect("Failed to connect to the server");
+    client
+        .send(client_message::Message::EchoMessage(EchoMessage::from("flood")))
+        .expect("Failed to send message");
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(server.active_client_count(), 1, "Blocked client was disconnected");
+    for expected in 0..2000 {
+        assert_eq!(client.receive().expect("Response missing").seq, expected);
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With validate_on_connect, connect() only succeeds against an endpoint that answers the probe
+#[test]
+fn test_validate_on_connect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).validate_on_connect(true);
+    client.connect().expect("Probe against the server failed");
+    assert_eq!(client.echo("validated").expect("Echo failed"), "validated");
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+
+    // A socket that accepts but never answers fails validation within the timeout
+    let silent = std::net::TcpListener::bind("localhost:0").expect("Failed to bind");
+    let port = silent.local_addr().expect("No local address").port() as u32;
+    let mut client = client::Client::new("localhost", port, 300).validate_on_connect(true);
+    let started = Instant::now();
+    let error = client.connect().expect_err("Silent endpoint passed validation");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "Unexpected error: {}", error);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(client.echo("unvalidated").expect_err("Failed validation left a connection").kind(), ErrorKind::NotConnected);
+}
+
+//config() reflects the builder options and serializes to plain JSON
+#[test]
+fn test_server_config() {
+    let server = Server::builder("localhost:0")
+        .max_clients(7)
+        .max_connections_per_ip(2)
+        .accept_queue_capacity(3)
+        .accept_order(AcceptOrder::Lifo)
+        .require_auth(|token| token == "secret")
+        .frame_deadline(Some(Duration::from_millis(1500)))
+        .max_message_size(4096)
+        .large_message_policy(LargeMessagePolicy::Truncate(1024))
+        .backpressure_policy(BackpressurePolicy::Disconnect)
+        .build()
+        .expect("Failed to start server");
+
+    let config = server.config();
+    assert_eq!(
+        config,
+        ServerConfig {
+            address: Some(server.local_addr().expect("No local address")),
+            max_clients: 7,
+            max_connections_per_ip: Some(2),
+            accept_queue_capacity: 3,
+            evict_idle_on_capacity_ms: None,
+            reuse_port: false,
+            accept_order: AcceptOrder::Lifo,
+            max_accepts_per_iteration: 1,
+            auth_required: true,
+            frame_deadline_ms: Some(1500),
+            header_timeout_ms: None,
+            slow_handler_threshold_ms: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            max_message_size: 4096,
+            max_reassembled_size: 4096,
+            large_message_policy: LargeMessagePolicy::Truncate(1024),
+            max_pending_responses: 1024,
+            response_coalesce_window_ms: None,
+            buffered_writes: false,
+            adaptive_nodelay_threshold: None,
+            backpressure_policy: BackpressurePolicy::Disconnect,
+            write_timeout_ms: None,
+            write_retry: WriteRetryPolicy::Disconnect,
+            unknown_message_policy: UnknownMessagePolicy::Ignore,
+            expired_request_policy: ExpiredRequestPolicy::Drop,
+            draining_request_policy: DrainingRequestPolicy::Serve,
+            strict_sequencing: false,
+            echo_add_operands: false,
+            fault_injection_rate: FaultRate::default(),
+            fault_action: FaultAction::Error,
+            max_concurrent_handlers: None,
+            worker_pools: vec![],
+            idle_timeout_ms: None,
+            max_connection_lifetime_ms: None,
+            max_bytes_per_connection: None,
+            status_interval_ms: None,
+            thread_join_timeout_ms: None,
+            thread_stack_size: None,
+            admin_address: None,
+            protocol_version: 3,
+            record_dir: None,
+        }
+    );
+
+    let json = serde_json::to_value(&config).expect("Config does not serialize");
+    assert_eq!(json["max_clients"], 7);
+    assert_eq!(json["accept_order"], "Lifo");
+    assert_eq!(json["large_message_policy"]["Truncate"], 1024);
+    let text = json.to_string();
+    assert!(!text.contains("listener") && !text.contains("socket"), "Internals leaked into {}", text);
+}
+
+//A ClientMessage without a message is handled by the configured UnknownMessagePolicy
+#[test]
+fn test_unknown_message_policy() {
+    // An empty envelope encodes to nothing, so it goes out as a zero-length heartbeat frame
+    assert!(ClientMessage::default().encode_to_vec().is_empty());
+    // With an unknown field (number 100) it still decodes to `message: None`
+    let unknown = [0xA0, 0x06, 0x01];
+    assert_eq!(ClientMessage::decode(&unknown[..]).expect("Does not decode").message, None);
+
+    for policy in [UnknownMessagePolicy::Ignore, UnknownMessagePolicy::Reject, UnknownMessagePolicy::Disconnect] {
+        let server = Arc::new(
+            Server::builder("localhost:0")
+                .unknown_message_policy(policy)
+                .build()
+                .expect("Failed to start server"),
+        );
+        let handle = setup_server_thread(server.clone());
+        let mut client = client::Client::new("localhost", server_port(&server), 300);
+        client.connect().expect("Failed to connect to the server");
+
+        client.send_raw(&ClientMessage::default().encode_to_vec(), true).expect("Failed to send heartbeat");
+        client.send_raw(&unknown, true).expect("Failed to send raw frame");
+        match policy {
+            UnknownMessagePolicy::Ignore => {
+                let error = client.receive().expect_err("Ignored message was answered");
+                assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+                assert_eq!(client.echo("still open").expect("Echo failed"), "still open");
+            }
+            UnknownMessagePolicy::Reject => {
+                match client.receive_message().expect("Rejected message was not answered") {
+                    server_message::Message::Error(error) => assert_eq!(error.reason, "ClientMessage has no content"),
+                    other => panic!("Expected an Error, got {:?}", other),
+                }
+                assert_eq!(client.echo("still open").expect("Echo failed"), "still open");
+            }
+            UnknownMessagePolicy::Disconnect => {
+                let error = client.receive().expect_err("Connection stayed open");
+                assert!(matches!(error.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset));
+            }
+        }
+
+        server.stop();
+        handle.join().expect("Server thread panicked or failed to join");
+    }
+}
+
+//Messages arriving while max_concurrent_handlers calls are already running get ServerBusy
+#[test]
+fn test_max_concurrent_handlers() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_concurrent_handlers(1)
+            .handler(|message, _: &mut ConnectionContext| {
+                thread::sleep(Duration::from_millis(300));
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut first = client::Client::new("localhost", port, 1000);
+    first.connect().expect("Failed to connect to the server");
+    let mut second = client::Client::new("localhost", port, 1000);
+    second.connect().expect("Failed to connect to the server");
+
+    first
+        .send(client_message::Message::EchoMessage(EchoMessage::from("first")))
+        .expect("Failed to send message");
+    assert!(wait_for(|| server.draining_status().remaining_inflight == 1), "First request never started");
+    second
+        .send(client_message::Message::EchoMessage(EchoMessage::from("second")))
+        .expect("Failed to send message");
+
+    assert!(matches!(second.receive_message(), Ok(server_message::Message::ServerBusy(_))), "Excess request was not refused");
+    match first.receive_message().expect("First request failed") {
+        server_message::Message::EchoMessage(echo) => assert_eq!(echo.content, "first"),
+        other => panic!("Expected an EchoMessage, got {:?}", other),
+    }
+    // The permit is released once the call finishes
+    assert_eq!(second.echo("later").expect("Echo failed"), "later");
+
+    first.disconnect().expect("Failed to disconnect");
+    second.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//measure_rtt pings over the existing connection and leaves nothing behind
+#[test]
+fn test_measure_rtt() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    let stats = client.measure_rtt(20).expect("Failed to measure RTT");
+    assert!(stats.min <= stats.mean && stats.mean <= stats.max, "Inconsistent stats: {:?}", stats);
+    assert!(stats.max < Duration::from_secs(1));
+    assert_eq!(client.measure_rtt(0).expect_err("Zero samples accepted").kind(), ErrorKind::InvalidInput);
+
+    // No stray Pong ahead of the next response
+    assert_eq!(client.add(1, 1).expect("Add failed"), 2);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+// Starts a server on a mock clock and connects a client to it
+fn mock_clock_setup(
+    configure: impl FnOnce(embedded_recruitment_task::server::ServerBuilder) -> embedded_recruitment_task::server::ServerBuilder,
+) -> (Arc<MockClock>, Arc<Server>, JoinHandle<()>, client::Client) {
+    let clock = Arc::new(MockClock::new());
+    let server = Arc::new(
+        configure(Server::builder("localhost:0").clock(clock.clone()))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 1));      // Accepted, so the connection's timers start at the mock clock's origin
+    (clock, server, handle, client)
+}
+
+//Advancing the mock clock past the idle timeout closes a silent connection, activity resets the timer
+#[test]
+fn test_idle_timeout_with_mock_clock() {
+    let (clock, server, handle, mut client) = mock_clock_setup(|builder| builder.idle_timeout(Duration::from_secs(30)));
+
+    clock.advance(Duration::from_secs(29));
+    assert_eq!(client.echo("active").expect("Echo failed"), "active");
+    clock.advance(Duration::from_secs(29));
+    thread::sleep(Duration::from_millis(100));        // A few timeout polls, nothing expires
+    assert_eq!(server.active_client_count(), 1, "Connection closed before the idle timeout");
+
+    clock.advance(Duration::from_secs(2));
+    assert!(wait_for(|| server.active_client_count() == 0), "Idle connection was not closed");
+    let error = client.receive().expect_err("Idle connection still open");
+    assert!(matches!(error.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset));
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Advancing the mock clock past the lifetime closes even an active connection
+#[test]
+fn test_connection_lifetime_with_mock_clock() {
+    let (clock, server, handle, mut client) =
+        mock_clock_setup(|builder| builder.max_connection_lifetime(Duration::from_secs(60)));
+
+    for _ in 0..5 {
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(client.echo("active").expect("Echo failed"), "active");
+    }
+    clock.advance(Duration::from_secs(10));
+    assert!(wait_for(|| server.active_client_count() == 0), "Connection outlived its lifetime");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Client heartbeats are scheduled on the injected clock
+#[test]
+fn test_heartbeat_follows_mock_clock() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let clock = Arc::new(MockClock::new());
+
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).clock(clock.clone());
+    client.connect().expect("Failed to connect to the server");
+    client.enable_heartbeat(Duration::from_secs(60)).expect("Failed to enable heartbeat");
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.heartbeats_sent(), 0, "Heartbeat sent before the interval elapsed");
+
+    clock.advance(Duration::from_secs(60));
+    assert!(wait_for(|| client.heartbeats_sent() == 1), "Heartbeat did not follow the clock");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//The admin port lists and kicks data connections while the data port keeps serving echoes
+#[test]
+fn test_admin_port() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .admin_address("localhost:0")
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let admin_port = server.admin_addr().expect("No admin listener").port() as u32;
+
+    let mut first = client::Client::new("localhost", server_port(&server), 1000);
+    first.connect().expect("Failed to connect to the server");
+    let mut second = client::Client::new("localhost", server_port(&server), 1000);
+    second.connect().expect("Failed to connect to the server");
+    let mut admin = client::Client::new("localhost", admin_port, 1000);
+    admin.connect().expect("Failed to connect to the admin port");
+    assert!(wait_for(|| server.active_client_count() == 2), "Admin connection counted as a client");
+
+    // Only data connections are listed
+    let listed = match admin.send_and_receive(client_message::Message::ClientInfoRequest(ClientInfoRequest {})).expect("ClientInfoRequest failed").message {
+        Some(server_message::Message::ClientInfoResponse(response)) => response.clients.into_iter().map(|client| client.address).collect::<Vec<_>>(),
+        other => panic!("Expected a ClientInfoResponse, got {:?}", other),
+    };
+    let mut expected = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+    expected.sort();
+    assert_eq!(listed, expected.iter().map(|addr| addr.to_string()).collect::<Vec<_>>());
+
+    match admin.send_and_receive(client_message::Message::MetricsRequest(MetricsRequest {})).expect("MetricsRequest failed").message {
+        Some(server_message::Message::MetricsResponse(metrics)) => assert_eq!(metrics.active_clients, 2),
+        other => panic!("Expected a MetricsResponse, got {:?}", other),
+    }
+
+    match admin.send_and_receive(client_message::Message::Broadcast(Broadcast { content: "maintenance".to_string() })).expect("Broadcast failed").message {
+        Some(server_message::Message::BroadcastResponse(response)) => assert_eq!(response.delivered, 2),
+        other => panic!("Expected a BroadcastResponse, got {:?}", other),
+    }
+    for client in [&mut first, &mut second] {
+        let notification = client.next_notification().expect("No broadcast received");
+        assert_eq!((notification.topic.as_str(), notification.content.as_str()), ("broadcast", "maintenance"));
+    }
+
+    let kick = Kick { address: first.local_addr().unwrap().to_string() };
+    match admin.send_and_receive(client_message::Message::Kick(kick)).expect("Kick failed").message {
+        Some(server_message::Message::KickResponse(response)) => assert!(response.kicked),
+        other => panic!("Expected a KickResponse, got {:?}", other),
+    }
+    assert!(wait_for(|| server.active_client_count() == 1), "Kicked client still connected");
+    assert!(first.echo("still there?").is_err(), "Kicked client still served");
+    assert_eq!(second.echo("hello").expect("Echo failed"), "hello");
+
+    // Each port refuses the other's traffic
+    match admin.send_and_receive(client_message::Message::EchoMessage(EchoMessage::from("hello"))).expect("Echo failed").message {
+        Some(server_message::Message::Error(error)) => assert_eq!(error.reason, "Admin port only accepts admin messages"),
+        other => panic!("Expected an Error, got {:?}", other),
+    }
+    let kick = Kick { address: second.local_addr().unwrap().to_string() };
+    match second.send_and_receive(client_message::Message::Kick(kick)).expect("Kick failed").message {
+        Some(server_message::Message::Error(error)) => assert_eq!(error.reason, "Admin messages are only accepted on the admin port"),
+        other => panic!("Expected an Error, got {:?}", other),
+    }
+    assert_eq!(server.active_client_count(), 1);
+
+    admin.disconnect().expect("Failed to disconnect");
+    second.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A chunking client echoes content three times the server's frame limit, up to the reassembly limit
+#[test]
+fn test_chunked_echo() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_message_size(1000)
+            .max_reassembled_size(4000)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).chunk_echoes(1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let content = "é".repeat(1500);      // 3000 bytes, two bytes per char so chunks must split on char boundaries
+    assert_eq!(client.echo(&content).expect("Chunked echo failed"), content);
+    assert_eq!(client.echo("small").expect("Echo failed"), "small");       // Fits in one frame, sent as is
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Partial chunked echoes are bounded: a message growing past the reassembly limit is dropped, and a connection can't
+//leave more than 16 messages unfinished. Both are answered with Error
+#[test]
+fn test_chunked_echo_limits() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_message_size(1000)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    let mut chunk = |message_id: u64, index: u32, content: &str| {
+        client
+            .send(client_message::Message::EchoChunk(EchoChunk { message_id, index, content: content.to_string(), ..Default::default() }))
+            .expect("Failed to send chunk");
+    };
+
+    chunk(1, 0, &"a".repeat(600));
+    chunk(1, 1, &"a".repeat(600));
+    for message_id in 100..116 {
+        chunk(message_id, 0, "open");
+    }
+    chunk(116, 0, "one too many");
+    let errors: Vec<String> = (0..2)
+        .map(|_| match client.receive_message().expect("Missing Error") {
+            server_message::Message::Error(error) => error.reason,
+            other => panic!("Expected Error, got {:?}", other),
+        })
+        .collect();
+    assert!(errors[0].contains("exceeds the 1000 byte limit"), "Unexpected error: {}", errors[0]);
+    assert!(errors[1].contains("At most 16 chunked messages"), "Unexpected error: {}", errors[1]);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A failed split hands the client back usable, a successful one yields halves usable from separate threads
+#[test]
+fn test_split_client() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let client = client::Client::new("localhost", server_port(&server), 1000);
+    let failed = client.split().expect_err("Split an unconnected client");
+    assert_eq!(failed.error().kind(), ErrorKind::NotConnected);
+    let mut client = failed.into_client();
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("intact").expect("Echo failed"), "intact");
+
+    let (mut sender, mut receiver) = client.split().expect("Split failed");
+    let reader = thread::spawn(move || {
+        (0..3)
+            .map(|_| match receiver.receive().expect("Receive failed").message {
+                Some(server_message::Message::EchoMessage(echo)) => echo.content,
+                other => panic!("Expected an EchoMessage, got {:?}", other),
+            })
+            .collect::<Vec<_>>()
+    });
+    for content in ["one", "two", "three"] {
+        sender
+            .send(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() }))
+            .expect("Send failed");
+    }
+    assert_eq!(reader.join().expect("Receiver thread panicked"), ["one", "two", "three"]);
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A notification the client set aside while waiting for a response isn't lost by split, the receiver returns it first
+#[test]
+fn test_split_keeps_buffered_notifications() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let mut subscriber = client::Client::new("localhost", port, 1000);
+    subscriber.connect().expect("Failed to connect to the server");
+    subscriber.subscribe("news").expect("Subscribe failed");
+    let mut publisher = client::Client::new("localhost", port, 1000);
+    publisher.connect().expect("Failed to connect to the server");
+
+    assert_eq!(publisher.publish("news", "buffered").expect("Publish failed"), 1);
+    assert_eq!(subscriber.timed_echo("after").expect("Echo failed").0, "after");     // The notification arrived first and was set aside
+    let (_sender, mut receiver) = subscriber.split().expect("Split failed");
+    match receiver.receive().expect("Receive failed").message {
+        Some(server_message::Message::Notification(notification)) => assert_eq!(notification.content, "buffered"),
+        other => panic!("Expected the buffered Notification, got {:?}", other),
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//The server copies the request's send time onto the response, so the client measures end-to-end latency
+#[test]
+fn test_timed_echo_latency() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let (content, latency) = client.timed_echo("timed").expect("Timed echo failed");
+    assert_eq!(content, "timed");
+    assert!(latency > Duration::ZERO, "Latency not measured");
+    assert!(latency < Duration::from_secs(1), "Local round trip took {:?}", latency);
+
+    // Responses to unstamped requests carry no timestamp
+    let response = client
+        .send_and_receive(client_message::Message::EchoMessage(EchoMessage::from("plain")))
+        .expect("Echo failed");
+    assert_eq!(response.sent_at_unix_nanos, 0);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//shutdown(true) still serves connections waiting in the accept queue, then stops on its own
+#[test]
+fn test_shutdown_drains_accept_queue() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_clients(1)
+            .accept_queue_capacity(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut holder = client::Client::new("localhost", port, 1000);
+    holder.connect().expect("Failed to connect");
+    assert!(wait_for(|| server.active_client_count() == 1));
+    let mut queued = Vec::new();
+    for (index, name) in ["first", "second"].iter().enumerate() {
+        let mut client = client::Client::new("localhost", port, 2000);
+        client.connect().expect("Failed to connect");
+        client
+            .send(client_message::Message::EchoMessage(EchoMessage { content: name.to_string() }))
+            .expect("Failed to send");
+        assert!(wait_for(|| server.queued_connection_count() == index + 1), "Connection was not queued");
+        queued.push(client);
+    }
+
+    server.shutdown(true);
+    let mut late = client::Client::new("localhost", port, 500);
+    late.connect().expect("Failed to connect");
+    assert!(late.echo("too late").is_err(), "Connection accepted during shutdown");
+
+    // Queued connections are served in turn as the slot frees up
+    assert_eq!(holder.echo("holder").expect("Echo failed"), "holder");
+    holder.disconnect().expect("Failed to disconnect");
+    for (mut client, name) in queued.into_iter().zip(["first", "second"]) {
+        match client.receive().expect("Queued connection was not served").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, name),
+            other => panic!("Expected an EchoMessage, got {:?}", other),
+        }
+        client.disconnect().expect("Failed to disconnect");
+    }
+
+    handle.join().expect("Server thread panicked or failed to join");
+    assert!(!server.is_running(), "Server did not stop after draining its queue");
+}
+
+//A client acquired from the pool a second time reports that its request reused the pooled connection
+#[test]
+fn test_pool_reports_connection_reuse() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut pool = client::ClientPool::new("localhost", server_port(&server), 1000);
+
+    let mut client = pool.acquire();
+    assert_eq!(client.echo("first").expect("Echo failed"), "first");
+    assert!(!client.last_connection_reused(), "First request reported a reused connection");
+    pool.release(client);
+
+    let mut client = pool.acquire();
+    assert_eq!(pool.idle_count(), 0);
+    assert_eq!(client.echo("second").expect("Echo failed"), "second");
+    assert!(client.last_connection_reused(), "Pooled connection not reported as reused");
+    assert_eq!(server.active_client_count(), 1, "Pool opened a second connection");
+
+    // A reconnect opens a new connection, its first request is not a reuse
+    client.reconnect().expect("Failed to reconnect");
+    assert_eq!(client.echo("third").expect("Echo failed"), "third");
+    assert!(!client.last_connection_reused());
+    client.disconnect().expect("Failed to disconnect");
+
+    // A send that fails doesn't use up the connection's first request
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).chunk_echoes(64);
+    client.connect().expect("Failed to connect to the server");
+    let oversized = client_message::Message::EchoMessage(EchoMessage { content: "o".repeat(100) });
+    client.send(oversized).expect_err("Sent a frame over the frame size");
+    assert_eq!(client.echo("fourth").expect("Echo failed"), "fourth");
+    assert!(!client.last_connection_reused(), "A failed send counted as the connection's first request");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A server capped at version 1 answers a version 2 message with UnsupportedOperation and keeps serving
+#[test]
+fn test_unsupported_operation_for_negotiated_version() {
+    let server = Arc::new(Server::builder("localhost:0").protocol_version(1).build().expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    assert_eq!(client.hello(PROTOCOL_VERSION).expect("Hello failed"), 1);
+    let subscribe = client_message::Message::Subscribe(Subscribe { topic: "news".to_string() });
+    match client.send_and_receive(subscribe).expect("Subscribe failed").message {
+        Some(server_message::Message::UnsupportedOperation(unsupported)) => {
+            assert_eq!((unsupported.op.as_str(), unsupported.version), ("Subscribe", 1));
+        }
+        other => panic!("Expected UnsupportedOperation, got {:?}", other),
+    }
+    assert_eq!(client.echo("still served").expect("Echo failed"), "still served");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A client offering an older version gets the older feature set from a newer server
+#[test]
+fn test_hello_negotiates_lower_version() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    assert_eq!(client.hello(1).expect("Hello failed"), 1);
+    let error = client.subscribe("news").expect_err("Version 1 connection subscribed");
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A deferred server is constructed on a busy port, fails at run() and binds once the port is free
+#[test]
+fn test_deferred_bind() {
+    let holder = std::net::TcpListener::bind("localhost:0").expect("Failed to reserve a port");
+    let port = holder.local_addr().expect("No local address").port();
+    let server = Arc::new(Server::deferred(&format!("localhost:{}", port), 10));
+    assert_eq!(server.local_addr().expect_err("Deferred server bound early").kind(), ErrorKind::NotConnected);
+
+    assert_eq!(server.run().expect_err("Bound a busy port").kind(), ErrorKind::AddrInUse);
+    assert!(!server.is_running(), "Server left running after a bind error");
+
+    drop(holder);
+    let handle = setup_server_thread(server.clone());
+    assert!(wait_for(|| server.local_addr().is_ok()), "Server did not bind at run time");
+    assert_eq!(server.local_addr().unwrap().port(), port);
+    let mut client = client::Client::new("localhost", port as u32, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("deferred").expect("Echo failed"), "deferred");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A sum of two values near i64::MAX comes back exact through the big-number encoding
+#[test]
+fn test_add_big_result() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let (a, b) = (i64::MAX - 1, i64::MAX - 2);
+    assert_eq!(client.add(a, b).expect("Add failed"), a as i128 + b as i128);
+    assert_eq!(client.add(i64::MIN, -1).expect("Add failed"), i64::MIN as i128 - 1);
+    assert_eq!(client.add(40, 2).expect("Add failed"), 42);        // Fits, sent as a plain int64
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Two bytes of a length prefix followed by a close are logged as a truncated header, not an error
+#[test]
+fn test_truncated_header_disconnect() {
+    logger::init();
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    let addr = client.local_addr().expect("No local address").to_string();
+
+    assert!(wait_for(|| server.active_client_count() == 1));
+
+    client.send_raw(&[0, 0], false).expect("Failed to send partial header");
+    client.disconnect().expect("Failed to disconnect");
+    assert!(wait_for(|| server.active_client_count() == 0), "Server kept the connection open");
+    assert!(
+        logger::contains(&["truncated frame header", &addr, "2 of 4 bytes"]),
+        "Truncated header was not logged"
+    );
+    assert!(!logger::contains(&["Error handling client", &addr]), "Truncated header logged as an error");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Backpressure on a slow reader is reported with the connection and the action the policy took
+#[test]
+fn test_backpressure_events() {
+    for policy in [BackpressurePolicy::Block, BackpressurePolicy::Disconnect] {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let (server, handle) = flooding_server(|builder| {
+            builder
+                .backpressure_policy(policy)
+                .on_event(move |event| recorded.lock().unwrap().push(event.clone()))
+        });
+        let mut client = client::Client::new("localhost", server_port(&server), 2000);
+        client.connect().expect("Failed to connect to the server");
+        client
+            .send(client_message::Message::EchoMessage(EchoMessage::from("flood")))
+            .expect("Failed to send message");
+
+        let expected = ServerEvent::Backpressure { connection: client.local_addr().unwrap(), action: policy };
+        assert!(wait_for(|| events.lock().unwrap().contains(&expected)), "No {:?} backpressure event", policy);
+
+        client.disconnect().expect("Failed to disconnect");
+        server.stop();
+        handle.join().expect("Server thread panicked or failed to join");
+    }
+}
+
+// Minimal SOCKS5 proxy serving one connection, returns its address and a thread yielding the requested host and port
+#[cfg(feature = "proxy")]
+fn socks5_stub() -> (std::net::SocketAddr, JoinHandle<(String, u16)>) {
+    let listener = std::net::TcpListener::bind("localhost:0").expect("Failed to bind proxy");
+    let addr = listener.local_addr().expect("No proxy address");
+    let handle = thread::spawn(move || {
+        let (mut client, _) = listener.accept().expect("Proxy accept failed");
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).expect("Missing greeting");
+        assert_eq!(greeting, [0x05, 0x01, 0x00]);
+        client.write_all(&[0x05, 0x00]).unwrap();
+
+        let mut request = [0u8; 5];
+        client.read_exact(&mut request).expect("Missing CONNECT");
+        assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x03], "Expected a CONNECT by host name");
+        let mut host = vec![0u8; request[4] as usize];
+        client.read_exact(&mut host).unwrap();
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).unwrap();
+        let (host, port) = (String::from_utf8(host).unwrap(), u16::from_be_bytes(port));
+
+        let server = TcpStream::connect((host.as_str(), port)).expect("Proxy failed to reach the server");
+        client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).unwrap();
+        // Relay both directions until either side closes
+        let (mut upstream, mut downstream) = (server.try_clone().unwrap(), client.try_clone().unwrap());
+        let relay = thread::spawn(move || {
+            let _ = std::io::copy(&mut downstream, &mut upstream);
+            let _ = upstream.shutdown(std::net::Shutdown::Write);
+        });
+        let (mut server, mut client) = (server, client);
+        let _ = std::io::copy(&mut server, &mut client);
+        let _ = client.shutdown(std::net::Shutdown::Write);
+        relay.join().unwrap();
+        (host, port)
+    });
+    (addr, handle)
+}
+
+//A client configured with a SOCKS5 proxy reaches the server through it and completes an echo
+#[cfg(feature = "proxy")]
+#[test]
+fn test_socks5_proxy() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let (proxy, stub) = socks5_stub();
+
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).socks5_proxy(proxy);
+    client.connect().expect("Failed to connect through the proxy");
+    assert_eq!(client.echo("proxied").expect("Echo through the proxy failed"), "proxied");
+    client.disconnect().expect("Failed to disconnect");
+
+    assert_eq!(stub.join().expect("Proxy thread panicked"), ("localhost".to_string(), server_port(&server) as u16));
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+// Answers one request with a burst of 100 small echoes and returns the socket writes the server needed
+fn writes_for_burst(configure: impl FnOnce(embedded_recruitment_task::server::ServerBuilder) -> embedded_recruitment_task::server::ServerBuilder) -> usize {
+    let server = Arc::new(
+        configure(Server::builder("localhost:0"))
+            .handler(|_, _: &mut ConnectionContext| HandlerAction::RespondMany(vec![server_message::Message::EchoMessage(EchoMessage::from("x")); 100]))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    client.set_nodelay(true).expect("Failed to set TCP_NODELAY");
+    client
+        .send(client_message::Message::EchoMessage(EchoMessage::from("burst")))
+        .expect("Failed to send message");
+    assert_eq!(client.receive_all(100).expect("Burst incomplete").len(), 100);
+    let writes = server.response_writes();
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    writes
+}
+
+//Coalescing writes a burst of small responses in a handful of writes instead of one each
+#[test]
+fn test_response_coalescing() {
+    assert_eq!(writes_for_burst(|builder| builder), 100);
+    let coalesced = writes_for_burst(|builder| builder.response_coalesce_window(Duration::from_millis(20)));
+    assert!(coalesced < 10, "Coalescing still took {} writes", coalesced);
+}
+
+//Buffered writes send the responses a handler returns together in a single write
+#[test]
+fn test_buffered_writes() {
+    assert_eq!(writes_for_burst(|builder| builder.buffered_writes(true)), 1);
+}
+
+//A multi-megabyte file round-trips byte for byte as a streamed binary echo
+#[test]
+fn test_file_echo_round_trip() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+
+    let dir = std::env::temp_dir();
+    let source = dir.join(format!("echo_source_{}.bin", std::process::id()));
+    let target = dir.join(format!("echo_target_{}.bin", std::process::id()));
+    // Pseudo-random bytes, so misordered or dropped pieces can't go unnoticed
+    let mut state = 0x2545_f491_u32;
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 123)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    std::fs::write(&source, &data).expect("Failed to write the source file");
+
+    client.send_file(&source).expect("Failed to send the file");
+    assert_eq!(client.receive_to_file(&target).expect("Failed to receive the file"), data.len() as u64);
+    assert!(std::fs::read(&target).expect("Failed to read the echoed file") == data, "Echoed file differs");
+    assert_eq!(client.echo("after").expect("Echo failed"), "after");
+
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&target);
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A file of more pieces than the server's response queue holds round-trips on a split client: the receiver drains
+//the echoed pieces while the sender is still streaming, so the server never blocks for good
+#[test]
+fn test_file_echo_on_split_client() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_pending_responses(4)
+            .socket_buffer_sizes(None, Some(64 * 1024))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+
+    let dir = std::env::temp_dir();
+    let source = dir.join(format!("split_source_{}.bin", std::process::id()));
+    let target = dir.join(format!("split_target_{}.bin", std::process::id()));
+    let data: Vec<u8> = (0..64 * 64 * 1024).map(|i: usize| (i % 251) as u8).collect();      // 64 pieces
+    std::fs::write(&source, &data).expect("Failed to write the source file");
+
+    let (mut sender, mut receiver) = client.split().expect("Split failed");
+    let receiving = {
+        let target = target.clone();
+        thread::spawn(move || receiver.receive_to_file(&target))
+    };
+    sender.send_file(&source).expect("Failed to send the file");
+    assert_eq!(receiving.join().expect("Receiver panicked").expect("Failed to receive the file"), data.len() as u64);
+    assert!(std::fs::read(&target).expect("Failed to read the echoed file") == data, "Echoed file differs");
+
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&target);
+    drop(sender);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//for_each_client calls back without holding the registry, so the server keeps accepting during a slow callback
+#[test]
+fn test_for_each_client_does_not_block_accepts() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let mut clients: Vec<client::Client> = (0..2)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            client.connect().expect("Failed to connect to the server");
+            client
+        })
+        .collect();
+    assert!(wait_for(|| server.active_client_count() == 2));
+
+    let mut visited = Vec::new();
+    server.for_each_client(|info| {
+        visited.push(info.clone());
+        if visited.len() == 1 {
+            // Accepted and served while the iteration is still in this callback
+            let mut late = client::Client::new("localhost", port, 1000);
+            late.connect().expect("Failed to connect during iteration");
+            assert_eq!(late.echo("accepted").expect("Echo during iteration failed"), "accepted");
+            clients.push(late);
+        }
+        thread::sleep(Duration::from_millis(100));
+    });
+    let mut expected: Vec<ConnectionInfo> = clients[..2]
+        .iter()
+        .map(|client| ConnectionInfo { address: client.local_addr().unwrap() })
+        .collect();
+    expected.sort_by_key(|info| info.address);
+    assert_eq!(visited, expected, "Iteration should cover the snapshot taken when it started");
+    assert_eq!(server.active_client_count(), 3);
+
+    for mut client in clients {
+        client.disconnect().expect("Failed to disconnect");
+    }
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//disconnect() tells a normal close from one where the server had already gone away
+#[test]
+fn test_disconnect_outcome() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("open").expect("Echo failed"), "open");
+    assert_eq!(client.disconnect().expect("Failed to disconnect"), client::DisconnectOutcome::Clean);
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 1));
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    assert_eq!(client.disconnect().expect("Failed to disconnect"), client::DisconnectOutcome::AlreadyClosed);
+}
+
+//A connection sending many small frames gets TCP_NODELAY, one sending large frames keeps Nagle on
+#[test]
+fn test_adaptive_nodelay() {
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .adaptive_nodelay(64)
+            .on_event(move |event| recorded.lock().unwrap().push(event.clone()))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut bulk = client::Client::new("localhost", port, 1000);
+    bulk.connect().expect("Failed to connect to the server");
+    let mut chatty = client::Client::new("localhost", port, 1000);
+    chatty.connect().expect("Failed to connect to the server");
+    for i in 0..32 {
+        assert_eq!(bulk.echo(&"B".repeat(256)).expect("Echo failed").len(), 256);
+        assert_eq!(chatty.echo(&i.to_string()).expect("Echo failed"), i.to_string());
+    }
+
+    let nodelay = |client: &client::Client| ServerEvent::NodelayEnabled { connection: client.local_addr().unwrap() };
+    let events = events.lock().unwrap();
+    assert_eq!(events.iter().filter(|event| **event == nodelay(&chatty)).count(), 1, "Small messages did not enable nodelay once");
+    assert!(!events.contains(&nodelay(&bulk)), "Large messages enabled nodelay");
+    drop(events);
+
+    bulk.disconnect().expect("Failed to disconnect");
+    chatty.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//receive_into reuses the caller's buffer, it stops growing once it fits the largest reply
+#[test]
+fn test_receive_into_reuses_buffer() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let content = "reuse".repeat(20);
+    let request = client_message::Message::EchoMessage(EchoMessage { content: content.clone() });
+    let mut buf = Vec::new();
+    let mut warm_capacity = 0;
+    const WARMUP: usize = 200;
+    for i in 0..1000 {
+        client.send(request.clone()).expect("Failed to send message");
+        let len = client.receive_into(&mut buf).expect("Failed to receive message");
+        assert_eq!(len, buf.len());
+        let reply = ServerMessage::decode(&buf[..]).expect("Failed to decode reply");
+        match reply.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+        if i < WARMUP {
+            warm_capacity = buf.capacity();         // Replies grow by a byte once seq needs a longer varint
+        } else {
+            assert_eq!(buf.capacity(), warm_capacity, "Buffer reallocated on receive {}", i);
+        }
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//receive_into checks what it reads like receive(): a rejection at capacity and a message the validator refuses fail
+#[test]
+fn test_receive_into_checks_messages() {
+    let server = Arc::new(Server::new("localhost:0", 1).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let mut client = client::Client::new("localhost", port, 1000).response_validator(|message| match &message.message {
+        Some(server_message::Message::EchoMessage(echo)) if echo.content == "bad" => Err("bad echo".to_string()),
+        _ => Ok(()),
+    });
+    client.connect().expect("Failed to connect to the server");
+    let mut buf = Vec::new();
+    client.send(client_message::Message::EchoMessage(EchoMessage::from("bad"))).expect("Failed to send message");
+    let error = client.receive_into(&mut buf).expect_err("The validator was skipped");
+    assert_eq!(client::ResponseRejected::find(&error).map(|rejected| rejected.reason), Some("bad echo".to_string()));
+    client.send(client_message::Message::EchoMessage(EchoMessage::from("good"))).expect("Failed to send message");
+    assert_eq!(client.receive_into(&mut buf).expect("Failed to receive message"), buf.len());
+
+    assert!(wait_for(|| server.active_client_count() == 1));
+    let mut extra = client::Client::new("localhost", port, 1000);
+    extra.connect().expect("The TCP handshake completes in the kernel");
+    let error = extra.receive_into(&mut buf).expect_err("A full server served the extra client");
+    assert_eq!(client::ServerAtCapacity::find(&error), Some(client::ServerAtCapacity { max_clients: 1 }), "Unexpected error: {}", error);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//At capacity with evict_idle_on_capacity, a new connection closes the longest-idle one instead of being refused, but
+//only once that one has been idle for the minimum idle time
+#[test]
+fn test_evict_idle_on_capacity() {
+    const MIN_IDLE: Duration = Duration::from_millis(300);
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_clients(2)
+            .evict_idle_on_capacity(MIN_IDLE)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().evict_idle_on_capacity_ms, Some(300));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut idle = client::Client::new("localhost", port, 1000);
+    idle.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 1));
+    let mut busy = client::Client::new("localhost", port, 1000);
+    busy.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 2));
+
+    // Both connections are younger than the minimum idle time, so there is nothing to evict
+    let mut early = client::Client::new("localhost", port, 1000);
+    early.connect().expect("The TCP handshake completes in the kernel");
+    let error = early.receive().expect_err("A full server served the extra client");
+    assert_eq!(client::ServerAtCapacity::find(&error), Some(client::ServerAtCapacity { max_clients: 2 }), "Unexpected error: {}", error);
+
+    thread::sleep(MIN_IDLE);
+    assert_eq!(busy.echo("still here").expect("Echo failed"), "still here");
+
+    let mut newcomer = client::Client::new("localhost", port, 1000);
+    newcomer.connect().expect("Failed to connect to the server");
+    assert_eq!(newcomer.echo("made room").expect("The new connection was not served"), "made room");
+    assert!(idle.receive().is_err(), "The idle connection was not evicted");
+    assert_eq!(busy.echo("not evicted").expect("The busy connection was evicted"), "not evicted");
+    assert_eq!(server.active_client_count(), 2);
+
+    busy.disconnect().expect("Failed to disconnect");
+    newcomer.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A client that stops reading long enough for a response write to time out gets the whole response once it reads again
+//with WriteRetryPolicy::Retry, and is disconnected with the default policy
+#[test]
+fn test_write_retry_after_timeout() {
+    const REPLY_SIZE: usize = 16 * 1024 * 1024;        // More than the socket buffers hold while the client isn't reading
+    for (policy, delivered) in [(WriteRetryPolicy::Retry(Duration::from_secs(5)), true), (WriteRetryPolicy::Disconnect, false)] {
+        let server = Arc::new(
+            Server::builder("localhost:0")
+                .write_timeout(Duration::from_millis(50))
+                .write_retry(policy)
+                .handler(|_, _: &mut ConnectionContext| HandlerAction::Respond(server_message::Message::EchoMessage(EchoMessage { content: "W".repeat(REPLY_SIZE) })))
+                .build()
+                .expect("Failed to start server"),
+        );
+        let handle = setup_server_thread(server.clone());
+        let mut client = client::Client::new("localhost", server_port(&server), 2000);
+        client.connect().expect("Failed to connect to the server");
+        client
+            .send(client_message::Message::EchoMessage(EchoMessage::from("slow")))
+            .expect("Failed to send message");
+
+        thread::sleep(Duration::from_millis(400));      // Slow reader: several write timeouts pass before it reads
+        match client.receive_message() {
+            Ok(server_message::Message::EchoMessage(echo)) if delivered => assert_eq!(echo.content.len(), REPLY_SIZE),
+            Err(_) if !delivered => {}
+            other => panic!("Unexpected outcome with {:?}: {:?}", policy, other.map(|_| ())),
+        }
+
+        let _ = client.disconnect();
+        server.stop();
+        handle.join().expect("Server thread panicked or failed to join");
+    }
+}
+
+//Server::bind takes a SocketAddr, so the listener family is exactly the one asked for
+#[test]
+fn test_bind_socket_addr() {
+    let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = Arc::new(Server::bind(addr, 10).expect("Failed to start server"));
+    let bound = server.local_addr().expect("Failed to read server address");
+    assert_eq!(bound.ip(), addr.ip());
+    assert_ne!(bound.port(), 0);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("127.0.0.1", bound.port() as u32, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("bound").expect("Echo failed"), "bound");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A server bound to localhost is reachable over both loopback families, whichever one the client's resolver picks
+#[test]
+fn test_localhost_binds_both_families() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let addrs = server.local_addrs().expect("Failed to read server addresses");
+    assert!(addrs.iter().all(|addr| addr.port() as u32 == port), "Listeners on different ports: {:?}", addrs);
+
+    for ip in ["127.0.0.1", "::1"] {
+        if !addrs.iter().any(|addr| addr.ip().to_string() == ip) {
+            // Only allowed when the host has no such loopback, e.g. IPv6 is disabled
+            let error = std::net::TcpListener::bind((ip, 0)).expect_err("The server skipped a loopback address the host has");
+            assert_eq!(error.kind(), ErrorKind::AddrNotAvailable, "Unexpected error binding {}: {}", ip, error);
+            continue;
+        }
+        let mut client = client::Client::new(ip, port, 1000);
+        client.connect().unwrap_or_else(|e| panic!("Failed to connect via {}: {}", ip, e));
+        assert_eq!(client.echo(ip).expect("Echo failed"), ip);
+        client.disconnect().expect("Failed to disconnect");
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A response validator turns a message it rejects into an error, other messages pass through
+#[test]
+fn test_response_validator() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).response_validator(|message| match &message.message {
+        Some(server_message::Message::AddResponse(add)) if add.result < 0 => Err(format!("negative sum {}", add.result)),
+        _ => Ok(()),
+    });
+    client.connect().expect("Failed to connect to the server");
+
+    assert_eq!(client.add(2, 3).expect("Valid AddResponse was rejected"), 5);
+    let error = client.add(-10, 3).expect_err("Negative AddResponse was accepted");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("negative sum -7"), "Unexpected error: {}", error);
+    assert_eq!(client::ResponseRejected::find(&error).map(|rejected| rejected.reason), Some("negative sum -7".to_string()));
+    assert_eq!(client.echo("still valid").expect("Echo failed"), "still valid");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A rejected response isn't retried: the server already handled the request, so a non-idempotent one runs exactly once
+#[test]
+fn test_rejected_response_not_retried() {
+    let adds = Arc::new(AtomicUsize::new(0));
+    let counted = adds.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                if matches!(message, client_message::Message::AddRequest(_)) {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000)
+        .max_retries(5)
+        .response_validator(|message| match &message.message {
+            Some(server_message::Message::AddResponse(_)) => Err("sums are rejected".to_string()),
+            _ => Ok(()),
+        });
+    client.connect().expect("Failed to connect to the server");
+
+    let error = client.add(1, 2).expect_err("Rejected AddResponse was accepted");
+    assert!(client::ResponseRejected::find(&error).is_some(), "Unexpected error: {}", error);
+    assert_eq!(client.echo("after").expect("Echo failed"), "after");      // Nothing else was sent or left unread
+    assert_eq!(adds.load(Ordering::SeqCst), 1, "The rejected add was sent again");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A replacement server binds the same port with SO_REUSEPORT and inherits the old listener, the old one hands off and
+//drains: every connection made during the overlap is served and the old server's open connection keeps working
+#[cfg(unix)]
+#[test]
+fn test_listener_hand_off() {
+    let old = Arc::new(Server::builder("127.0.0.1:0").reuse_port(true).build().expect("Failed to start server"));
+    let old_handle = setup_server_thread(old.clone());
+    let port = server_port(&old);
+    let mut long_lived = client::Client::new("127.0.0.1", port, 1000);
+    long_lived.connect().expect("Failed to connect to the server");
+    assert_eq!(long_lived.echo("before").expect("Echo failed"), "before");
+
+    let mut builder = Server::builder(&format!("127.0.0.1:{}", port)).reuse_port(true);
+    for listener in old.try_clone_listeners().expect("Failed to duplicate the listener") {
+        builder = builder.inherit_listener(listener);
+    }
+    let new = Arc::new(builder.build().expect("Failed to bind the port a second time"));
+    let new_handle = setup_server_thread(new.clone());
+
+    let connect_and_echo = |i: usize| {
+        let mut client = client::Client::new("127.0.0.1", port, 1000);
+        client.connect().unwrap_or_else(|e| panic!("Connection {} was refused: {}", i, e));
+        assert_eq!(client.echo(&i.to_string()).unwrap_or_else(|e| panic!("Connection {} was not served: {}", i, e)), i.to_string());
+        client.disconnect().expect("Failed to disconnect");
+    };
+    (0..20).for_each(connect_and_echo);         // Both servers accepting
+    old.hand_off();
+    (20..60).for_each(connect_and_echo);        // Only the new one accepting, on both sockets
+    assert_eq!(long_lived.echo("after").expect("The old server dropped its connection"), "after");
+    assert_eq!(old.draining_status().remaining_connections, 1);
+
+    long_lived.disconnect().expect("Failed to disconnect");
+    assert!(wait_for(|| old.draining_status().remaining_connections == 0));
+    old.stop();
+    old_handle.join().expect("Server thread panicked or failed to join");
+    (60..70).for_each(connect_and_echo);        // The inherited socket outlives the old server
+    new.stop();
+    new_handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A Cancel stops a running stream: it ends with a cancelled StreamEnd and no item for that id follows
+#[test]
+fn test_cancel_stream() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    const COUNT: u32 = 1000;
+    client.start_stream(7, "tick", COUNT, Duration::from_millis(10)).expect("Failed to start the stream");
+    for expected in 0..3 {
+        match client.receive_message().expect("Missing stream item") {
+            server_message::Message::StreamItem(item) => assert_eq!((item.request_id, item.index, item.content.as_str()), (7, expected, "tick")),
+            other => panic!("Expected StreamItem, got {:?}", other),
+        }
+    }
+
+    let cancelled_at = Instant::now();
+    client.cancel(7).expect("Failed to send Cancel");
+    let mut items_after_cancel = 0;
+    loop {
+        match client.receive_message().expect("Stream did not end after Cancel") {
+            server_message::Message::StreamItem(_) => items_after_cancel += 1,      // Already on the way
+            server_message::Message::StreamEnd(end) => {
+                assert_eq!(end.request_id, 7);
+                assert!(end.cancelled, "Stream reported completion instead of cancellation");
+                break;
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+    assert!(cancelled_at.elapsed() < Duration::from_millis(500), "Cancel took {:?}", cancelled_at.elapsed());
+    assert!(items_after_cancel <= 2, "{} items arrived after the Cancel", items_after_cancel);
+    // Nothing more for that id, the connection still answers
+    assert_eq!(client.echo("after cancel").expect("Echo failed"), "after cancel");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A connection runs at most 8 streams at once and may not ask for unbounded ones, a Cancel ends a stream waiting out
+//a long interval right away
+#[test]
+fn test_stream_limits() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let long = Duration::from_secs(30);
+    for id in 1..=8 {
+        client.start_stream(id, "slow", 2, long).expect("Failed to start the stream");
+    }
+    client.start_stream(9, "one too many", 2, long).expect("Failed to start the stream");
+    client.start_stream(10, "too long", 2, Duration::from_secs(3600)).expect("Failed to start the stream");
+    let mut first_items = 0;
+    let mut errors = Vec::new();
+    while first_items < 8 || errors.len() < 2 {
+        match client.receive_message().expect("Missing stream item or error") {
+            server_message::Message::StreamItem(item) => {
+                assert_eq!(item.index, 0);
+                first_items += 1;
+            }
+            server_message::Message::Error(error) => errors.push(error.reason),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+    assert!(errors[0].contains("At most 8 streams"), "Unexpected error: {}", errors[0]);
+    assert!(errors[1].contains("limited to 100000 items"), "Unexpected error: {}", errors[1]);
+
+    let cancelled_at = Instant::now();
+    client.cancel(1).expect("Failed to send Cancel");
+    match client.receive_message().expect("Stream did not end after Cancel") {
+        server_message::Message::StreamEnd(end) => assert_eq!((end.request_id, end.cancelled), (1, true)),
+        other => panic!("Expected StreamEnd, got {:?}", other),
+    }
+    assert!(cancelled_at.elapsed() < Duration::from_millis(500), "Cancel took {:?}", cancelled_at.elapsed());
+
+    // The cancelled stream's slot is free again
+    client.start_stream(11, "replacement", 1, long).expect("Failed to start the stream");
+    match client.receive_message().expect("Replacement stream did not start") {
+        server_message::Message::StreamItem(item) => assert_eq!(item.request_id, 11),
+        other => panic!("Expected StreamItem, got {:?}", other),
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A running stream holds a handler permit until it ends, so max_concurrent_handlers bounds stream threads too
+#[test]
+fn test_streams_hold_handler_permits() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_concurrent_handlers(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    client.start_stream(1, "held", 2, Duration::from_secs(30)).expect("Failed to start the stream");
+    assert!(matches!(client.receive_message().expect("Missing stream item"), server_message::Message::StreamItem(_)));
+    client.start_stream(2, "second", 2, Duration::from_secs(30)).expect("Failed to start the stream");
+    assert!(matches!(client.receive_message().expect("Missing response"), server_message::Message::ServerBusy(_)));
+
+    client.cancel(1).expect("Failed to send Cancel");
+    assert!(matches!(client.receive_message().expect("Missing StreamEnd"), server_message::Message::StreamEnd(_)));
+    // The permit is released right after the StreamEnd was queued
+    let freed = (0..100).any(|_| {
+        client.send(client_message::Message::EchoMessage(EchoMessage::from("free"))).expect("Failed to send echo");
+        let echoed = matches!(client.receive_message().expect("Missing response"), server_message::Message::EchoMessage(_));
+        if !echoed {
+            thread::sleep(Duration::from_millis(10));
+        }
+        echoed
+    });
+    assert!(freed, "The ended stream kept its permit");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Retry backoffs double per attempt and are spread over ±jitter_fraction, reproducibly for a given seed
+#[test]
+fn test_retry_backoff_jitter() {
+    let base = Duration::from_millis(100);
+    let mut plain = client::Client::new("localhost", 0, 1000).retry_backoff(base);
+    assert_eq!((1..=4).map(|attempt| plain.backoff(attempt)).collect::<Vec<_>>(), [100, 200, 400, 800].map(Duration::from_millis));
+
+    let jittered = || client::Client::new("localhost", 0, 1000).retry_backoff(base).jitter_fraction(0.2).jitter_seed(42);
+    let mut client = jittered();
+    let mut first_retries = Vec::new();
+    for _ in 0..50 {
+        let backoff = client.backoff(1);
+        assert!(backoff >= base.mul_f64(0.8) && backoff <= base.mul_f64(1.2), "{:?} is outside ±20% of {:?}", backoff, base);
+        first_retries.push(backoff);
+    }
+    first_retries.dedup();
+    assert!(first_retries.len() > 40, "Jitter barely varies: {:?}", first_retries);
+    for attempt in 1..=4 {
+        let expected = base * (1 << (attempt - 1));
+        let backoff = client.backoff(attempt);
+        assert!(backoff >= expected.mul_f64(0.8) && backoff <= expected.mul_f64(1.2), "Attempt {}: {:?}", attempt, backoff);
+    }
+
+    let (mut a, mut b) = (jittered(), jittered());
+    assert_eq!((1..=8).map(|n| a.backoff(n)).collect::<Vec<_>>(), (1..=8).map(|n| b.backoff(n)).collect::<Vec<_>>());
+
+    // A NaN fraction is no jitter, and a huge base saturates instead of overflowing
+    let mut nan = client::Client::new("localhost", 0, 1000).retry_backoff(base).jitter_fraction(f64::NAN);
+    assert_eq!(nan.backoff(3), base * 4);
+    let mut huge = client::Client::new("localhost", 0, 1000).retry_backoff(Duration::from_secs(u64::MAX / 4)).jitter_fraction(1.0);
+    let longest = (1..=20).map(|attempt| huge.backoff(attempt)).max().unwrap();
+    assert!(longest > Duration::from_secs(u64::MAX / 4), "Backoff didn't grow: {:?}", longest);
+}
+
+//Slow AddRequests queue up in their own single-thread pool while echoes keep being answered from theirs
+#[test]
+fn test_worker_pools_by_message_type() {
+    const SLOW: Duration = Duration::from_millis(200);
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let handled_on = threads.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                if matches!(message, client_message::Message::AddRequest(_)) {
+                    thread::sleep(SLOW);          // Expensive operation
+                }
+                let thread = thread::current().name().unwrap_or_default().to_string();
+                handled_on.lock().unwrap().push((message_kind(&message), thread));
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .worker_pool(&["AddRequest"], 1)
+            .worker_pool(&["EchoMessage"], 1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(
+        server.config().worker_pools,
+        vec![
+            WorkerPoolConfig { message_types: vec!["AddRequest".to_string()], threads: 1 },
+            WorkerPoolConfig { message_types: vec!["EchoMessage".to_string()], threads: 1 },
+        ]
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let started = Instant::now();
+    let flood: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = client::Client::new("localhost", port, 5000);
+                client.connect().expect("Failed to connect to the server");
+                assert_eq!(client.add(i, 1).expect("AddRequest failed"), i as i128 + 1);
+                client.disconnect().expect("Failed to disconnect");
+            })
+        })
+        .collect();
+
+    let mut echo = client::Client::new("localhost", port, 1000);
+    echo.connect().expect("Failed to connect to the server");
+    thread::sleep(Duration::from_millis(50));     // The flood is being handled
+    for i in 0..10 {
+        let sent = Instant::now();
+        assert_eq!(echo.echo(&i.to_string()).expect("Echo failed"), i.to_string());
+        assert!(sent.elapsed() < Duration::from_millis(100), "Echo {} waited {:?} behind the AddRequests", i, sent.elapsed());
+    }
+
+    for client in flood {
+        client.join().expect("Flooding client panicked");
+    }
+    assert!(started.elapsed() >= SLOW * 4, "The AddRequest pool ran more than one at a time");
+    let threads = threads.lock().unwrap().clone();
+    assert_eq!(threads.len(), 14);
+    for (kind, thread) in threads {
+        assert_eq!(thread, format!("worker-pool-{}", kind), "{} handled outside its pool", kind);
+    }
+    echo.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+// Variant name of the messages the pool tests route
+fn message_kind(message: &client_message::Message) -> &'static str {
+    match message {
+        client_message::Message::AddRequest(_) => "AddRequest",
+        client_message::Message::EchoMessage(_) => "EchoMessage",
+        _ => "other",
+    }
+}
+
+//A handler that panics on a pool thread fails its message with an Error, the thread lives on to handle the next one
+#[test]
+fn test_worker_pool_survives_handler_panic() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|message, _: &mut ConnectionContext| {
+                if matches!(message, client_message::Message::EchoMessage(ref echo) if echo.content == "panic") {
+                    panic!("Handler failure");
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .worker_pool(&["EchoMessage"], 1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    client.send(client_message::Message::EchoMessage(EchoMessage::from("panic"))).expect("Failed to send");
+    let response = client.receive_message().expect("No answer to the panicking echo");
+    assert_eq!(response, server_message::Message::Error(Error { reason: "Internal error".to_string() }));
+    assert_eq!(server.handler_thread_count(), 1, "The pool lost its thread");
+    assert_eq!(client.echo("after").expect("Echo failed"), "after");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A worker pool for a message type that doesn't exist, a type in two pools or a pool without threads is a build error
+//rather than a pool that never runs
+#[test]
+fn test_worker_pool_rejects_unknown_message_type() {
+    let error = Server::builder("localhost:0")
+        .worker_pool(&["AddRequest", "Echo"], 1)
+        .build()
+        .err()
+        .expect("Built a pool for an unknown message type");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(error.to_string().contains("Echo"), "Unexpected error: {}", error);
+
+    let error = Server::builder("localhost:0")
+        .worker_pool(&["AddRequest", "EchoMessage"], 1)
+        .worker_pool(&["EchoMessage"], 2)
+        .build()
+        .err()
+        .expect("Built two pools for the same message type");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(error.to_string().contains("EchoMessage assigned to two"), "Unexpected error: {}", error);
+
+    let error = Server::builder("localhost:0")
+        .worker_pool(&["AddRequest"], 0)
+        .build()
+        .err()
+        .expect("Built a pool without threads");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+//The offline codec matches the live path: send() writes the bytes encode_client_message() returns, and a server's raw
+//response bytes decode with decode_server_message()
+#[test]
+fn test_offline_codec_matches_the_wire() {
+    let request = client_message::Message::EchoMessage(EchoMessage::from("offline"));
+
+    // What send() puts on the wire
+    let capture = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let mut client = client::Client::new("127.0.0.1", capture.local_addr().unwrap().port() as u32, 1000);
+    client.connect().expect("Failed to connect");
+    let (mut peer, _) = capture.accept().expect("Failed to accept");
+    client.send(request.clone()).expect("Failed to send message");
+    let expected = frame::encode_client_message(&request).expect("Failed to encode");
+    let mut sent = vec![0u8; expected.len()];
+    peer.read_exact(&mut sent).expect("Failed to read the sent frame");
+    assert_eq!(sent, expected);
+    drop(client);
+
+    // Raw bytes through a live server and back
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut raw = TcpStream::connect(("localhost", server_port(&server) as u16)).expect("Failed to connect to the server");
+    raw.write_all(&frame::encode_client_message(&request).expect("Failed to encode")).expect("Failed to write");
+    let mut header = [0u8; frame::HEADER_LEN];
+    raw.read_exact(&mut header).expect("Missing response header");
+    let mut response = header.to_vec();
+    response.resize(frame::HEADER_LEN + u32::from_be_bytes(header) as usize, 0);
+    raw.read_exact(&mut response[frame::HEADER_LEN..]).expect("Missing response body");
+    let decoded = frame::decode_server_message(&response).expect("Failed to decode the response");
+    assert_eq!(decoded.message, Some(server_message::Message::EchoMessage(EchoMessage::from("offline"))));
+
+    drop(raw);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A recorded echo and add session, replayed against a fresh server, gets byte-identical responses
+#[test]
+fn test_record_and_replay() {
+    let dir = std::env::temp_dir().join(format!("recordings_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create the recording directory");
+    let recorded = Arc::new(Server::builder("localhost:0").record_traffic(&dir).build().expect("Failed to start server"));
+    let handle = setup_server_thread(recorded.clone());
+    let mut client = client::Client::new("localhost", server_port(&recorded), 1000);
+    client.connect().expect("Failed to connect to the server");
+    let path = recording::recording_path(&dir, client.local_addr().unwrap(), 0);      // The server's first connection
+    assert_eq!(client.echo("recorded").expect("Echo failed"), "recorded");
+    assert_eq!(client.add(20, 22).expect("Add failed"), 42);
+    assert_eq!(client.echo("again").expect("Echo failed"), "again");
+    client.disconnect().expect("Failed to disconnect");
+    assert!(wait_for(|| recorded.active_client_count() == 0));
+    recorded.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+
+    let records = recording::read_recording(&path).expect("Failed to read the recording");
+    let frames = |direction| records.iter().filter(|record| record.direction == direction).map(|record| record.frame.clone()).collect::<Vec<_>>();
+    let (requests, responses) = (frames(Direction::Inbound), frames(Direction::Outbound));
+    assert_eq!((requests.len(), responses.len()), (3, 3));
+    assert!(records.windows(2).all(|pair| pair[0].at <= pair[1].at), "Timestamps go backwards");
+
+    let fresh = create_server();
+    let handle = setup_server_thread(fresh.clone());
+    let replayed = recording::replay(&path, ("localhost", server_port(&fresh) as u16)).expect("Replay failed");
+    assert_eq!(replayed.into_iter().map(|record| record.frame).collect::<Vec<_>>(), responses);
+
+    fresh.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+
+    // A second server recording to the same directory leaves the first recording alone
+    let again = Arc::new(Server::builder("localhost:0").record_traffic(&dir).build().expect("Failed to start server"));
+    let handle = setup_server_thread(again.clone());
+    let mut client = client::Client::new("localhost", server_port(&again), 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("second").expect("Echo failed"), "second");
+    client.disconnect().expect("Failed to disconnect");
+    assert!(wait_for(|| again.active_client_count() == 0));
+    again.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    assert_eq!(recording::read_recording(&path).expect("Failed to read the recording"), records, "The first recording was overwritten");
+    assert_eq!(std::fs::read_dir(&dir).expect("Failed to list the recordings").count(), 2);
+    std::fs::remove_dir_all(&dir).expect("Failed to remove the recording directory");
+}
+
+//Of many threads calling stop() at once, exactly one performs the shutdown
+#[test]
+fn test_concurrent_stop() {
+    logger::init();
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address").to_string();
+
+    let barrier = Arc::new(std::sync::Barrier::new(16));
+    let stoppers: Vec<_> = (0..16)
+        .map(|_| {
+            let (server, barrier) = (server.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                server.stop();
+            })
+        })
+        .collect();
+    for stopper in stoppers {
+        stopper.join().expect("Stopping thread panicked");
+    }
+    handle.join().expect("Server thread panicked or failed to join");
+
+    assert!(!server.is_running());
+    assert_eq!(logger::count(&["Shutdown signal sent", &addr]), 1);
+}
+
+//A client refused because the server is full gets ServerAtCapacity right away, send_and_receive doesn't retry it
+#[test]
+fn test_server_at_capacity_fails_fast() {
+    logger::init();
+    let server = Arc::new(Server::new("localhost:0", 1).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let mut admitted = client::Client::new("localhost", port, 1000);
+    admitted.connect().expect("Failed to connect to the server");
+    assert!(wait_for(|| server.active_client_count() == 1));
+
+    // Backoffs this long would show up if the rejection were retried
+    let mut extra = client::Client::new("localhost", port, 1000).retry_backoff(Duration::from_millis(500));
+    extra.connect().expect("The TCP handshake completes in the kernel");
+    let addr = extra.local_addr().unwrap().to_string();
+    assert!(wait_for(|| logger::contains(&["Max clients reached", &addr])), "The extra client was not refused");
+    let started = Instant::now();
+    let error = extra
+        .send_and_receive(client_message::Message::EchoMessage(EchoMessage::from("full?")))
+        .expect_err("A full server served the extra client");
+    assert_eq!(client::ServerAtCapacity::find(&error), Some(client::ServerAtCapacity { max_clients: 1 }), "Unexpected error: {}", error);
+    assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    assert!(started.elapsed() < Duration::from_millis(400), "The rejection was retried, took {:?}", started.elapsed());
+    assert!(!logger::contains(&["Attempt", "at capacity"]), "The rejection counted as a failed attempt");
+
+    // The same rejection fails a validating connect
+    let mut validating = client::Client::new("localhost", port, 1000).validate_on_connect(true);
+    let error = validating.connect().expect_err("A full server passed the connect probe");
+    assert!(client::ServerAtCapacity::find(&error).is_some(), "Unexpected error: {}", error);
+
+    assert_eq!(admitted.echo("still served").expect("Echo failed"), "still served");
+    admitted.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Handler state kept in the ConnectionContext lives as long as the connection: it counts up across messages on one
+//connection and starts over on the next
+#[test]
+fn test_connection_context_is_per_connection() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|_, context: &mut ConnectionContext| {
+                let count = context.extensions.entry("count".to_string()).or_insert_with(|| Box::new(0u32));
+                let count = count.downcast_mut::<u32>().expect("The counter is a u32");
+                *count += 1;
+                let count = *count;
+                HandlerAction::Respond(server_message::Message::EchoMessage(EchoMessage {
+                    content: format!("{} {}", context.peer_addr(), count),
+                }))
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    for _ in 0..2 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        client.connect().expect("Failed to connect to the server");
+        let peer = client.local_addr().unwrap();
+        for expected in 1..=3 {
+            assert_eq!(client.echo("count").expect("Echo failed"), format!("{} {}", peer, expected));
+        }
+        client.disconnect().expect("Failed to disconnect");
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//run_with_ready() reports the bound address once the server listens, a deferred server can be connected to right away
+#[test]
+fn test_run_with_ready() {
+    let server = Arc::new(Server::builder("localhost:0").build_deferred());
+    let (ready, bound) = mpsc::channel();
+    let running = server.clone();
+    let handle = thread::spawn(move || running.run_with_ready(ready).expect("Server encountered an error"));
+    let addr = bound.recv_timeout(Duration::from_secs(5)).expect("Server didn't report readiness");
+    assert!(server.is_running());
+    assert_eq!(server.local_addr().unwrap(), addr);
+
+    let mut client = client::Client::new(&addr.ip().to_string(), addr.port() as u32, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("ready").expect("Echo failed"), "ready");
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+
+    // A server that fails to start drops the sender without reporting
+    let blocker = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to take a port");
+    let taken = Server::builder(&blocker.local_addr().unwrap().to_string()).build_deferred();
+    let (ready, bound) = mpsc::channel();
+    assert!(taken.run_with_ready(ready).is_err());
+    assert!(bound.recv().is_err());
+}
+
+//A connection is closed with QuotaExceeded as soon as the bytes it received and sent add up to more than the quota, the
+//echo that crossed it is still answered
+#[test]
+fn test_max_bytes_per_connection() {
+    const QUOTA: u64 = 200;
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_bytes_per_connection(QUOTA)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().max_bytes_per_connection, Some(QUOTA));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut raw = TcpStream::connect(("localhost", port as u16)).expect("Failed to connect to the server");
+    raw.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let request = frame::encode_client_message(&client_message::Message::EchoMessage(EchoMessage::from("metered"))).expect("Failed to encode");
+    let read_response = |mut raw: &TcpStream| frame::read_frame(&mut raw).expect("Failed to read").expect("Connection closed early");
+    let mut transferred = 0;
+    let mut echoes = 0;
+    while transferred <= QUOTA {
+        raw.write_all(&request).expect("Failed to write");
+        let response = read_response(&raw);
+        transferred += (request.len() + frame::HEADER_LEN + response.len()) as u64;
+        let response = ServerMessage::decode(&response[..]).expect("Failed to decode the echo");
+        assert!(matches!(response.message, Some(server_message::Message::EchoMessage(_))), "Unexpected response: {:?}", response);
+        echoes += 1;
+    }
+    assert!(echoes > 1, "A single echo crossed the quota");
+
+    // Closed right after the echo that crossed it, without another request
+    let response = ServerMessage::decode(&read_response(&raw)[..]).expect("Failed to decode");
+    assert_eq!(
+        response.message,
+        Some(server_message::Message::QuotaExceeded(QuotaExceeded { max_bytes: QUOTA, transferred }))
+    );
+    assert_eq!(raw.read(&mut [0u8; 1]).expect("Expected EOF"), 0, "The connection stayed open");
+
+    // The quota is per connection
+    let mut client = client::Client::new("localhost", port, 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.echo("fresh").expect("Echo failed"), "fresh");
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A subscriber that never sends anything is still held to max_bytes_per_connection by the notifications it receives
+#[test]
+fn test_max_bytes_per_connection_counts_pushed_messages() {
+    const QUOTA: u64 = 300;
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_bytes_per_connection(QUOTA)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut subscriber = client::Client::new("localhost", port, 2000);
+    subscriber.connect().expect("Failed to connect to the server");
+    subscriber.subscribe("metered").expect("Failed to subscribe");
+    let mut notifications = 0;
+    let exceeded = (0..50).find_map(|_| {
+        // A fresh publisher each time, so only the subscriber goes over its quota
+        let mut publisher = client::Client::new("localhost", port, 1000);
+        publisher.connect().expect("Failed to connect to the server");
+        publisher.publish("metered", &"x".repeat(40)).expect("Failed to publish");
+        publisher.disconnect().expect("Failed to disconnect");
+        match subscriber.receive().expect("Failed to receive").message {
+            Some(server_message::Message::Notification(_)) => {
+                notifications += 1;
+                None
+            }
+            Some(server_message::Message::QuotaExceeded(exceeded)) => Some(exceeded),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    });
+    let exceeded = exceeded.expect("The subscriber never went over its quota");
+    assert_eq!(exceeded.max_bytes, QUOTA);
+    assert!(exceeded.transferred > QUOTA);
+    assert!(notifications > 1, "A single notification crossed the quota");
+    let closed = subscriber.receive().expect_err("The connection stayed open");
+    assert_eq!(closed.kind(), ErrorKind::ConnectionAborted);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//call_within() gives up once its budget is spent, whether the connect, the send or the receive is slow
+#[test]
+fn test_call_within_budget() {
+    const BUDGET: Duration = Duration::from_millis(300);
+    const SLACK: Duration = Duration::from_millis(150);
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|message, _: &mut ConnectionContext| {
+                if matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "slow") {
+                    thread::sleep(Duration::from_secs(1));
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connects on its own, then a slow receive
+    let mut client = client::Client::new("localhost", port, 5000);
+    let response = client.call_within(echo("fast"), BUDGET).expect("A fast call failed");
+    assert_eq!(response.message, Some(server_message::Message::EchoMessage(EchoMessage::from("fast"))));
+    let started = Instant::now();
+    let error = client.call_within(echo("slow"), BUDGET).expect_err("The slow handler answered within the budget");
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() >= BUDGET - SLACK && started.elapsed() <= BUDGET + SLACK, "Gave up after {:?}", started.elapsed());
+    // The late answer to "slow" went with the dropped connection
+    let response = client.call_within(echo("after"), Duration::from_secs(5)).expect("Call after a timeout failed");
+    assert_eq!(response.message, Some(server_message::Message::EchoMessage(EchoMessage::from("after"))));
+
+    // A peer that accepts but never reads or answers: the connection probe, then a large send, run out
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let silent_port = silent.local_addr().unwrap().port() as u32;
+    let mut probing = client::Client::new("127.0.0.1", silent_port, 5000).validate_on_connect(true);
+    let started = Instant::now();
+    let error = probing.call_within(echo("probe"), BUDGET).expect_err("The silent peer passed the probe");
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() <= BUDGET + SLACK, "Connect phase overran: {:?}", started.elapsed());
+
+    let mut sending = client::Client::new("127.0.0.1", silent_port, 5000);
+    sending.connect().expect("Failed to connect to the silent peer");
+    let large = echo(&"x".repeat(16 * 1024 * 1024));
+    let started = Instant::now();
+    let error = sending.call_within(large, BUDGET).expect_err("A 16 MiB frame fit in the socket buffers");
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() <= BUDGET + SLACK, "Send phase overran: {:?}", started.elapsed());
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Fault injection fails every request at rate 1.0 and none at 0.0, a seed makes the failing requests reproducible, and a
+//lazy client's retries get past injected disconnects
+#[test]
+fn test_fault_injection() {
+    // Which of 20 echoes get an injected Error
+    let faults = |rate: f64, seed: u64| -> Vec<bool> {
+        let server = Arc::new(
+            Server::builder("localhost:0")
+                .fault_injection_rate(rate)
+                .fault_injection_seed(seed)
+                .build()
+                .expect("Failed to start server"),
+        );
+        let handle = setup_server_thread(server.clone());
+        let mut client = client::Client::new("localhost", server_port(&server), 1000);
+        client.connect().expect("Failed to connect to the server");
+        let faults = (0..20)
+            .map(|i| {
+                let response = client
+                    .send_and_receive(client_message::Message::EchoMessage(EchoMessage { content: i.to_string() }))
+                    .expect("Failed to receive a response");
+                match response.message {
+                    Some(server_message::Message::EchoMessage(echo)) => {
+                        assert_eq!(echo.content, i.to_string());
+                        false
+                    }
+                    Some(server_message::Message::Error(error)) => {
+                        assert_eq!(error.reason, "Injected fault");
+                        true
+                    }
+                    other => panic!("Unexpected response: {:?}", other),
+                }
+            })
+            .collect();
+        client.disconnect().expect("Failed to disconnect");
+        server.stop();
+        handle.join().expect("Server thread panicked or failed to join");
+        faults
+    };
+    assert_eq!(faults(1.0, 1), vec![true; 20]);
+    assert_eq!(faults(0.0, 1), vec![false; 20]);
+    let pattern = faults(0.5, 42);
+    assert!(pattern.contains(&true) && pattern.contains(&false), "Rate 0.5 gave {:?}", pattern);
+    assert_eq!(faults(0.5, 42), pattern, "The same seed failed different requests");
+
+    // Injected disconnects: every echo still succeeds, on a new connection when needed
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .fault_injection_rate(0.3)
+            .fault_injection_seed(7)
+            .fault_action(FaultAction::Disconnect)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().fault_injection_rate, FaultRate::new(0.3));
+    assert_eq!(server.config().fault_injection_rate.value(), 0.3);
+    assert_eq!(FaultRate::new(f64::NAN), FaultRate::default(), "A NaN rate would break ServerConfig's Eq");
+    assert_eq!(server.config().fault_action, FaultAction::Disconnect);
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).lazy_connect(true).max_retries(10);
+    let mut connections = std::collections::HashSet::new();
+    for i in 0..30 {
+        assert_eq!(client.echo(&i.to_string()).expect("Retries didn't get past the injected faults"), i.to_string());
+        connections.insert(client.local_addr().unwrap());
+    }
+    assert!(connections.len() > 1, "No fault was injected");
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Eight threads share one multiplexed connection, each gets the sums of its own AddRequests back
+#[test]
+fn test_multiplexer() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+    let multiplexer = Arc::new(client.multiplexer().expect("Failed to multiplex the connection"));
+
+    let threads: Vec<_> = (0..8i64)
+        .map(|thread| {
+            let multiplexer = multiplexer.clone();
+            thread::spawn(move || {
+                for i in 0..50i64 {
+                    let add = AddRequest { a: thread * 1000, b: i, ..Default::default() };
+                    let response = multiplexer.call(client_message::Message::AddRequest(add)).expect("Call failed");
+                    match response.message {
+                        Some(server_message::Message::AddResponse(sum)) => assert_eq!(sum.result, thread * 1000 + i),
+                        other => panic!("Unexpected response: {:?}", other),
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("Calling thread panicked");
+    }
+    assert_eq!(server.active_client_count(), 1);
+
+    drop(multiplexer);
+    assert!(wait_for(|| server.active_client_count() == 0));
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A message too large for the client's frame size fails send() with an EncodeError before anything is written, and
+//sending many messages reuses one encode buffer
+#[test]
+fn test_send_encode_buffer() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000).chunk_echoes(64);
+    client.connect().expect("Failed to connect to the server");
+
+    let publish = client_message::Message::Publish(Publish { topic: "news".to_string(), content: "x".repeat(200) });
+    let error = client.send_and_receive(publish).expect_err("A 200 byte publish fit a 64 byte frame");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    let encode_error = client::EncodeError::find(&error).expect("Not an EncodeError");
+    assert_eq!(encode_error.limit, 64);
+    assert!(encode_error.required > 200, "Unexpected error: {}", encode_error);
+    // Nothing reached the server, the connection is still in step
+    assert_eq!(client.echo("still in step").expect("Echo failed"), "still in step");
+
+    let content = "y".repeat(40);
+    client.echo(&content).expect("Echo failed");
+    let capacity = client.encode_buffer_capacity();
+    assert!(capacity > content.len(), "The echo wasn't encoded into the buffer");
+    for _ in 0..500 {
+        assert_eq!(client.echo(&content).expect("Echo failed"), content);
+        client.echo("short").expect("Echo failed");
+    }
+    assert_eq!(client.encode_buffer_capacity(), capacity, "Sends of the same size grew the encode buffer");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A client subscribed to status updates receives them periodically, with the active client count and a growing uptime
+#[test]
+fn test_status_updates() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .status_updates(Duration::from_millis(50))
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().status_interval_ms, Some(50));
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    client.subscribe_status().expect("Failed to subscribe to status updates");
+
+    let started = Instant::now();
+    let first = client.next_status_update().expect("No status update");
+    // A request in between still gets its own response, the updates are set aside
+    assert_eq!(client.echo("between").expect("Echo failed"), "between");
+    let second = client.next_status_update().expect("No second status update");
+    assert!(started.elapsed() < Duration::from_millis(500), "Two updates took {:?}", started.elapsed());
+    assert_eq!(first.active_clients, 1);
+    assert_eq!(second.active_clients, 1);
+    assert!(second.uptime_ms > first.uptime_ms, "Uptime went from {} to {} ms", first.uptime_ms, second.uptime_ms);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Status updates follow the server clock: none arrives until a mock clock reaches the interval, the update then
+//reports the mock uptime
+#[test]
+fn test_status_updates_with_mock_clock() {
+    let (clock, server, handle, mut client) = mock_clock_setup(|builder| builder.status_updates(Duration::from_secs(60)));
+    client.subscribe_status().expect("Failed to subscribe to status updates");
+
+    // The client's one second read timeout spans many polls of the status thread
+    let error = client.next_status_update().expect_err("Update sent before the mock interval passed");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "Unexpected error: {}", error);
+    clock.advance(Duration::from_secs(60));
+    let update = client.next_status_update().expect("No status update once the mock clock advanced");
+    assert_eq!(update.uptime_ms, 60_000);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Responses larger than a single read come back whole: the frame length decides how much is read, not the size of the
+//first read. A response cut short is an error, never a truncated message
+#[test]
+fn test_receive_response_larger_than_one_read() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    let content = "r".repeat(600);
+    assert_eq!(client.echo(&content).expect("Echo failed"), content);
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+
+    // A peer that announces a 600 byte body, sends 512 bytes of it and closes
+    let peer = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = peer.local_addr().unwrap().port() as u32;
+    let responder = thread::spawn(move || {
+        let (mut stream, _) = peer.accept().expect("Failed to accept");
+        frame::read_frame(&mut stream).expect("Failed to read the request");
+        let mut partial = 600u32.to_be_bytes().to_vec();
+        partial.extend_from_slice(&[0u8; 512]);
+        stream.write_all(&partial).expect("Failed to write");
+    });
+    let mut client = client::Client::new("127.0.0.1", port, 1000);
+    client.connect().expect("Failed to connect to the peer");
+    client.send(client_message::Message::EchoMessage(EchoMessage { content: content.clone() })).expect("Failed to send");
+    responder.join().expect("Responder panicked");
+    let error = client.receive().expect_err("A truncated frame was returned as a message");
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof, "Unexpected error: {}", error);
+}
+
+//The accept loop hook runs on every iteration, so it keeps being called while the server has nothing to do
+#[test]
+fn test_accept_loop_hook() {
+    let iterations = Arc::new(AtomicUsize::new(0));
+    let counted = iterations.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .accept_loop_hook(move |server| {
+                assert!(server.is_running());
+                counted.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let load = || iterations.load(Ordering::SeqCst);
+    assert!(wait_for(|| load() >= 1), "The hook was never called");
+    let first = load();
+    assert!(wait_for(|| load() >= first + 5), "The hook stopped being called while idle");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    let stopped = load();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(load(), stopped, "The hook ran after the server stopped");
+}
+
+//wait_for_server returns once a server that starts late is accepting, and times out if it isn't up in time
+#[test]
+fn test_wait_for_server() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();     // A port with nothing on it
+    let error = client::Client::wait_for_server(addr, Duration::from_millis(50)).expect_err("Nothing is listening yet");
+    assert_eq!(error.kind(), ErrorKind::TimedOut, "Unexpected error: {}", error);
+
+    let server = Arc::new(Server::builder(&addr.to_string()).build_deferred());
+    let running = server.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        running.run().expect("Server encountered an error")
+    });
+    let start = Instant::now();
+    client::Client::wait_for_server(addr, Duration::from_secs(5)).expect("The server never came up");
+    assert!(start.elapsed() >= Duration::from_millis(150), "Returned before the server started");
+    assert!(server.is_running());
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Under contention the connection's writer sends the highest-priority response first: a Ping sent after a backlog of
+//large echoes to a client that isn't reading comes back ahead of echoes that were queued before it. seq still counts
+//the responses in the order they arrive
+#[test]
+fn test_priority_responses_overtake_queued_ones() {
+    const BUFFER: usize = 4096;
+    const ECHOES: u64 = 16;
+    let (audited, pong_queued) = mpsc::channel();
+    let audited = Mutex::new(audited);
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .socket_buffer_sizes(None, Some(BUFFER))
+            .audit_sink(move |record: &AuditRecord| {
+                if record.message_type == "Ping" {
+                    let _ = audited.lock().unwrap().send(());       // Audited once the Pong is queued
+                }
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 5000);
+    client.connect().expect("Failed to connect to the server");
+
+    // The first echoes fill both socket buffers, the rest wait in the connection's queue
+    let content = "p".repeat(64 * 1024);
+    for id in 1..=ECHOES {
+        let mut request = ClientMessage::default();
+        request.message = Some(client_message::Message::EchoMessage(EchoMessage { content: content.clone() }));
+        request.correlation_id = id;
+        client.send_envelope(request).expect("Failed to send echo");
+    }
+    let mut urgent = ClientMessage::default();
+    urgent.message = Some(client_message::Message::Ping(Ping {}));
+    urgent.correlation_id = 100;
+    urgent.priority = 200;
+    client.send_envelope(urgent).expect("Failed to send ping");
+    pong_queued.recv_timeout(Duration::from_secs(5)).expect("The Pong was never queued");
+
+    let responses: Vec<ServerMessage> = (0..=ECHOES).map(|_| client.receive().expect("Failed to receive")).collect();
+    let seqs: Vec<u64> = responses.iter().map(|response| response.seq).collect();
+    assert_eq!(seqs, (0..=ECHOES).collect::<Vec<_>>(), "seq doesn't follow the order on the wire");
+    let order: Vec<u64> = responses.iter().map(|response| response.correlation_id).collect();
+    let pong = order.iter().position(|id| *id == 100).expect("No Pong");
+    assert!(pong < ECHOES as usize, "The Pong waited behind every echo: {:?}", order);
+    let echoes: Vec<u64> = order.iter().copied().filter(|id| *id != 100).collect();
+    assert_eq!(echoes, (1..=ECHOES).collect::<Vec<_>>(), "Echoes of equal priority were reordered");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A worker pool takes waiting messages by priority: with its only thread busy, a high-priority AddRequest sent after
+//low-priority ones is handled before them
+#[test]
+fn test_priority_worker_pool_scheduling() {
+    let handled = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let released = Arc::new(AtomicUsize::new(0));
+    let (order, gate) = (handled.clone(), released.clone());
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                if let client_message::Message::AddRequest(ref add) = message {
+                    order.lock().unwrap().push(add.a);
+                    while gate.load(Ordering::SeqCst) == 0 {
+                        thread::sleep(Duration::from_millis(1));      // Holds the pool thread until the rest are queued
+                    }
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .worker_pool(&["AddRequest"], 1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let count = |handled: &Arc<std::sync::Mutex<Vec<i64>>>| handled.lock().unwrap().len();
+
+    // Request 0 holds the pool thread while 1 to 3 (low) and then 9 (high) queue up, one connection each
+    // A request is listed by inflight_requests() from the moment it is read until it is answered
+    let mut clients = Vec::new();
+    for (a, priority) in [(0, 0), (1, 1), (2, 1), (3, 1), (9, 9)] {
+        let mut client = client::Client::new("localhost", port, 5000);
+        client.connect().expect("Failed to connect to the server");
+        let mut request = ClientMessage::default();
+        request.message = Some(client_message::Message::AddRequest(AddRequest { a, b: 0, ..Default::default() }));
+        request.priority = priority;
+        request.correlation_id = a as u64 + 1;
+        client.send_envelope(request).expect("Failed to send");
+        if a == 0 {
+            assert!(wait_for(|| count(&handled) == 1), "The first request never reached the pool");
+        }
+        let peer = client.local_addr().expect("Failed to read the client address");
+        assert!(wait_for(|| server.inflight_requests(peer) == [a as u64 + 1]), "Request {} never reached the server", a);
+        clients.push(client);
+    }
+    released.store(1, Ordering::SeqCst);
+    for client in &mut clients {
+        client.receive().expect("Failed to receive the AddResponse");
+    }
+    let order = handled.lock().unwrap().clone();
+    assert_eq!(order[..2], [0, 9], "The high-priority request waited behind low-priority ones: {:?}", order);
+    let mut low = order[2..].to_vec();
+    low.sort();
+    assert_eq!(low, vec![1, 2, 3]);
+
+    drop(clients);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With a thread join timeout, handlers that never return don't stall the shutdown: run() returns once the timeout has
+//passed, one real-time timeout for all the stuck threads even with a mock clock, and the abandoned threads are logged
+#[test]
+fn test_thread_join_timeout() {
+    logger::init();
+    const JOIN_TIMEOUT: Duration = Duration::from_millis(300);
+    const STUCK: usize = 3;
+    let release = Arc::new(AtomicBool::new(false));
+    let entered = Arc::new(AtomicUsize::new(0));
+    let (stuck, started) = (release.clone(), entered.clone());
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                started.fetch_add(1, Ordering::SeqCst);
+                while !stuck.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));      // Wedged until the end of the test
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .thread_join_timeout(JOIN_TIMEOUT)
+            .clock(Arc::new(MockClock::new()))      // Never advanced, the join timeout still passes
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().thread_join_timeout_ms, Some(300));
+    let handle = setup_server_thread(server.clone());
+    let clients: Vec<_> = (0..STUCK)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", server_port(&server), 1000);
+            client.connect().expect("Failed to connect to the server");
+            client.send(client_message::Message::EchoMessage(EchoMessage::from("stuck"))).expect("Failed to send");
+            client
+        })
+        .collect();
+    assert!(wait_for(|| entered.load(Ordering::SeqCst) == STUCK), "The handlers never started");
+
+    let stopped = Instant::now();
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    let elapsed = stopped.elapsed();
+    assert!(elapsed < JOIN_TIMEOUT * 2, "Cleanup waited {:?} for {} stuck handlers", elapsed, STUCK);
+    assert!(elapsed >= JOIN_TIMEOUT, "Cleanup returned before the join timeout: {:?}", elapsed);
+    assert_eq!(logger::count(&["Abandoned thread", "after the server stopped"]), STUCK, "The abandoned threads were not logged");
+    release.store(true, Ordering::SeqCst);
+    drop(clients);
+}
+
+//A client with failover endpoints skips a server that is down: the first address refuses, the client connects to
+//the second one and the echo completes there
+#[test]
+fn test_failover_endpoints() {
+    let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as u32;     // Nothing listens here
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut client = client::Client::new("127.0.0.1", down, 1000).failover_endpoints(&[("localhost", port)]);
+    client.connect().expect("Failover to the second endpoint failed");
+    assert_eq!(client.endpoint(), ("localhost", port));
+    assert_eq!(client.echo("failover").expect("Echo failed"), "failover");
+    client.disconnect().expect("Failed to disconnect");
+
+    // Without a reachable endpoint connect() still fails, once every endpoint was tried
+    let mut client = client::Client::new("127.0.0.1", down, 1000).failover_endpoints(&[("127.0.0.1", down)]);
+    assert!(client.connect().is_err());
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A connection whose threads can't be started is refused with ServerBusy instead of crashing the accept loop: a stack
+//size no system can map makes every spawn fail, the server keeps running and frees the slot each time
+#[test]
+fn test_thread_spawn_failure_refuses_connection() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .thread_stack_size(1 << 46)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().thread_stack_size, Some(1 << 46));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    for _ in 0..3 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        client.connect().expect("Failed to connect to the server");
+        let refusal = client.receive_message().expect("No refusal was sent");
+        assert!(matches!(refusal, server_message::Message::ServerBusy(_)), "Unexpected response: {:?}", refusal);
+        assert!(client.receive().is_err(), "The refused connection was left open");
+    }
+    assert!(server.is_running());
+    assert!(wait_for(|| server.active_client_count() == 0), "A refused connection kept its slot");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//wait_for_clients returns as soon as the expected number of clients connected from other threads, and false when
+//the count isn't reached in time
+#[test]
+fn test_wait_for_clients() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    assert!(server.wait_for_clients(0, Duration::ZERO));
+
+    let (done, finished) = mpsc::channel::<()>();
+    let finished = Arc::new(std::sync::Mutex::new(finished));
+    let clients: Vec<_> = (0..3)
+        .map(|_| {
+            let finished = finished.clone();
+            thread::spawn(move || {
+                let mut client = client::Client::new("localhost", port, 1000);
+                client.connect().expect("Failed to connect to the server");
+                let _ = finished.lock().unwrap().recv();       // Stays connected until the test is done
+                client.disconnect().expect("Failed to disconnect");
+            })
+        })
+        .collect();
+    assert!(server.wait_for_clients(3, Duration::from_secs(5)), "Only {} clients connected", server.active_client_count());
+    assert!(!server.wait_for_clients(4, Duration::from_millis(50)));
+
+    drop(done);
+    for client in clients {
+        client.join().expect("Client thread panicked");
+    }
+    assert!(server.wait_for_clients(0, Duration::from_secs(5)), "Clients didn't disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A request whose TTL runs out while it waits behind a slow one is not handled: with ExpiredRequestPolicy::Reply it is
+//answered with Expired, and a request without a TTL sent after it is still served
+#[test]
+fn test_expired_request_dropped() {
+    let echoes = Arc::new(AtomicUsize::new(0));
+    let handled = echoes.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                match message {
+                    client_message::Message::AddRequest(_) => thread::sleep(Duration::from_millis(300)),   // Slow request
+                    client_message::Message::EchoMessage(_) => {
+                        handled.fetch_add(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .expired_request_policy(ExpiredRequestPolicy::Reply)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().expired_request_policy, ExpiredRequestPolicy::Reply);
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 2000);
+    client.connect().expect("Failed to connect to the server");
+
+    client.send(client_message::Message::AddRequest(AddRequest { a: 1, b: 2, ..Default::default() })).expect("Failed to send");
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let mut stale = ClientMessage::default();
+    stale.message = Some(client_message::Message::EchoMessage(EchoMessage::from("stale")));
+    stale.correlation_id = 7;
+    stale.expires_at_unix_nanos = (now + Duration::from_millis(100)).as_nanos() as u64;
+    client.send_envelope(stale).expect("Failed to send the stale echo");
+
+    assert!(matches!(client.receive_message().expect("No AddResponse"), server_message::Message::AddResponse(_)));
+    let expired = client.receive().expect("No answer to the expired echo");
+    assert_eq!(expired.correlation_id, 7);
+    assert!(matches!(expired.message, Some(server_message::Message::Expired(_))), "Unexpected response: {:?}", expired);
+    assert_eq!(echoes.load(Ordering::SeqCst), 0, "The expired echo reached the handler");
+    assert_eq!(client.echo("fresh").expect("Echo failed"), "fresh");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//An unauthenticated client is told to authenticate even when its request expired, so expiry answers leak nothing
+//before Auth
+#[test]
+fn test_expired_request_checks_auth_first() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .require_auth(|token| token == "secret")
+            .expired_request_policy(ExpiredRequestPolicy::Reply)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let mut stale = ClientMessage::default();
+    stale.message = Some(client_message::Message::EchoMessage(EchoMessage::from("stale")));
+    stale.expires_at_unix_nanos = 1;        // Long past
+    client.send_envelope(stale.clone()).expect("Failed to send the stale echo");
+    let response = client.receive_message().expect("No answer to the stale echo");
+    assert!(matches!(response, server_message::Message::Unauthorized(_)), "Unexpected response: {:?}", response);
+
+    client.authenticate("secret").expect("Valid token was rejected");
+    client.send_envelope(stale).expect("Failed to send the stale echo");
+    let response = client.receive_message().expect("No answer to the stale echo");
+    assert!(matches!(response, server_message::Message::Expired(_)), "Unexpected response: {:?}", response);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//stop() lets the request being handled finish but reads no further one: the slow AddRequest is answered, then the
+//connection gets a Goodbye and closes, and the echo sent after stop() never reaches the handler
+#[test]
+fn test_stop_finishes_inflight_request_only() {
+    let started = Arc::new(AtomicUsize::new(0));
+    let echoes = Arc::new(AtomicUsize::new(0));
+    let (adds, handled) = (started.clone(), echoes.clone());
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                match message {
+                    client_message::Message::AddRequest(_) => {
+                        adds.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(300));
+                    }
+                    client_message::Message::EchoMessage(_) => {
+                        handled.fetch_add(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 2000);
+    client.connect().expect("Failed to connect to the server");
+
+    client.send(client_message::Message::AddRequest(AddRequest { a: 2, b: 3, ..Default::default() })).expect("Failed to send");
+    assert!(wait_for(|| started.load(Ordering::SeqCst) == 1), "The AddRequest was never handled");
+    server.stop();
+    client.send(client_message::Message::EchoMessage(EchoMessage::from("late"))).expect("Failed to send");
+
+    match client.receive_message().expect("The in-flight request was not answered") {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 5),
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    let goodbye = client.receive_message().expect("No Goodbye");
+    assert!(matches!(goodbye, server_message::Message::Goodbye(_)), "Unexpected response: {:?}", goodbye);
+    assert!(client.receive().is_err(), "The connection stayed open after Goodbye");
+    handle.join().expect("Server thread panicked or failed to join");
+    assert_eq!(echoes.load(Ordering::SeqCst), 0, "A request sent after stop() was handled");
+}
+
+//send_and_forget needs no response: against a handler that discards echoes, 50 of them all reach the server without
+//the client ever receiving
+#[test]
+fn test_send_and_forget() {
+    let received = Arc::new(AtomicUsize::new(0));
+    let counted = received.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |_, _: &mut ConnectionContext| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                HandlerAction::Ignore        // Discard mode, nothing is answered
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    for i in 0..50 {
+        let notification = client_message::Message::EchoMessage(EchoMessage::from(format!("notification {}", i)));
+        client.send_and_forget(notification).expect("Failed to send");
+    }
+    assert!(wait_for(|| received.load(Ordering::SeqCst) == 50), "Only {} of 50 arrived", received.load(Ordering::SeqCst));
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With strict sequencing, a connection sending seq 0, 1, 3 gets its first two messages handled and the third flagged
+//as a gap, a replayed seq is flagged as a regression
+#[test]
+fn test_strict_sequencing_flags_gap() {
+    logger::init();
+    let server = Arc::new(Server::builder("localhost:0").strict_sequencing(true).build().expect("Failed to start server"));
+    assert!(server.config().strict_sequencing);
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    let addr = client.local_addr().unwrap().to_string();
+
+    let mut send = |seq: u64| {
+        let mut request = ClientMessage::default();
+        request.message = Some(client_message::Message::EchoMessage(EchoMessage::from(format!("seq {}", seq))));
+        request.seq = seq;
+        client.send_envelope(request).expect("Failed to send");
+        client.receive_message().expect("No response")
+    };
+    for seq in [0, 1] {
+        assert_eq!(send(seq), server_message::Message::EchoMessage(EchoMessage::from(format!("seq {}", seq))));
+    }
+    match send(3) {
+        server_message::Message::Error(error) => assert_eq!(error.reason, "Sequence gap: expected 2, got 3"),
+        other => panic!("The gap was not flagged: {:?}", other),
+    }
+    assert!(logger::contains(&["Sequence gap", &addr]), "The gap was not logged");
+    match send(3) {
+        server_message::Message::Error(error) => assert_eq!(error.reason, "Sequence regression: expected 4, got 3"),
+        other => panic!("The regression was not flagged: {:?}", other),
+    }
+    assert!(matches!(send(4), server_message::Message::EchoMessage(_)), "Sequencing didn't resume after the gap");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//The header timeout and the body timeout are separate: a client may idle far longer than the body timeout before a
+//message, but once its length prefix arrived a body trickled in too slowly gets it disconnected. Idling past the
+//header timeout closes the connection too, and a length prefix cut short by the header timeout is logged as a stall
+#[test]
+fn test_header_and_body_timeouts() {
+    logger::init();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .header_timeout(Duration::from_secs(1))
+            .body_timeout(Duration::from_millis(200))
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().header_timeout_ms, Some(1000));
+    assert_eq!(server.config().frame_deadline_ms, Some(200));
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().unwrap();
+    let closed = |stream: &mut TcpStream| {
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        matches!(stream.read(&mut [0u8; 1]), Ok(0) | Err(_))
+    };
+
+    // Idle for longer than the body timeout, then an echo sent at once is answered
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    thread::sleep(Duration::from_millis(500));
+    let echo = client_message::Message::EchoMessage(EchoMessage::from("after a pause"));
+    stream.write_all(&frame::encode_client_message(&echo).expect("Failed to encode")).expect("Failed to send");
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    assert!(frame::read_frame(&mut stream).expect("Echo failed").is_some(), "Closed after idling");
+
+    // The same echo with its body trickled over ~500ms
+    let request = frame::encode_client_message(&echo).expect("Failed to encode");
+    stream.write_all(&request[..frame::HEADER_LEN]).expect("Failed to send the header");
+    for byte in &request[frame::HEADER_LEN..] {
+        if stream.write_all(&[*byte]).is_err() {
+            break;     // Already disconnected
+        }
+        thread::sleep(Duration::from_millis(30));
+    }
+    assert!(closed(&mut stream), "A body trickled past the body timeout was accepted");
+
+    // Nothing at all for longer than the header timeout
+    let mut idle = TcpStream::connect(addr).expect("Failed to connect to the server");
+    thread::sleep(Duration::from_millis(1200));
+    assert!(closed(&mut idle), "The connection outlived the header timeout");
+    assert!(logger::contains(&["sent no message within the header timeout"]), "The idle client was not logged");
+
+    // Half a length prefix, then nothing for longer than the header timeout
+    let mut stalled = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stalled.write_all(&request[..2]).expect("Failed to send part of the header");
+    thread::sleep(Duration::from_millis(1200));
+    assert!(closed(&mut stalled), "The connection outlived the header timeout");
+    let peer = stalled.local_addr().unwrap().to_string();
+    assert!(logger::contains(&[&peer, "stalled within the header timeout", "2 of 4 bytes"]), "The partial header was not logged");
+    assert!(!logger::contains(&[&peer, "sent no message"]), "The partial header was logged as an idle client");
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Worker pool threads are all started when the server is built, so they are running before run() and before any
+//client connects
+#[test]
+fn test_worker_pool_threads_prestarted() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .worker_pool(&["AddRequest", "EchoMessage"], 3)
+            .worker_pool(&["Ping"], 2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.handler_thread_count(), 5);
+    let handle = setup_server_thread(server.clone());
+    assert_eq!(server.handler_thread_count(), 5);
+    assert_eq!(server.active_client_count(), 0);
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A client out of step with the server, holding a peeked response and with another unread on the socket, is back in
+//step after close_and_reconnect
+#[test]
+fn test_close_and_reconnect_resets_buffered_state() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    for content in ["stale 1", "stale 2"] {
+        client
+            .send(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() }))
+            .expect("Failed to send");
+    }
+    assert!(matches!(client.peek_message(), Ok(server_message::Message::EchoMessage(_))));
+
+    client.close_and_reconnect().expect("Failed to reconnect");
+    assert_eq!(client.echo("fresh").expect("Echo failed"), "fresh");
+    assert!(server.wait_for_clients(1, Duration::from_secs(2)), "The old connection was left open");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Refused connections are counted under their reason: a full server and a per-IP cap each count their own, a drained
+//server counts the connections arriving afterwards
+#[test]
+fn test_rejection_stats() {
+    let full = Arc::new(Server::new("localhost:0", 1).expect("Failed to start server"));
+    let capped = Arc::new(
+        Server::builder("localhost:0")
+            .max_connections_per_ip(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handles = [setup_server_thread(full.clone()), setup_server_thread(capped.clone())];
+
+    for server in [&full, &capped] {
+        let mut admitted = client::Client::new("localhost", server_port(server), 1000);
+        admitted.connect().expect("Failed to connect to the server");
+        assert!(server.wait_for_clients(1, Duration::from_secs(2)));
+        for _ in 0..2 {
+            let mut refused = client::Client::new("localhost", server_port(server), 1000);
+            refused.connect().expect("The TCP handshake completes in the kernel");
+            refused.receive().expect_err("A refused connection was served");
+        }
+        admitted.disconnect().expect("Failed to disconnect");
+    }
+    assert_eq!(full.rejection_stats(), RejectionStats { at_capacity: 2, ..Default::default() });
+    assert_eq!(capped.rejection_stats(), RejectionStats { per_ip_limit: 2, ..Default::default() });
+
+    capped.drain();
+    let _late = TcpStream::connect(capped.local_addr().unwrap()).expect("The TCP handshake completes in the kernel");
+    assert!(wait_for(|| capped.rejection_stats().draining == 1), "The connection after drain() was not counted");
+    assert_eq!(capped.rejection_stats().per_ip_limit, 2);
+
+    full.stop();
+    capped.stop();
+    for handle in handles {
+        handle.join().expect("Server thread panicked or failed to join");
+    }
+}
+
+//An EchoMessage converts from and to its text, and the echoed content comes back through into()
+#[test]
+fn test_echo_message_string_conversions() {
+    let echo: EchoMessage = "hello".into();
+    assert_eq!(echo.as_str(), "hello");
+    assert_eq!(echo, EchoMessage::from(String::from("hello")));
+    let content: String = echo.into();
+    assert_eq!(content, "hello");
+
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    match client.send_and_receive(client_message::Message::EchoMessage("round trip".into())).expect("Echo failed").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(String::from(echo), "round trip"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//An audit sink sees every handled message with its peer, type, size and result, once it was answered
+#[test]
+fn test_audit_sink_records_handled_messages() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let sink = records.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .audit_sink(move |record: &AuditRecord| sink.lock().unwrap().push(record.clone()))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    assert_eq!(client.echo("audited").expect("Echo failed"), "audited");
+    assert_eq!(client.add(2, 3).expect("Add failed"), 5);
+    assert!(wait_for(|| records.lock().unwrap().len() == 2), "Not every message was audited");
+
+    let peer = client.local_addr().unwrap();
+    let records = records.lock().unwrap();
+    assert_eq!(records.iter().map(|record| record.message_type).collect::<Vec<_>>(), ["EchoMessage", "AddRequest"]);
+    for record in records.iter() {
+        assert_eq!(record.peer, peer);
+        assert_eq!(record.result, AuditResult::Responded);
+        assert!(record.size > 0);
+        assert!(record.latency < Duration::from_secs(1), "Implausible latency {:?}", record.latency);
+    }
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//server_features() reports what the HelloResponse announced: auth on a server requiring it, and the features of the
+//negotiated version, reset by a new connection
+#[test]
+fn test_server_features() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .require_auth(|token| token == "secret")
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+    assert_eq!(client.server_features(), client::ServerFeatures::default());
+
+    client.hello(PROTOCOL_VERSION).expect("Hello failed");
+    let features = client.server_features();
+    assert_eq!(features.version, PROTOCOL_VERSION);
+    assert!(features.auth_required && features.subscriptions && features.streaming, "Missing features: {:?}", features);
+
+    client.reconnect().expect("Failed to reconnect");
+    assert_eq!(client.server_features(), client::ServerFeatures::default());
+    client.hello(1).expect("Hello failed");
+    assert_eq!(
+        client.server_features(),
+        client::ServerFeatures { version: 1, auth_required: true, subscriptions: false, streaming: false }
+    );
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//Disconnect closes a connection declaring an oversized frame as soon as its length prefix arrives: the sender is cut
+//off promptly, having written little of the declared body
+#[test]
+fn test_large_message_disconnect_skips_body() {
+    const DECLARED: usize = 512 * 1024 * 1024;
+    let (server, handle, mut client) = large_message_setup(LargeMessagePolicy::Disconnect);
+    client.disconnect().expect("Failed to disconnect");
+
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to connect to the server");
+    stream.write_all(&(DECLARED as u32).to_be_bytes()).expect("Failed to send the length prefix");
+    stream.set_write_timeout(Some(Duration::from_millis(100))).unwrap();
+    let started = Instant::now();
+    let chunk = vec![0u8; 64 * 1024];
+    let mut written = 0;
+    let closed = loop {
+        if started.elapsed() > Duration::from_secs(3) {
+            break false;
+        }
+        match stream.write(&chunk) {
+            Ok(n) => written += n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}     // Nobody is reading
+            Err(_) => break true,
+        }
+    };
+    assert!(closed, "Still connected after {:?}, {} bytes written", started.elapsed(), written);
+    assert!(written < DECLARED / 16, "{} of the {} declared bytes were consumed", written, DECLARED);
+    assert!(server.wait_for_clients(0, Duration::from_secs(2)));
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//With echo_add_operands every AddResponse carries the operands its client sent, under concurrent adds from many clients
+#[test]
+fn test_echo_add_operands_concurrent() {
+    let server = Arc::new(Server::builder("localhost:0").echo_add_operands(true).build().expect("Failed to start server"));
+    assert!(server.config().echo_add_operands);
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let threads: Vec<_> = (0..8i64)
+        .map(|thread| {
+            thread::spawn(move || {
+                let mut client = client::Client::new("localhost", port, 1000);
+                client.connect().expect("Failed to connect to the server");
+                for i in 0..20 {
+                    let (a, b) = (thread * 1000 + i, -i);
+                    let request = client_message::Message::AddRequest(AddRequest { a, b, ..Default::default() });
+                    match client.send_and_receive(request).expect("Add failed").message {
+                        Some(server_message::Message::AddResponse(sum)) => {
+                            assert_eq!((sum.a, sum.b), (a, b), "Response to another request");
+                            assert_eq!(sum.result, a + b);
+                        }
+                        other => panic!("Expected AddResponse, got {:?}", other),
+                    }
+                }
+                client.disconnect().expect("Failed to disconnect");
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("Client thread panicked");
+    }
+
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A request on a connection that was open before drain() is served, answered with ShuttingDown or ignored, as the
+//draining request policy says
+#[test]
+fn test_draining_request_policy() {
+    for policy in [DrainingRequestPolicy::Serve, DrainingRequestPolicy::Reject, DrainingRequestPolicy::Ignore] {
+        let server = Arc::new(
+            Server::builder("localhost:0")
+                .draining_request_policy(policy)
+                .build()
+                .expect("Failed to start server"),
+        );
+        let handle = setup_server_thread(server.clone());
+        let mut client = client::Client::new("localhost", server_port(&server), 300);
+        client.connect().expect("Failed to connect to the server");
+        assert_eq!(client.echo("before").expect("Echo failed"), "before");
+
+        server.drain();
+        client
+            .send(client_message::Message::EchoMessage("during".into()))
+            .expect("Failed to send");
+        match (policy, client.receive()) {
+            (DrainingRequestPolicy::Serve, Ok(response)) => {
+                assert_eq!(response.message, Some(server_message::Message::EchoMessage("during".into())));
+            }
+            (DrainingRequestPolicy::Reject, Ok(response)) => {
+                assert!(matches!(response.message, Some(server_message::Message::ShuttingDown(_))), "Got {:?}", response);
+            }
+            (DrainingRequestPolicy::Ignore, Err(e)) => {
+                assert!(matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "Unexpected error: {}", e);
+            }
+            (policy, other) => panic!("{:?} while draining gave {:?}", policy, other),
+        }
+        assert_eq!(server.draining_status().remaining_connections, 1, "{:?} closed the connection", policy);
+
+        client.disconnect().expect("Failed to disconnect");
+        server.stop();
+        handle.join().expect("Server thread panicked or failed to join");
+    }
+}
+
+//with_retry_on retries only the server rejections it names: a transient ServerBusy is retried until the handler
+//answers, a validation Error is returned after one attempt
+#[test]
+fn test_with_retry_on_server_codes() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let seen = calls.clone();
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(move |message, _: &mut ConnectionContext| {
+                let call = seen.fetch_add(1, Ordering::SeqCst);
+                match message {
+                    client_message::Message::AddRequest(add) if add.a < 0 => {
+                        HandlerAction::Respond(server_message::Message::Error(Error { reason: "a must not be negative".to_string() }))
+                    }
+                    client_message::Message::EchoMessage(_) if call < 2 => HandlerAction::Respond(server_message::Message::ServerBusy(ServerBusy {})),
+                    message => process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond),
+                }
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000)
+        .max_retries(5)
+        .with_retry_on(&[client::ServerErrorCode::ServerBusy]);
+    client.connect().expect("Failed to connect to the server");
+
+    assert_eq!(client.echo("eventually").expect("Echo failed"), "eventually");
+    assert_eq!(calls.load(Ordering::SeqCst), 3, "ServerBusy was not retried");
+
+    let response = client
+        .send_and_receive(client_message::Message::AddRequest(AddRequest { a: -1, b: 1, ..Default::default() }))
+        .expect("Add failed");
+    assert_eq!(client::ServerErrorCode::of(&response), Some(client::ServerErrorCode::Error));
+    assert_eq!(calls.load(Ordering::SeqCst), 4, "A permanent error was retried");
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//A flood of connections doesn't hold up stop(): the accept loop takes at most max_accepts_per_iteration of them before
+//it checks whether it should keep running
+#[test]
+fn test_stop_during_connection_flood() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .max_clients(1000)
+            .max_accepts_per_iteration(8)
+            .build()
+            .expect("Failed to start server"),
+    );
+    assert_eq!(server.config().max_accepts_per_iteration, 8);
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().unwrap();
+
+    let flooding = Arc::new(AtomicBool::new(true));
+    let connected = Arc::new(AtomicUsize::new(0));
+    let flooders: Vec<_> = (0..4)
+        .map(|_| {
+            let (flooding, connected) = (flooding.clone(), connected.clone());
+            thread::spawn(move || {
+                while flooding.load(Ordering::SeqCst) {
+                    // Bounded, once the backlog is full a plain connect waits out every SYN retry
+                    if TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok() {
+                        connected.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+        })
+        .collect();
+    assert!(wait_for(|| connected.load(Ordering::SeqCst) > 100), "The flood didn't get going");
+
+    let started = Instant::now();
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+    assert!(started.elapsed() < Duration::from_secs(2), "Stopping took {:?} under the flood", started.elapsed());
+
+    flooding.store(false, Ordering::SeqCst);
+    for flooder in flooders {
+        flooder.join().expect("Flooding thread panicked");
+    }
+}
+
+//checked_add surfaces a sum outside int64 as an AddOverflow error instead of the saturated result, in both directions
+#[test]
+fn test_checked_add_overflow() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    client.connect().expect("Failed to connect to the server");
+
+    let error = client.checked_add(i64::MAX, 1).expect_err("An overflowing sum was returned");
+    assert_eq!(client::AddOverflow::find(&error), Some(client::AddOverflow { a: i64::MAX, b: 1 }), "Unexpected error: {}", error);
+    let error = client.checked_add(i64::MIN, -1).expect_err("An overflowing sum was returned");
+    assert!(client::AddOverflow::find(&error).is_some(), "Unexpected error: {}", error);
+    assert_eq!(client.checked_add(i64::MAX - 1, 1).expect("Add failed"), i64::MAX);
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
+
+//inflight_requests() lists every pipelined request a connection received behind a slow handler, in order, requests
+//without a correlation id aren't listed, and each id leaves the list once its response is queued
+#[test]
+fn test_inflight_requests() {
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .handler(|message, _: &mut ConnectionContext| {
+                thread::sleep(Duration::from_millis(300));      // Keeps each request in flight long enough to observe it
+                process(message).map_or(HandlerAction::Ignore, HandlerAction::Respond)
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 3000);
+    client.connect().expect("Failed to connect to the server");
+    let peer = client.local_addr().unwrap();
+
+    // One write, so every request arrives while the first one is handled
+    let mut frames = Vec::new();
+    for id in [1, 2, 0, 3] {
+        let mut request = ClientMessage::default();
+        request.message = Some(client_message::Message::EchoMessage(EchoMessage::from("pipelined")));
+        request.correlation_id = id;
+        write_frame(&mut frames, &request.encode_to_vec()).unwrap();
+    }
+    client.send_raw(&frames, false).expect("Failed to send the pipelined requests");
+
+    assert!(wait_for(|| server.inflight_requests(peer) == [1, 2, 3]), "Listed {:?}", server.inflight_requests(peer));
+    assert_eq!(client.receive().expect("Failed to receive").correlation_id, 1);
+    assert!(wait_for(|| server.inflight_requests(peer) == [2, 3]), "Listed {:?}", server.inflight_requests(peer));
+    let rest: Vec<u64> = (0..3).map(|_| client.receive().expect("Failed to receive").correlation_id).collect();
+    assert_eq!(rest, [2, 0, 3]);
+    assert!(wait_for(|| server.inflight_requests(peer).is_empty()), "Answered requests are still listed");
+    assert!(server.inflight_requests("127.0.0.1:1".parse().unwrap()).is_empty());
+
+    client.disconnect().expect("Failed to disconnect");
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}
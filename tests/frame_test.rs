@@ -0,0 +1,157 @@
+//Replays the frame decoder fuzz corpus and the inputs the fuzz target guards against, and checks framing over mock streams, without a running server.
+
+//IMPORTS
+use embedded_recruitment_task::{
+    frame::{decode_server_message, encode_client_message, read_frame, write_frame, HEADER_LEN},
+    handler::process,
+    message::{client_message, server_message, AddRequest, AddResponse, ClientMessage, EchoMessage, ServerMessage},
+};
+use prost::Message;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+
+const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/frame_decoder");
+
+// Answers every frame in `data` like the server does, stopping at the first framing error
+fn answer_frames(data: &[u8]) -> Vec<Option<server_message::Message>> {
+    let mut reader = Cursor::new(data);
+    let mut responses = Vec::new();
+    while let Ok(Some(frame)) = read_frame(&mut reader) {
+        assert!(frame.len() + HEADER_LEN <= data.len());
+        if let Ok(request) = ClientMessage::decode(&frame[..]) {
+            responses.push(request.message.and_then(process));
+        }
+    }
+    responses
+}
+
+//The seed corpus holds valid framed echo and add requests
+#[test]
+fn test_seed_corpus_round_trips() {
+    let echo = server_message::Message::EchoMessage(EchoMessage { content: "Hello, World!".to_string() });
+    let add = server_message::Message::AddResponse(AddResponse { result: 30, ..Default::default() });
+    let expected = [
+        ("echo", vec![Some(echo.clone())]),
+        ("add", vec![Some(add.clone())]),
+        ("echo_then_add", vec![Some(echo), Some(add)]),
+    ];
+    for (name, responses) in expected {
+        let data = std::fs::read(format!("{}/{}", CORPUS, name)).expect("Missing corpus file");
+        assert_eq!(answer_frames(&data), responses, "Corpus file {}", name);
+    }
+}
+
+//A length prefix of u32::MAX followed by a few bytes fails as a truncated frame instead of allocating 4 GB
+#[test]
+fn test_huge_declared_length() {
+    let mut data = u32::MAX.to_be_bytes().to_vec();
+    data.extend_from_slice(b"abc");
+    let error = read_frame(&mut Cursor::new(&data)).expect_err("Truncated frame was accepted");
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    assert!(answer_frames(&data).is_empty());
+}
+
+//Bodies that aren't a valid ClientMessage are skipped, later frames are still answered
+#[test]
+fn test_garbage_body_is_skipped() {
+    let mut data = Vec::new();
+    for body in [&[0xff, 0xff, 0xff][..], &[0x0a, 0x02, 0x0a, 0x00][..]] {
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        data.extend_from_slice(body);
+    }
+    assert_eq!(
+        answer_frames(&data),
+        vec![Some(server_message::Message::EchoMessage(EchoMessage { content: String::new() }))]
+    );
+}
+
+// Reader that fails with Interrupted before every successful read, like a syscall hit by signals
+struct InterruptingReader {
+    inner: Cursor<Vec<u8>>,
+    interrupt_next: bool,
+}
+
+impl Read for InterruptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt_next = !self.interrupt_next;
+        if !self.interrupt_next {
+            return Err(io::Error::new(ErrorKind::Interrupted, "Interrupted by a signal"));
+        }
+        let len = buf.len().min(3);       // Short reads, so a frame takes several calls
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+// Writer that fails with Interrupted before every successful write
+struct InterruptingWriter {
+    written: Vec<u8>,
+    interrupt_next: bool,
+}
+
+impl Write for InterruptingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.interrupt_next = !self.interrupt_next;
+        if !self.interrupt_next {
+            return Err(io::Error::new(ErrorKind::Interrupted, "Interrupted by a signal"));
+        }
+        let len = buf.len().min(3);
+        self.written.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+//Interrupted reads and writes are retried, the message still round-trips
+#[test]
+fn test_interrupted_io_is_retried() {
+    let request = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "interrupted".to_string() })),
+        ..Default::default()
+    };
+    let mut writer = InterruptingWriter { written: Vec::new(), interrupt_next: false };
+    write_frame(&mut writer, &request.encode_to_vec()).expect("Interrupted write was not retried");
+
+    let mut reader = InterruptingReader { inner: Cursor::new(writer.written), interrupt_next: false };
+    let frame = read_frame(&mut reader)
+        .expect("Interrupted read was not retried")
+        .expect("Frame missing");
+    assert_eq!(ClientMessage::decode(&frame[..]).expect("Frame does not decode"), request);
+    assert!(read_frame(&mut reader).expect("Clean EOF reported as an error").is_none());
+}
+
+//EOF inside the length prefix is a truncated header, EOF before it is a clean close
+#[test]
+fn test_truncated_header() {
+    let error = read_frame(&mut Cursor::new([0u8, 0])).expect_err("Truncated header was accepted");
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    assert!(error.to_string().contains("2 of 4 bytes"), "Unexpected error: {}", error);
+    assert!(read_frame(&mut Cursor::new([])).expect("Clean EOF reported as an error").is_none());
+}
+
+//encode_client_message() is a length-prefixed ClientMessage and decode_server_message() reads back what write_frame() wrote
+#[test]
+fn test_offline_codec_round_trip() {
+    let request = client_message::Message::AddRequest(AddRequest { a: -4, b: 9, allow_big_result: true });
+    let wire = encode_client_message(&request).expect("Failed to encode");
+    assert_eq!(u32::from_be_bytes(wire[..HEADER_LEN].try_into().unwrap()) as usize, wire.len() - HEADER_LEN);
+    let body = read_frame(&mut Cursor::new(&wire)).unwrap().expect("No frame");
+    assert_eq!(ClientMessage::decode(&body[..]).unwrap().message, Some(request));
+
+    let response = ServerMessage {
+        message: Some(server_message::Message::AddResponse(AddResponse { result: 5, ..Default::default() })),
+        seq: 3,
+        sent_at_unix_nanos: 0,
+        correlation_id: 0,
+    };
+    let mut frame = Vec::new();
+    write_frame(&mut frame, &response.encode_to_vec()).unwrap();
+    assert_eq!(decode_server_message(&frame).unwrap(), response);
+
+    // Whole frames only
+    assert_eq!(decode_server_message(&[]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(decode_server_message(&frame[..frame.len() - 1]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    frame.push(0);
+    assert_eq!(decode_server_message(&frame).unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,58 @@
+//Tests the message handling logic directly, without sockets or a running server.
+
+//IMPORTS
+use embedded_recruitment_task::{
+    handler::{process, ConnectionContext, DefaultHandler, HandlerAction, MessageHandler},
+    message::{client_message, server_message, AddRequest, AddResponse, Auth, EchoMessage},
+};
+
+//An echo is answered with the same content
+#[test]
+fn test_process_echo() {
+    let echo = EchoMessage { content: "Hello, World!".to_string() };
+    assert_eq!(
+        process(client_message::Message::EchoMessage(echo.clone())),
+        Some(server_message::Message::EchoMessage(echo))
+    );
+}
+
+//An add is answered with the sum, saturating and flagging the overflow instead of overflowing
+#[test]
+fn test_process_add() {
+    assert_eq!(
+        process(client_message::Message::AddRequest(AddRequest { a: 10, b: 20, ..Default::default() })),
+        Some(server_message::Message::AddResponse(AddResponse { result: 30, ..Default::default() }))
+    );
+    assert_eq!(
+        process(client_message::Message::AddRequest(AddRequest { a: i64::MAX, b: 1, ..Default::default() })),
+        Some(server_message::Message::AddResponse(AddResponse { result: i64::MAX, overflow: true, ..Default::default() }))
+    );
+}
+
+//A request allowing big results gets an overflowing sum as an exact decimal string
+#[test]
+fn test_process_add_big_result() {
+    let add = AddRequest { a: i64::MAX, b: i64::MAX, allow_big_result: true };
+    assert_eq!(
+        process(client_message::Message::AddRequest(add)),
+        Some(server_message::Message::AddResponse(AddResponse {
+            result: i64::MAX,
+            big_result: "18446744073709551614".to_string(),
+            ..Default::default()
+        }))
+    );
+    let add = AddRequest { a: 1, b: 2, allow_big_result: true };
+    assert_eq!(
+        process(client_message::Message::AddRequest(add)),
+        Some(server_message::Message::AddResponse(AddResponse { result: 3, ..Default::default() }))
+    );
+}
+
+//Messages without a default answer produce no response, the default handler ignores them
+#[test]
+fn test_process_unknown() {
+    let auth = client_message::Message::Auth(Auth { token: "token".to_string() });
+    assert_eq!(process(auth.clone()), None);
+    let mut context = ConnectionContext::new("127.0.0.1:1".parse().unwrap(), 1);
+    assert_eq!(DefaultHandler::default().handle(auth, &mut context), HandlerAction::Ignore);
+}
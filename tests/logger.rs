@@ -0,0 +1,51 @@
+//Test logger that keeps info messages, warnings and errors in memory, so tests can assert on what the server logged.
+#![allow(dead_code)]    // Shared by several test binaries, each one only uses part of the API
+
+//IMPORTS
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{Mutex, Once};
+
+static LOGGER: CaptureLogger = CaptureLogger { records: Mutex::new(Vec::new()) };
+static INIT: Once = Once::new();
+
+struct CaptureLogger {
+    records: Mutex<Vec<String>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Installs the capturing logger, safe to call from every test
+pub fn init() {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).expect("Another logger is already installed");
+        log::set_max_level(LevelFilter::Info);
+    });
+}
+
+// Returns true if any captured message contains every part of `needles`
+pub fn contains(needles: &[&str]) -> bool {
+    count(needles) > 0
+}
+
+// Returns how many captured messages contain every part of `needles`
+pub fn count(needles: &[&str]) -> usize {
+    LOGGER
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|record| needles.iter().all(|needle| record.contains(needle)))
+        .count()
+}
@@ -0,0 +1,178 @@
+//Checks the tracing spans the server opens with the "tracing" feature: a span per connection, a child span per message.
+#![cfg(feature = "tracing")]
+
+//IMPORTS
+use embedded_recruitment_task::{
+    frame::{encode_client_message, read_frame},
+    message::{client_message, EchoMessage},
+    server::Server,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    io::Write,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+thread_local! {
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };    // Spans entered on this thread, innermost last
+}
+
+// A recorded span or event: its name, the span it happened in and its fields as "name=value"
+#[derive(Debug, Clone)]
+struct Captured {
+    name: String,
+    parent: Option<u64>,
+    fields: Vec<String>,
+}
+
+//CaptureSubscriber: keeps every span and event in memory, so the test can walk the tree the server built
+#[derive(Clone, Default)]
+struct CaptureSubscriber {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<HashMap<u64, Captured>>>,
+    events: Arc<Mutex<Vec<Captured>>>,
+}
+
+struct Fields(Vec<String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(format!("{}={}", field.name(), value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push(format!("{}={:?}", field.name(), value));
+    }
+}
+
+// The span an event or new span belongs to, explicit or the innermost one entered on this thread
+fn parent_of(explicit: Option<&span::Id>, contextual: bool) -> Option<u64> {
+    match explicit {
+        Some(id) => Some(id.into_u64()),
+        None if contextual => ENTERED.with(|entered| entered.borrow().last().copied()),
+        None => None,
+    }
+}
+
+impl Subscriber for CaptureSubscriber {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;     // Ids can't be 0
+        let mut fields = Fields(Vec::new());
+        attributes.record(&mut fields);
+        let span = Captured {
+            name: attributes.metadata().name().to_string(),
+            parent: parent_of(attributes.parent(), attributes.is_contextual()),
+            fields: fields.0,
+        };
+        self.spans.lock().unwrap().insert(id, span);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+        let mut fields = Fields(Vec::new());
+        values.record(&mut fields);
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.fields.extend(fields.0);
+        }
+    }
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields(Vec::new());
+        event.record(&mut fields);
+        self.events.lock().unwrap().push(Captured {
+            name: event.metadata().name().to_string(),
+            parent: parent_of(event.parent(), event.is_contextual()),
+            fields: fields.0,
+        });
+    }
+
+    fn enter(&self, id: &span::Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(id.into_u64()));
+    }
+
+    fn exit(&self, id: &span::Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|entered| *entered == id.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+}
+
+impl CaptureSubscriber {
+    fn span(&self, id: u64) -> Option<Captured> {
+        self.spans.lock().unwrap().get(&id).cloned()
+    }
+}
+
+//Every message handled on a connection is a "message" span inside that connection's "connection" span, with the
+//message type, and its "Message handled" event carries the latency
+#[test]
+fn test_connection_span_contains_message_events() {
+    let subscriber = CaptureSubscriber::default();
+    tracing::subscriber::set_global_default(subscriber.clone()).expect("Another subscriber is already installed");
+
+    let server = Arc::new(Server::new("localhost:0", 10).expect("Failed to start server"));
+    let running = server.clone();
+    let handle = thread::spawn(move || running.run().expect("Server encountered an error"));
+    while !server.is_running() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).expect("Failed to connect to the server");
+    let peer = stream.local_addr().unwrap().to_string();
+    for content in ["first", "second"] {
+        let echo = client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+        stream.write_all(&encode_client_message(&echo).expect("Failed to encode")).expect("Failed to send");
+        read_frame(&mut stream).expect("Failed to read the echo").expect("Connection closed");
+    }
+
+    // The event is emitted right after the response is queued, it may come after the client read it
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let handled = loop {
+        let handled: Vec<Captured> = subscriber
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.fields.iter().any(|field| field == "message=Message handled"))
+            .cloned()
+            .collect();
+        if handled.len() >= 2 || Instant::now() > deadline {
+            break handled;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(handled.len(), 2, "Expected one event per echo: {:?}", handled);
+    for event in handled {
+        assert!(event.fields.iter().any(|field| field.starts_with("latency_us=")), "No latency: {:?}", event);
+        let message = subscriber.span(event.parent.expect("Event outside any span")).unwrap();
+        assert_eq!(message.name, "message");
+        assert!(message.fields.contains(&"message_type=EchoMessage".to_string()), "Unexpected span: {:?}", message);
+        let connection = subscriber.span(message.parent.expect("Message span outside the connection span")).unwrap();
+        assert_eq!(connection.name, "connection");
+        assert!(connection.fields.contains(&format!("peer={}", peer)), "Unexpected span: {:?}", connection);
+    }
+
+    drop(stream);
+    server.stop();
+    handle.join().expect("Server thread panicked or failed to join");
+}